@@ -0,0 +1,90 @@
+// recommend latency on a 100k pie, 5k label catalog, without redis: the
+// exclusion bitmaps are built in memory the same way redis would return them
+
+#[macro_use]
+extern crate criterion;
+extern crate rustc_serialize;
+
+use criterion::Criterion;
+
+#[path = "../src/params.rs"]
+mod params;
+#[path = "../src/pies.rs"]
+mod pies;
+#[path = "../src/taxonomy.rs"]
+mod taxonomy;
+#[path = "../src/index.rs"]
+mod index;
+
+const PIES: usize = 100000;
+const LABELS: usize = 5000;
+const LABELS_PER_PIE: usize = 8;
+
+// deterministic, so runs are comparable
+fn next(seed: &mut u64) -> u64 {
+    *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    *seed >> 33
+}
+
+fn catalog() -> Vec<pies::Pie> {
+    let mut seed = 42;
+    (0..PIES).map(|i| {
+        // skew label popularity so some labels are dense and most are sparse
+        let labels = (0..LABELS_PER_PIE).map(|_| {
+            let r = next(&mut seed) as usize;
+            format!("label-{}", (r % LABELS) * (r % 7 + 1) % LABELS)
+        }).collect();
+        pies::Pie {
+            id: i as u64,
+            name: format!("pie {}", i),
+            image_url: String::new(),
+            price_per_slice: (PIES - i) as f64 / 100.0,
+            slices: 8,
+            labels: labels,
+            contains: None,
+            may_contain: None,
+            certified: None
+        }
+    }).collect()
+}
+
+fn exclusion(every: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; PIES / 8 + 1];
+    for pos in (0..PIES).filter(|pos| pos % every == 0) {
+        bytes[pos / 8] |= 0x80 >> (pos % 8);
+    }
+    bytes
+}
+
+fn recommend(label_index: &index::LabelIndex, labels: &Vec<String>, excluded: &[&[u8]]) -> Option<usize> {
+    label_index.matching(labels)
+        .and_then(|bitmap| index::candidates(&bitmap, excluded, true).next())
+}
+
+fn bench_recommend(c: &mut Criterion) {
+    let pies = catalog();
+    let taxonomy = taxonomy::Taxonomy::default();
+    let label_index = index::LabelIndex::new(&pies, &taxonomy);
+    let blacklist = exclusion(97);
+    let sold_out = exclusion(5);
+
+    c.bench_function("build label index", |b| b.iter(|| index::LabelIndex::new(&pies, &taxonomy)));
+
+    let dense = vec!["label-0".to_string()];
+    c.bench_function("recommend one dense label", |b| {
+        b.iter(|| recommend(&label_index, &dense, &[&blacklist, &sold_out]))
+    });
+
+    let mixed = vec!["label-0".to_string(), "label-12".to_string()];
+    c.bench_function("recommend two labels", |b| {
+        b.iter(|| recommend(&label_index, &mixed, &[&blacklist, &sold_out]))
+    });
+
+    let sparse = vec!["label-4999".to_string(), "label-1".to_string(), "label-0".to_string()];
+    c.bench_function("recommend three labels", |b| {
+        b.iter(|| recommend(&label_index, &sparse, &[&blacklist, &sold_out]))
+    });
+}
+
+criterion_group!(benches, bench_recommend);
+criterion_main!(benches);
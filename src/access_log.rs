@@ -0,0 +1,90 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::{typemap, BeforeMiddleware, AfterMiddleware};
+
+extern crate router;
+use router::Router;
+
+extern crate log;
+use log::Level;
+
+extern crate persistent;
+use persistent::Read;
+
+extern crate rustc_serialize;
+use rustc_serialize::json::{Json, ToJson};
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+use accounts;
+use cache;
+use logging;
+use request_id;
+use routes;
+
+// one line per request, once the response is ready, whether a handler or middleware
+// answered it
+
+pub struct AccessLog;
+impl typemap::Key for AccessLog { type Value = Instant; }
+
+// what a purchase came to, e.g. bought or sold_out, for the log line
+struct Outcome;
+impl typemap::Key for Outcome { type Value = &'static str; }
+
+pub fn outcome(req: &mut Request, name: &'static str) {
+    req.extensions.insert::<Outcome>(name);
+}
+
+// logs the request, and counts it toward the metrics
+fn log(req: &mut Request, res: &Response) {
+    let latency = req.extensions.get::<AccessLog>()
+        .map(|started| {
+            let elapsed = started.elapsed();
+            elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1e6
+        })
+        .unwrap_or(0.0);
+    let status = res.status.map(|status| status.to_u16()).unwrap_or(200);
+    if let Ok(metrics) = req.get::<Read<cache::Metrics>>() {
+        // requests the router found nothing for share one label
+        metrics.request(routes::name(req).unwrap_or("unrouted"), status, latency / 1000.0);
+    }
+    let pie_id = req.extensions.get::<Router>()
+        .and_then(|params| params.find("pie_id"))
+        .and_then(|id| u64::from_str(id).ok());
+
+    let mut fields = BTreeMap::new();
+    fields.insert("request_id".to_string(), request_id::get(req).to_json());
+    fields.insert("method".to_string(), req.method.to_string().to_json());
+    fields.insert("path".to_string(), format!("/{}", req.url.path().join("/")).to_json());
+    fields.insert("route".to_string(), routes::name(req).map(|name| name.to_string()).to_json());
+    fields.insert("pie_id".to_string(), pie_id.to_json());
+    fields.insert("username".to_string(), accounts::current_user(req).map(|user| user.username).to_json());
+    fields.insert("status".to_string(), status.to_json());
+    fields.insert("outcome".to_string(), req.extensions.get::<Outcome>().map(|name| name.to_string()).to_json());
+    fields.insert("latency_ms".to_string(), ((latency * 1000.0).round() / 1000.0).to_json());
+
+    let level = if status >= 500 { Level::Error } else { Level::Info };
+    logging::emit(level, "access", fields);
+}
+
+impl BeforeMiddleware for AccessLog {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<AccessLog>(Instant::now());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for AccessLog {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        log(req, &res);
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        log(req, &err.response);
+        Err(err)
+    }
+}
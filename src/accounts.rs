@@ -0,0 +1,289 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::{typemap, BeforeMiddleware};
+use iron::method::Method;
+use iron::headers::{Authorization, Bearer, ContentType, Cookie, SetCookie, CookiePair, Headers};
+use iron::mime::{Mime, TopLevel, SubLevel};
+
+extern crate redis;
+use redis::Commands;
+
+extern crate bcrypt;
+
+use std::collections::HashMap;
+use std::str;
+
+use forms;
+use roles::Role;
+use store;
+
+// accounts are a bcrypt hash under the username, sessions a random token naming the user,
+// both in redis so every server sees the same ones
+macro_rules! account_key { ($x:expr) => (format!("account-{}", $x)) }
+macro_rules! session_key { ($x:expr) => (format!("session-{}", $x)) }
+
+const SESSION_COOKIE: &'static str = "bakeoff_session";
+// service accounts send a key from the config rather than signing in
+const API_KEY_HEADER: &'static str = "X-Api-Key";
+// a week, renewed by signing in again
+pub const SESSION_TTL: usize = 7 * 24 * 60 * 60;
+const MAX_USERNAME: usize = 64;
+const MIN_PASSWORD: usize = 8;
+
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct User {
+    pub username: String,
+    pub role: Role
+}
+
+impl User {
+    pub fn can_see_purchases_of(&self, username: &str) -> bool {
+        self.role >= Role::Staff || self.username == username
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Registration {
+    Created,
+    Taken
+}
+
+impl Registration {
+    // what SETNX answering created, or not because the name already exists, means
+    fn from_set_nx(created: bool) -> Registration {
+        if created { Registration::Created } else { Registration::Taken }
+    }
+}
+
+// None when fine, otherwise what's wrong with it
+pub fn check_username(username: &str) -> Option<&'static str> {
+    if username.is_empty() || username.len() > MAX_USERNAME {
+        Some("must be 1 to 64 characters")
+    } else if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        Some("may only use letters, digits, - _ and .")
+    } else {
+        None
+    }
+}
+
+pub fn check_password(password: &str) -> Option<&'static str> {
+    if password.chars().count() < MIN_PASSWORD {
+        Some("must be at least 8 characters")
+    } else {
+        None
+    }
+}
+
+// Err when redis couldn't be reached, so nothing is known to have been created
+pub fn register(store: &store::Store, username: &str, password: &str) -> Result<Registration, store::Unavailable> {
+    let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST).expect("bcrypt failed");
+
+    // set only if absent, so two people can't register the same name at once
+    let created: bool = try!(store.write(|conn| conn.set_nx(account_key!(username), &hash)));
+    Ok(Registration::from_set_nx(created))
+}
+
+// a new session token when the password is right
+pub fn login(store: &store::Store, username: &str, password: &str) -> Result<Option<String>, store::Unavailable> {
+    let hash: Option<String> = try!(store.read(|conn| conn.get(account_key!(username))));
+
+    let valid = match hash {
+        Some(ref hash) => bcrypt::verify(password, hash).unwrap_or(false),
+        None => false
+    };
+    if !valid {
+        return Ok(None);
+    }
+
+    let token = forms::new_csrf_token();
+    let _: () = try!(store.write(|conn| conn.set_ex(session_key!(token), username, SESSION_TTL)));
+    Ok(Some(token))
+}
+
+pub fn logout(store: &store::Store, token: &str) -> Result<(), store::Unavailable> {
+    store.write(|conn| conn.del(session_key!(token)))
+}
+
+// every request asks, so while redis is unavailable the request goes on signed out
+// and the route decides whether it can still answer
+fn session_user(store: &store::Store, token: &str) -> Option<String> {
+    match store.read(|conn| conn.get(session_key!(token))) {
+        Ok(username) => username,
+        Err(_) => {
+            warn!("redis unavailable, treating the request as signed out");
+            None
+        }
+    }
+}
+
+fn is_form(headers: &Headers) -> bool {
+    match headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _))) => true,
+        _ => false
+    }
+}
+
+// a cookie rides along on requests from other sites too, so it only counts where nothing
+// changes or where the csrf token is checked; everything else needs the bearer token
+fn cookie_allowed(method: &Method, headers: &Headers) -> bool {
+    *method == Method::Get || *method == Method::Head || is_form(headers)
+}
+
+// Authorization: Bearer <token>, or the session cookie where that's safe
+pub fn token(req: &Request) -> Option<String> {
+    if let Some(bearer) = req.headers.get::<Authorization<Bearer>>() {
+        return Some(bearer.token.clone());
+    }
+    if !cookie_allowed(&req.method, &req.headers) {
+        return None;
+    }
+    req.headers.get::<Cookie>().and_then(|cookies| {
+        cookies.iter()
+            .find(|cookie| cookie.name == SESSION_COOKIE)
+            .map(|cookie| cookie.value.clone())
+    })
+}
+
+// signed in, but only by the session cookie, which other sites' links send along too
+pub fn by_cookie(req: &Request) -> bool {
+    current_user(req).is_some() && api_key(req).is_none()
+        && req.headers.get::<Authorization<Bearer>>().is_none()
+}
+
+// added to whatever cookies the response already sets
+fn add_cookie(res: &mut Response, cookie: CookiePair) {
+    let mut cookies = res.headers.get::<SetCookie>().map(|set| set.0.clone()).unwrap_or(vec![]);
+    cookies.push(cookie);
+    res.headers.set(SetCookie(cookies));
+}
+
+pub fn set_session_cookie(res: &mut Response, token: &str) {
+    let mut cookie = CookiePair::new(SESSION_COOKIE.to_string(), token.to_string());
+    cookie.path = Some("/".to_string());
+    cookie.httponly = true;
+    cookie.max_age = Some(SESSION_TTL as u64);
+    add_cookie(res, cookie);
+}
+
+pub fn clear_session_cookie(res: &mut Response) {
+    let mut cookie = CookiePair::new(SESSION_COOKIE.to_string(), String::new());
+    cookie.path = Some("/".to_string());
+    cookie.httponly = true;
+    cookie.max_age = Some(0);
+    add_cookie(res, cookie);
+}
+
+// whoever the request's token belongs to, if anyone
+pub fn current_user(req: &Request) -> Option<User> {
+    req.extensions.get::<Authenticate>().cloned()
+}
+
+pub fn api_key(req: &Request) -> Option<String> {
+    req.headers.get_raw(API_KEY_HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim().to_string())
+}
+
+pub struct Authenticate {
+    store: store::Store,
+    // anyone missing is a customer
+    roles: HashMap<String, Role>,
+    // key to service account
+    api_keys: HashMap<String, User>
+}
+
+impl typemap::Key for Authenticate { type Value = User; }
+
+impl Authenticate {
+    pub fn new(store: store::Store,
+               roles: HashMap<String, Role>,
+               api_keys: HashMap<String, User>) -> Authenticate {
+        Authenticate {
+            store: store,
+            roles: roles,
+            api_keys: api_keys
+        }
+    }
+
+    fn user(&self, req: &Request) -> Option<User> {
+        if let Some(key) = api_key(req) {
+            return self.api_keys.get(&key).cloned();
+        }
+
+        token(req).and_then(|token| session_user(&self.store, &token)).map(|username| User {
+            role: self.roles.get(&username).cloned().unwrap_or(Role::Customer),
+            username: username
+        })
+    }
+}
+
+impl BeforeMiddleware for Authenticate {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if let Some(user) = self.user(req) {
+            req.extensions.insert::<Authenticate>(user);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{ContentType, Headers};
+    use iron::method::Method;
+    use iron::mime::{Mime, TopLevel, SubLevel};
+
+    use roles::Role;
+
+    use super::{check_password, check_username, cookie_allowed, Registration, User};
+
+    fn headers(content_type: Option<ContentType>) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(content_type) = content_type {
+            headers.set(content_type);
+        }
+        headers
+    }
+
+    #[test]
+    fn cookies_count_for_reads_and_forms() {
+        let form = ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, vec![]));
+        assert!(cookie_allowed(&Method::Get, &headers(None)));
+        assert!(cookie_allowed(&Method::Head, &headers(None)));
+        assert!(cookie_allowed(&Method::Post, &headers(Some(form))));
+    }
+
+    #[test]
+    fn cookies_are_refused_for_json_and_other_writes() {
+        assert!(!cookie_allowed(&Method::Post, &headers(Some(ContentType::json()))));
+        assert!(!cookie_allowed(&Method::Post, &headers(Some(ContentType::plaintext()))));
+        assert!(!cookie_allowed(&Method::Post, &headers(None)));
+        assert!(!cookie_allowed(&Method::Delete, &headers(Some(ContentType::json()))));
+    }
+
+    #[test]
+    fn a_name_already_set_is_taken() {
+        assert_eq!(Registration::from_set_nx(true), Registration::Created);
+        assert_eq!(Registration::from_set_nx(false), Registration::Taken);
+    }
+
+    #[test]
+    fn usernames_and_passwords() {
+        assert_eq!(check_username("alice.b-c_1"), None);
+        assert_eq!(check_username(""), Some("must be 1 to 64 characters"));
+        assert_eq!(check_username(&"a".repeat(65)), Some("must be 1 to 64 characters"));
+        assert_eq!(check_username("alice smith"), Some("may only use letters, digits, - _ and ."));
+        assert_eq!(check_username("al:ice"), Some("may only use letters, digits, - _ and ."));
+        assert_eq!(check_password("1234567"), Some("must be at least 8 characters"));
+        assert_eq!(check_password("pässwörd"), None);
+    }
+
+    #[test]
+    fn staff_see_everyone_s_purchases() {
+        let customer = User { username: "alice".to_string(), role: Role::Customer };
+        let staff = User { username: "bob".to_string(), role: Role::Staff };
+        assert!(customer.can_see_purchases_of("alice"));
+        assert!(!customer.can_see_purchases_of("bob"));
+        assert!(staff.can_see_purchases_of("alice"));
+    }
+}
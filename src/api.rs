@@ -0,0 +1,415 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::status;
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+
+extern crate router;
+use router::Router;
+
+extern crate persistent;
+use persistent::{Read};
+
+extern crate rustc_serialize;
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+
+use std::str::FromStr;
+
+use endpoints::{self, PurchaseOutcome};
+use cache;
+use forms;
+use params;
+use pies;
+use request_id;
+use accounts;
+use idempotency;
+use store;
+
+// /api/v1: every body is an Envelope, with data on success and error otherwise.
+// the unversioned routes keep their old shapes for existing clients
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorCode {
+    NotFound,
+    InvalidRequest,
+    // a form post without a matching csrf token
+    Forbidden,
+    // the amount doesn't match price_per_slice * slices
+    WrongAmount,
+    // PurchaseStatus::Fatty
+    PurchaseLimit,
+    // PurchaseStatus::Gone
+    SoldOut,
+    NoRecommendation,
+    // no session, or it expired
+    Unauthorized,
+    BadCredentials,
+    UsernameTaken,
+    // a retry, by its Idempotency-Key, of an order still being bought
+    OrderInProgress,
+    // an Idempotency-Key sent before with a different order
+    IdempotencyKeyReused,
+    // the path exists, but not for this method; see Allow
+    MethodNotAllowed,
+    // the client's token bucket for the route is empty; see Retry-After
+    RateLimited,
+    // redis can't be reached, or the breaker is open; see Retry-After
+    Unavailable
+}
+
+// every code a client may see, for the openapi document
+pub const ERROR_CODES: &'static [ErrorCode] = &[
+    ErrorCode::NotFound, ErrorCode::InvalidRequest, ErrorCode::Forbidden, ErrorCode::WrongAmount,
+    ErrorCode::PurchaseLimit, ErrorCode::SoldOut, ErrorCode::NoRecommendation, ErrorCode::Unauthorized,
+    ErrorCode::BadCredentials, ErrorCode::UsernameTaken, ErrorCode::OrderInProgress, ErrorCode::IdempotencyKeyReused,
+    ErrorCode::MethodNotAllowed, ErrorCode::RateLimited, ErrorCode::Unavailable
+];
+
+impl ErrorCode {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::WrongAmount => "wrong_amount",
+            ErrorCode::PurchaseLimit => "purchase_limit",
+            ErrorCode::SoldOut => "sold_out",
+            ErrorCode::NoRecommendation => "no_recommendation",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::BadCredentials => "bad_credentials",
+            ErrorCode::UsernameTaken => "username_taken",
+            ErrorCode::OrderInProgress => "order_in_progress",
+            ErrorCode::IdempotencyKeyReused => "idempotency_key_reused",
+            ErrorCode::MethodNotAllowed => "method_not_allowed",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Unavailable => "unavailable"
+        }
+    }
+
+    pub fn status(&self) -> status::Status {
+        match *self {
+            ErrorCode::NotFound => status::NotFound,
+            ErrorCode::InvalidRequest => status::BadRequest,
+            ErrorCode::Forbidden => status::Forbidden,
+            ErrorCode::WrongAmount => status::PaymentRequired,
+            ErrorCode::PurchaseLimit => status::TooManyRequests,
+            ErrorCode::SoldOut => status::Gone,
+            ErrorCode::NoRecommendation => status::NotFound,
+            ErrorCode::Unauthorized => status::Unauthorized,
+            ErrorCode::BadCredentials => status::Unauthorized,
+            ErrorCode::UsernameTaken => status::Conflict,
+            ErrorCode::OrderInProgress => status::Conflict,
+            ErrorCode::IdempotencyKeyReused => status::UnprocessableEntity,
+            ErrorCode::MethodNotAllowed => status::MethodNotAllowed,
+            ErrorCode::RateLimited => status::TooManyRequests,
+            ErrorCode::Unavailable => status::ServiceUnavailable
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match *self {
+            ErrorCode::NotFound => "Not found.",
+            ErrorCode::InvalidRequest => "Invalid request.",
+            ErrorCode::Forbidden => "Your session expired, please try again.",
+            ErrorCode::WrongAmount => "You did math wrong.",
+            ErrorCode::PurchaseLimit => "Gluttony is discouraged.",
+            ErrorCode::SoldOut => "No more of that pie.  Try something else.",
+            ErrorCode::NoRecommendation => "Sorry we don’t have what you’re looking for.",
+            ErrorCode::Unauthorized => "Please sign in.",
+            ErrorCode::BadCredentials => "Wrong username or password.",
+            ErrorCode::UsernameTaken => "That username is taken.",
+            ErrorCode::OrderInProgress => "That order is still being placed.",
+            ErrorCode::IdempotencyKeyReused => "That Idempotency-Key was used for a different order.",
+            ErrorCode::MethodNotAllowed => "That method isn't allowed here.",
+            ErrorCode::RateLimited => "Too many requests, please slow down.",
+            ErrorCode::Unavailable => "Pie is unavailable right now, please try again shortly."
+        }
+    }
+}
+
+#[derive(RustcEncodable)]
+pub struct ApiError {
+    pub code: &'static str,
+    pub message: String,
+    // which inputs were wrong, for invalid_request
+    pub fields: Vec<params::FieldError>
+}
+
+#[derive(RustcEncodable)]
+pub struct Envelope<T> {
+    pub data: Option<T>,
+    pub error: Option<ApiError>,
+    pub request_id: String
+}
+
+#[derive(RustcEncodable)]
+pub struct Receipt {
+    pub pie_id: u64,
+    pub username: String,
+    pub slices: u64,
+    pub amount: f64
+}
+
+#[derive(RustcEncodable)]
+pub struct Session {
+    pub username: String,
+    // send as Authorization: Bearer <token>
+    pub token: String,
+    // seconds
+    pub expires_in: usize
+}
+
+#[derive(RustcEncodable)]
+pub struct Recommendation {
+    pub pie_id: u64,
+    pub pie_url: String
+}
+
+fn envelope<T: Encodable>(req: &Request, data: Option<T>, error: Option<ApiError>) -> String {
+    let envelope = Envelope {
+        data: data,
+        error: error,
+        request_id: request_id::get(req)
+    };
+    json::encode(&envelope).unwrap()
+}
+
+fn respond<T: Encodable>(req: &Request, status: status::Status, data: Option<T>, error: Option<ApiError>) -> IronResult<Response> {
+    Ok(Response::with((
+                          status,
+                          envelope(req, data, error),
+                          Header(ContentType::json())
+                      )))
+}
+
+pub fn ok<T: Encodable>(req: &Request, status: status::Status, data: T) -> IronResult<Response> {
+    respond(req, status, Some(data), None)
+}
+
+fn error(code: ErrorCode, fields: Vec<params::FieldError>) -> ApiError {
+    ApiError {
+        code: code.name(),
+        message: code.message().to_string(),
+        fields: fields
+    }
+}
+
+pub fn fail(req: &Request, code: ErrorCode, fields: Vec<params::FieldError>) -> IronResult<Response> {
+    respond::<()>(req, code.status(), None, Some(error(code, fields)))
+}
+
+// redis couldn't be reached; Retry-After says when to try again
+pub fn unavailable(req: &Request, unavailable: store::Unavailable) -> IronResult<Response> {
+    let mut res = try!(fail(req, ErrorCode::Unavailable, vec![]));
+    res.headers.set_raw("Retry-After", vec![unavailable.retry_after.to_string().into_bytes()]);
+    Ok(res)
+}
+
+// for middleware, which answers through an IronError rather than a handler's response
+pub fn error_body(req: &Request, code: ErrorCode) -> String {
+    envelope::<()>(req, None, Some(error(code, vec![])))
+}
+
+fn pie_id(req: &Request) -> Option<u64> {
+    req.extensions.get::<Router>()
+        .and_then(|router| router.find("pie_id"))
+        .and_then(|id| u64::from_str(id).ok())
+}
+
+pub fn not_found(req: &mut Request) -> IronResult<Response> {
+    fail(req, ErrorCode::NotFound, vec![])
+}
+
+pub fn pies(req: &mut Request) -> IronResult<Response> {
+    let pies = endpoints::show_pies(req);
+    ok(req, status::Ok, pies::ShowPies { pies: pies })
+}
+
+pub fn pie(req: &mut Request) -> IronResult<Response> {
+    let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+    let images = req.get::<Read<cache::Images>>().unwrap();
+    let viewer = accounts::current_user(req);
+
+    match pie_id(req).and_then(|id| id_index.get(&id)) {
+        Some(&(ref pie, _)) => ok(req, status::Ok, endpoints::show_pie(&redis, &routes, &images, pie, viewer.as_ref())),
+        None => fail(req, ErrorCode::NotFound, vec![])
+    }
+}
+
+pub fn purchase(req: &mut Request) -> IronResult<Response> {
+    let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let experiment = req.get::<Read<cache::Experiment>>().unwrap();
+
+    let (pie, bitvec_pos) = match pie_id(req).and_then(|id| id_index.get(&id)) {
+        Some(found) => found.clone(),
+        None => return fail(req, ErrorCode::NotFound, vec![])
+    };
+
+    let params = match params::read(req) {
+        Ok(params) => params,
+        Err(errors) => return fail(req, ErrorCode::InvalidRequest, errors)
+    };
+    let order = match params::purchase(&params) {
+        Ok(order) => order,
+        Err(errors) => return fail(req, ErrorCode::InvalidRequest, errors)
+    };
+
+    // a browser form could be posted here from another site, so it needs the token too
+    if params.source == params::Source::Form && !forms::check_csrf(req, order.csrf_token.as_ref()) {
+        return fail(req, ErrorCode::Forbidden, vec![]);
+    }
+
+    let user = match endpoints::buyer(req, &order) {
+        Ok(user) => user,
+        Err(outcome) => return purchase_failed(req, outcome)
+    };
+
+    let fingerprint = format!("api pie={} slices={} amount={:?}", pie.id, order.slices, order.amount);
+    let claim = match idempotency::begin(req, &redis, &user.username, fingerprint) {
+        Ok(idempotency::Begin::Fresh(claim)) => claim,
+        Ok(idempotency::Begin::Replay(res)) => {
+            endpoints::note_purchase(req, "replayed", pie.id, order.slices);
+            return Ok(res);
+        },
+        Err(refusal) => {
+            let outcome = endpoints::refused(refusal);
+            endpoints::note_purchase(req, outcome.name(), pie.id, order.slices);
+            return purchase_failed(req, outcome);
+        }
+    };
+
+    let outcome = endpoints::buy(&redis, &experiment, &pie, bitvec_pos, &user.username, order.slices, order.amount);
+    endpoints::note_purchase(req, outcome.name(), pie.id, order.slices);
+    let result = match outcome {
+        PurchaseOutcome::Bought => ok(req, status::Created, Receipt {
+            pie_id: pie.id,
+            username: user.username.clone(),
+            slices: order.slices,
+            amount: order.amount.unwrap_or(0.0)
+        }),
+        outcome => purchase_failed(req, outcome)
+    };
+    claim.finish(&redis, result)
+}
+
+fn purchase_failed(req: &Request, outcome: PurchaseOutcome) -> IronResult<Response> {
+    match outcome {
+        PurchaseOutcome::Bought => unreachable!("a purchase that went through is not a failure"),
+        PurchaseOutcome::Glutton => fail(req, ErrorCode::PurchaseLimit, vec![]),
+        PurchaseOutcome::Gone => fail(req, ErrorCode::SoldOut, vec![]),
+        PurchaseOutcome::BadMath => fail(req, ErrorCode::WrongAmount, vec![]),
+        PurchaseOutcome::Invalid(errors) => fail(req, ErrorCode::InvalidRequest, errors),
+        PurchaseOutcome::Forbidden => fail(req, ErrorCode::Forbidden, vec![]),
+        PurchaseOutcome::Unauthorized => fail(req, ErrorCode::Unauthorized, vec![]),
+        PurchaseOutcome::InProgress => fail(req, ErrorCode::OrderInProgress, vec![]),
+        PurchaseOutcome::KeyReused => fail(req, ErrorCode::IdempotencyKeyReused, vec![]),
+        PurchaseOutcome::Unavailable(e) => unavailable(req, e)
+    }
+}
+
+// the username and password from the body, refusing a form post from another site
+fn credentials(req: &mut Request) -> Result<params::Credentials, IronResult<Response>> {
+    let params = match params::read(req) {
+        Ok(params) => params,
+        Err(errors) => return Err(fail(req, ErrorCode::InvalidRequest, errors))
+    };
+    let credentials = match params::credentials(&params) {
+        Ok(credentials) => credentials,
+        Err(errors) => return Err(fail(req, ErrorCode::InvalidRequest, errors))
+    };
+    if params.source == params::Source::Form && !forms::check_csrf(req, credentials.csrf_token.as_ref()) {
+        return Err(fail(req, ErrorCode::Forbidden, vec![]));
+    }
+    Ok(credentials)
+}
+
+fn session(req: &Request, username: String, token: String) -> IronResult<Response> {
+    ok(req, status::Created, Session {
+        username: username,
+        token: token,
+        expires_in: accounts::SESSION_TTL
+    })
+}
+
+pub fn register(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+
+    let credentials = match credentials(req) {
+        Ok(credentials) => credentials,
+        Err(res) => return res
+    };
+    let errors = endpoints::credential_errors(&credentials);
+    if !errors.is_empty() {
+        return fail(req, ErrorCode::InvalidRequest, errors);
+    }
+
+    match accounts::register(&redis, &credentials.username, &credentials.password) {
+        // the account exists even if signing in fails; the client signs in once redis is back
+        Ok(accounts::Registration::Created) => match accounts::login(&redis, &credentials.username, &credentials.password) {
+            Ok(Some(token)) => session(req, credentials.username, token),
+            Ok(None) => fail(req, ErrorCode::BadCredentials, vec![]),
+            Err(e) => unavailable(req, e)
+        },
+        Ok(accounts::Registration::Taken) => fail(req, ErrorCode::UsernameTaken, vec![]),
+        Err(e) => unavailable(req, e)
+    }
+}
+
+pub fn login(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+
+    let credentials = match credentials(req) {
+        Ok(credentials) => credentials,
+        Err(res) => return res
+    };
+
+    match accounts::login(&redis, &credentials.username, &credentials.password) {
+        Ok(Some(token)) => session(req, credentials.username, token),
+        Ok(None) => fail(req, ErrorCode::BadCredentials, vec![]),
+        Err(e) => unavailable(req, e)
+    }
+}
+
+pub fn logout(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+
+    match (accounts::current_user(req), accounts::token(req)) {
+        (Some(user), Some(token)) => match accounts::logout(&redis, &token) {
+            Ok(()) => ok(req, status::Ok, user),
+            Err(e) => unavailable(req, e)
+        },
+        _ => fail(req, ErrorCode::Unauthorized, vec![])
+    }
+}
+
+pub fn me(req: &mut Request) -> IronResult<Response> {
+    match accounts::current_user(req) {
+        Some(user) => ok(req, status::Ok, user),
+        None => fail(req, ErrorCode::Unauthorized, vec![])
+    }
+}
+
+pub fn recommend(req: &mut Request) -> IronResult<Response> {
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+
+    match endpoints::recommended_pie(req) {
+        Ok(Some(pie)) => {
+            let url = routes.url(req, "pie", &[("pie_id", &pie.id.to_string())]);
+            ok(req, status::Ok, Recommendation { pie_id: pie.id, pie_url: url })
+        }
+        Ok(None) => fail(req, ErrorCode::NoRecommendation, vec![]),
+        Err(endpoints::NotRecommended::Invalid(errors)) => fail(req, ErrorCode::InvalidRequest, errors),
+        Err(endpoints::NotRecommended::Unavailable(e)) => unavailable(req, e)
+    }
+}
+
+pub fn search(req: &mut Request) -> IronResult<Response> {
+    match endpoints::search_page(req) {
+        Ok(page) => ok(req, status::Ok, page),
+        Err(errors) => fail(req, ErrorCode::InvalidRequest, errors)
+    }
+}
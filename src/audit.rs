@@ -0,0 +1,80 @@
+extern crate redis;
+use redis::Commands;
+
+extern crate rustc_serialize;
+use rustc_serialize::json;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use accounts;
+use store;
+
+// who changed inventory by hand, newest first, kept in one capped redis list
+macro_rules! audit_key { () => ("audit-log") }
+
+const MAX_ENTRIES: isize = 10000;
+
+// no price changes: prices only come from the catalog file, and nothing here can change them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    Restock,
+    Refund,
+    // every pie back to full, with no purchases
+    Reset,
+    // live state replaced from a snapshot
+    Import
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::Restock => "restock",
+            Action::Refund => "refund",
+            Action::Reset => "reset",
+            Action::Import => "import"
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct Entry {
+    // seconds since the epoch
+    pub at: u64,
+    pub actor: String,
+    pub role: String,
+    pub action: String,
+    pub pie_id: Option<u64>,
+    pub details: String
+}
+
+// pushed and trimmed together; Err leaves the log as it was, and callers carry on since
+// the change it describes has already been made
+pub fn record(store: &store::Store,
+              user: &accounts::User,
+              action: Action,
+              pie_id: Option<u64>,
+              details: String) -> Result<(), store::Unavailable> {
+    let entry = Entry {
+        at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        actor: user.username.clone(),
+        role: user.role.name().to_string(),
+        action: action.name().to_string(),
+        pie_id: pie_id,
+        details: details
+    };
+    let encoded = json::encode(&entry).unwrap();
+
+    store.write(|conn| redis::pipe().atomic()
+        .cmd("LPUSH").arg(audit_key!()).arg(&encoded).ignore()
+        .cmd("LTRIM").arg(audit_key!()).arg(0).arg(MAX_ENTRIES - 1).ignore()
+        .query(conn))
+}
+
+// LRANGE 0 -1 would be the whole log, so no limit is no entries
+pub fn recent(store: &store::Store, limit: usize) -> Result<Vec<Entry>, store::Unavailable> {
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    let entries : Vec<String> = try!(store.read(|conn| conn.lrange(audit_key!(), 0, limit as isize - 1)));
+    Ok(entries.iter().filter_map(|entry| json::decode(entry).ok()).collect())
+}
@@ -3,18 +3,20 @@ extern crate persistent;
 use iron::typemap::Key;
 use std::collections::HashMap;
 
-extern crate bit_vec;
-use bit_vec::BitVec;
-
-extern crate r2d2;
-extern crate r2d2_redis;
-extern crate redis;
-
 use pies;
+use experiments;
+use index;
+use search;
+use diet;
+use templates;
+use images;
+use routes;
+use metrics;
+use store;
 
 #[derive(Copy, Clone)]
 pub struct Redis;
-impl Key for Redis { type Value = r2d2::Pool<r2d2_redis::RedisConnectionManager>; }
+impl Key for Redis { type Value = store::Store; }
 
 #[derive(Copy, Clone)]
 pub struct SortedPies;
@@ -29,6 +31,37 @@ pub struct IdIndex;
 impl Key for IdIndex { type Value = HashMap<u64, (pies::Pie, usize)>; }
 
 #[derive(Copy, Clone)]
-pub struct LabelBitVec;
-impl Key for LabelBitVec { type Value = HashMap<String, BitVec>; }
+pub struct LabelIndex;
+impl Key for LabelIndex { type Value = index::LabelIndex; }
 
+#[derive(Copy, Clone)]
+pub struct Experiment;
+impl Key for Experiment { type Value = experiments::Experiment; }
+
+#[derive(Copy, Clone)]
+pub struct SearchIndex;
+impl Key for SearchIndex { type Value = search::SearchIndex; }
+
+#[derive(Copy, Clone)]
+pub struct DietIndex;
+impl Key for DietIndex { type Value = diet::DietIndex; }
+
+#[derive(Copy, Clone)]
+pub struct Templates;
+impl Key for Templates { type Value = templates::Templates; }
+
+#[derive(Copy, Clone)]
+pub struct Images;
+impl Key for Images { type Value = images::Images; }
+
+#[derive(Copy, Clone)]
+pub struct Routes;
+impl Key for Routes { type Value = routes::Routes; }
+
+#[derive(Copy, Clone)]
+pub struct OpenApi;
+impl Key for OpenApi { type Value = String; }
+
+#[derive(Copy, Clone)]
+pub struct Metrics;
+impl Key for Metrics { type Value = metrics::Metrics; }
@@ -0,0 +1,272 @@
+extern crate url;
+use url::Url;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+use pies;
+use taxonomy;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    // the pie is dropped from the catalog, or the server refuses to start in strict mode
+    Error,
+    // the pie is kept, but strict mode still refuses to start
+    Warning
+}
+
+#[derive(Clone, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    // position in the catalog as loaded, since ids themselves may be the problem
+    pub index: usize,
+    pub pie_id: u64,
+    pub field: &'static str,
+    pub message: String
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning"
+        };
+        write!(f, "{}: pie {} (entry {}) {}: {}", severity, self.pie_id, self.index, self.field, self.message)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub issues: Vec<Issue>
+}
+
+impl Report {
+    fn add(&mut self, severity: Severity, index: usize, pie: &pies::Pie, field: &'static str, message: String) {
+        self.issues.push(Issue {
+            severity: severity,
+            index: index,
+            pie_id: pie.id,
+            field: field,
+            message: message
+        });
+    }
+
+    pub fn errors(&self) -> usize {
+        self.issues.iter().filter(|issue| issue.severity == Severity::Error).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.issues.iter().filter(|issue| issue.severity == Severity::Warning).count()
+    }
+
+    pub fn passes(&self, strict: bool) -> bool {
+        self.errors() == 0 && (!strict || self.warnings() == 0)
+    }
+
+    pub fn log(&self) {
+        for issue in &self.issues {
+            match issue.severity {
+                Severity::Error => error!("{}", issue),
+                Severity::Warning => warn!("{}", issue)
+            }
+        }
+        info!("catalog: {} errors, {} warnings", self.errors(), self.warnings());
+    }
+
+    fn rejected(&self) -> HashSet<usize> {
+        self.issues.iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .map(|issue| issue.index)
+            .collect()
+    }
+}
+
+pub fn validate(pies: &Vec<pies::Pie>, taxonomy: &taxonomy::Taxonomy) -> Report {
+    let mut report = Report::default();
+    let mut first_seen: HashMap<u64, usize> = HashMap::new();
+
+    for (i, pie) in pies.iter().enumerate() {
+        match first_seen.get(&pie.id) {
+            Some(&first) => report.add(Severity::Error, i, pie, "id",
+                                       format!("duplicates the id of entry {}", first)),
+            None => {}
+        }
+        first_seen.entry(pie.id).or_insert(i);
+
+        if pie.name.trim().is_empty() {
+            report.add(Severity::Warning, i, pie, "name", "is empty".to_string());
+        }
+
+        if !pie.price_per_slice.is_finite() {
+            report.add(Severity::Error, i, pie, "price_per_slice", "must be a finite number".to_string());
+        } else if pie.price_per_slice < 0.0 {
+            report.add(Severity::Error, i, pie, "price_per_slice", "must not be negative".to_string());
+        } else if pie.price_per_slice == 0.0 {
+            report.add(Severity::Warning, i, pie, "price_per_slice", "is free".to_string());
+        }
+
+        if pie.slices == 0 {
+            report.add(Severity::Warning, i, pie, "slices", "is zero, so the pie starts sold out".to_string());
+        }
+
+        match Url::parse(&pie.image_url) {
+            Ok(ref url) if url.scheme() == "http" || url.scheme() == "https" => {},
+            Ok(ref url) => report.add(Severity::Warning, i, pie, "image_url",
+                                      format!("uses {} rather than http or https", url.scheme())),
+            Err(e) => report.add(Severity::Error, i, pie, "image_url",
+                                 format!("is not a valid url: {}", e))
+        }
+
+        if pie.labels.is_empty() {
+            report.add(Severity::Warning, i, pie, "labels", "is empty, so the pie is never recommended".to_string());
+        }
+
+        let mut labels = HashSet::new();
+        for label in &pie.labels {
+            let canonical = taxonomy.canonical(label);
+            if canonical.is_empty() {
+                report.add(Severity::Error, i, pie, "labels", "contains a blank label".to_string());
+            } else if !labels.insert(canonical.clone()) {
+                report.add(Severity::Warning, i, pie, "labels", format!("lists {} more than once", label));
+            } else if !taxonomy.is_empty() && !taxonomy.is_known(&canonical) {
+                report.add(Severity::Warning, i, pie, "labels", format!("{} is not in the taxonomy", label));
+            }
+        }
+
+        let allergen_fields = [("contains", &pie.contains), ("may_contain", &pie.may_contain), ("certified", &pie.certified)];
+        for &(field, values) in allergen_fields.iter() {
+            if let Some(ref values) = *values {
+                if values.iter().any(|value| taxonomy::normalize(value).is_empty()) {
+                    report.add(Severity::Error, i, pie, field, "contains a blank entry".to_string());
+                }
+            }
+        }
+    }
+
+    report
+}
+
+// the catalog minus every pie with an error against it
+pub fn accept(pies: Vec<pies::Pie>, report: &Report) -> Vec<pies::Pie> {
+    let rejected = report.rejected();
+    pies.into_iter()
+        .enumerate()
+        .filter(|&(i, _)| !rejected.contains(&i))
+        .map(|(_, pie)| pie)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use pies::Pie;
+    use taxonomy::{Taxonomy, TaxonomyConfig};
+
+    use super::{accept, validate, Severity};
+
+    fn apple() -> Pie {
+        Pie {
+            id: 1,
+            name: "Apple".to_string(),
+            image_url: "http://example.com/apple.jpg".to_string(),
+            price_per_slice: 3.5,
+            slices: 8,
+            labels: vec!["fruit".to_string()],
+            contains: None,
+            may_contain: None,
+            certified: None
+        }
+    }
+
+    fn issues(pies: &Vec<Pie>, taxonomy: &Taxonomy) -> Vec<(Severity, usize, &'static str)> {
+        validate(pies, taxonomy).issues.iter().map(|issue| (issue.severity, issue.index, issue.field)).collect()
+    }
+
+    #[test]
+    fn a_clean_catalog_passes_strict() {
+        let report = validate(&vec![apple()], &Taxonomy::default());
+        assert!(report.issues.is_empty());
+        assert!(report.passes(true));
+    }
+
+    #[test]
+    fn warnings_fail_only_strict() {
+        let mut free = apple();
+        free.price_per_slice = 0.0;
+        free.slices = 0;
+        free.name = " ".to_string();
+        let report = validate(&vec![free], &Taxonomy::default());
+        assert_eq!((report.errors(), report.warnings()), (0, 3));
+        assert!(report.passes(false));
+        assert!(!report.passes(true));
+    }
+
+    #[test]
+    fn errors_fail_either_way_and_drop_the_pie() {
+        let mut negative = apple();
+        negative.id = 2;
+        negative.price_per_slice = -1.0;
+        let pies = vec![apple(), negative];
+        let report = validate(&pies, &Taxonomy::default());
+        assert_eq!(report.errors(), 1);
+        assert!(!report.passes(false));
+        assert!(!report.passes(true));
+        let accepted = accept(pies, &report);
+        assert_eq!(accepted.iter().map(|pie| pie.id).collect::<Vec<u64>>(), vec![1]);
+    }
+
+    #[test]
+    fn duplicate_ids_keep_the_first() {
+        let pies = vec![apple(), apple()];
+        assert_eq!(issues(&pies, &Taxonomy::default()), vec![(Severity::Error, 1, "id")]);
+        let report = validate(&pies, &Taxonomy::default());
+        assert_eq!(report.issues[0].message, "duplicates the id of entry 0");
+        assert_eq!(accept(pies, &report).len(), 1);
+    }
+
+    #[test]
+    fn prices_must_be_finite() {
+        let mut nan = apple();
+        nan.price_per_slice = ::std::f64::NAN;
+        assert_eq!(issues(&vec![nan], &Taxonomy::default()), vec![(Severity::Error, 0, "price_per_slice")]);
+    }
+
+    #[test]
+    fn image_urls_must_parse_and_should_be_http() {
+        let mut relative = apple();
+        relative.image_url = "apple.jpg".to_string();
+        let mut ftp = apple();
+        ftp.image_url = "ftp://example.com/apple.jpg".to_string();
+        assert_eq!(issues(&vec![relative], &Taxonomy::default()), vec![(Severity::Error, 0, "image_url")]);
+        assert_eq!(issues(&vec![ftp], &Taxonomy::default()), vec![(Severity::Warning, 0, "image_url")]);
+    }
+
+    #[test]
+    fn labels_are_checked_against_the_taxonomy() {
+        let mut children = HashMap::new();
+        children.insert("fruit".to_string(), vec!["apple".to_string()]);
+        let taxonomy = Taxonomy::new(&TaxonomyConfig { children: Some(children), synonyms: None });
+        let mut labelled = apple();
+        labelled.labels = vec!["Fruit".to_string(), "fruit".to_string(), "cherry".to_string(), " ".to_string()];
+        assert_eq!(issues(&vec![labelled.clone()], &taxonomy),
+                   vec![(Severity::Warning, 0, "labels"), (Severity::Warning, 0, "labels"), (Severity::Error, 0, "labels")]);
+        // without a taxonomy any label goes
+        assert_eq!(issues(&vec![labelled], &Taxonomy::default()),
+                   vec![(Severity::Warning, 0, "labels"), (Severity::Error, 0, "labels")]);
+        let mut unlabelled = apple();
+        unlabelled.labels = vec![];
+        assert_eq!(issues(&vec![unlabelled], &taxonomy), vec![(Severity::Warning, 0, "labels")]);
+    }
+
+    #[test]
+    fn allergen_lists_must_not_hold_blanks() {
+        let mut blank = apple();
+        blank.contains = Some(vec![]);
+        blank.may_contain = Some(vec!["nuts".to_string(), "_".to_string()]);
+        blank.certified = Some(vec!["".to_string()]);
+        assert_eq!(issues(&vec![blank], &Taxonomy::default()),
+                   vec![(Severity::Error, 0, "may_contain"), (Severity::Error, 0, "certified")]);
+    }
+}
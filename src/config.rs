@@ -0,0 +1,95 @@
+extern crate rustc_serialize;
+use rustc_serialize::json;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use taxonomy;
+use experiments;
+use ratelimit;
+use store;
+
+const DEFAULT_PATH: &'static str = "bakeoff.json";
+
+// every section is optional so an empty or missing file means all defaults
+#[derive(RustcDecodable, Clone, Debug, Default)]
+pub struct Config {
+    pub taxonomy: Option<taxonomy::TaxonomyConfig>,
+    // directory holding layout.mustache and the page templates
+    pub templates: Option<String>,
+    // refuse to start on any catalog error or warning, rather than dropping bad pies
+    pub strict_catalog: Option<bool>,
+    // a url or a file path
+    pub catalog: Option<String>,
+    // json, csv, yaml or toml; detected from the catalog's extension when absent
+    pub catalog_format: Option<String>,
+    // separates the values in a csv catalog's labels and allergen columns
+    pub catalog_list_delimiter: Option<String>,
+    // holds <pie_id>.jpg and friends, with resized copies cached underneath
+    pub images: Option<String>,
+    // e.g. https://pies.example.com, for links in responses; taken from the request when absent
+    pub public_url: Option<String>,
+    // build those links from X-Forwarded-Proto and X-Forwarded-Host, when behind a proxy we run
+    pub trust_forwarded_host: Option<bool>,
+    // usernames who can see everyone's purchases and use the admin routes
+    pub admins: Option<Vec<String>>,
+    // usernames who can see everyone's purchases, restock and refund
+    pub staff: Option<Vec<String>>,
+    pub api_keys: Option<Vec<ApiKeyConfig>>,
+    // let /pie/:pie_id/purchases buy with GET, for clients that haven't moved to POST
+    pub legacy_get_purchases: Option<bool>,
+    // error, warn, info, debug or trace; BAKEOFF_LOG wins over it
+    pub log_level: Option<String>,
+    // seconds to let running requests finish after SIGTERM or SIGINT, 30 by default
+    pub shutdown_timeout: Option<u64>,
+    pub rate_limits: Option<ratelimit::RateLimitConfig>,
+    // timeouts, retries and the circuit breaker in front of redis
+    pub redis: Option<store::StoreConfig>,
+    // the arms recommend splits users between
+    pub experiment: Option<experiments::ExperimentConfig>
+}
+
+// a service account, which sends its key in X-Api-Key
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    pub key: String,
+    // customer, staff or admin
+    pub role: String
+}
+
+impl Config {
+    pub fn catalog(&self) -> String {
+        self.catalog.clone().unwrap_or("http://stash.truex.com/tech/bakeoff/pies.json".to_string())
+    }
+
+    pub fn images_dir(&self) -> String {
+        self.images.clone().unwrap_or("images".to_string())
+    }
+
+    pub fn catalog_list_delimiter(&self) -> String {
+        self.catalog_list_delimiter.clone().unwrap_or(";".to_string())
+    }
+
+    pub fn templates_dir(&self) -> String {
+        self.templates.clone().unwrap_or("templates".to_string())
+    }
+}
+
+// BAKEOFF_CONFIG names the file, falling back to bakeoff.json in the working directory
+pub fn load() -> Config {
+    let path = env::var("BAKEOFF_CONFIG").unwrap_or(DEFAULT_PATH.to_string());
+    if !Path::new(&path).exists() {
+        info!("no config at {}, using defaults", path);
+        return Default::default();
+    }
+
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .expect("failed to read config");
+
+    json::decode(&contents).expect("failed to parse config")
+}
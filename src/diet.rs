@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use pies;
+use index::Bitmap;
+use taxonomy;
+
+// what a customer refuses: pies that contain or may contain any of the
+// allergens, and pies lacking any of the certifications
+#[derive(Clone, Debug, Default)]
+pub struct Exclusions {
+    pub allergens: Vec<String>,
+    pub certified: Vec<String>
+}
+
+impl Exclusions {
+    pub fn is_empty(&self) -> bool {
+        self.allergens.is_empty() && self.certified.is_empty()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DietIndex {
+    allergens: HashMap<String, Bitmap>,
+    certified: HashMap<String, Bitmap>,
+    // pies with no contains list, which could contain anything
+    undeclared: Bitmap,
+    taxonomy: taxonomy::Taxonomy
+}
+
+// a pie carries its own labels and their ancestors: certified organic-apple is
+// certified organic
+fn certified_labels(taxonomy: &taxonomy::Taxonomy, value: &str) -> Vec<String> {
+    taxonomy.expand(value)
+}
+
+// and for allergens their descendants too, since a pie that contains nuts may well
+// contain almonds; erring toward excluding it is the safe way
+fn allergen_labels(taxonomy: &taxonomy::Taxonomy, value: &str) -> Vec<String> {
+    let mut labels = taxonomy.expand(value);
+    for label in taxonomy.descendants(value) {
+        if !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+    labels
+}
+
+fn index_by(pies: &Vec<pies::Pie>,
+            taxonomy: &taxonomy::Taxonomy,
+            field: &Fn(&pies::Pie) -> Vec<String>,
+            labels: fn(&taxonomy::Taxonomy, &str) -> Vec<String>) -> HashMap<String, Bitmap> {
+    let mut positions: HashMap<String, Vec<u32>> = HashMap::new();
+    for (i, pie) in pies.iter().enumerate() {
+        for value in field(pie) {
+            for label in labels(taxonomy, &value) {
+                let list = positions.entry(label).or_insert(vec![]);
+                if list.last() != Some(&(i as u32)) {
+                    list.push(i as u32);
+                }
+            }
+        }
+    }
+    positions.into_iter().map(|(label, list)| (label, Bitmap::from_sorted(&list))).collect()
+}
+
+impl DietIndex {
+    // "may contain" is treated exactly like "contains": exclusions are a safety guarantee.
+    // for the same reason a pie without a contains list is left out whenever any
+    // allergen is excluded; an empty list is how a pie says it contains none
+    pub fn new(pies: &Vec<pies::Pie>, taxonomy: &taxonomy::Taxonomy) -> DietIndex {
+        let undeclared: Vec<u32> = pies.iter().enumerate()
+            .filter(|&(_, pie)| pie.contains.is_none())
+            .map(|(i, _)| i as u32)
+            .collect();
+
+        DietIndex {
+            allergens: index_by(pies, taxonomy, &|pie| {
+                let mut all = pie.contains.clone().unwrap_or(vec![]);
+                all.extend(pie.may_contain.clone().unwrap_or(vec![]));
+                all
+            }, allergen_labels),
+            certified: index_by(pies, taxonomy, &|pie| pie.certified.clone().unwrap_or(vec![]), certified_labels),
+            undeclared: Bitmap::from_sorted(&undeclared),
+            taxonomy: taxonomy.clone()
+        }
+    }
+
+    pub fn parse(&self, allergens: &Vec<String>, certified: &Vec<String>) -> Exclusions {
+        let canonical = |values: &Vec<String>| -> Vec<String> {
+            values.iter()
+                .map(|label| self.taxonomy.canonical(label))
+                .filter(|label| !label.is_empty())
+                .collect()
+        };
+
+        Exclusions {
+            allergens: canonical(allergens),
+            certified: canonical(certified)
+        }
+    }
+
+    // narrows a label match to the pies the exclusions allow
+    pub fn filter(&self, matching: &Bitmap, exclusions: &Exclusions) -> Bitmap {
+        let mut result = matching.clone();
+
+        for label in &exclusions.certified {
+            result = match self.certified.get(label) {
+                Some(bitmap) => result.and(bitmap),
+                None => return Bitmap::from_sorted(&[])
+            };
+        }
+
+        if !exclusions.allergens.is_empty() {
+            result = result.and_not(&self.undeclared);
+        }
+        for label in &exclusions.allergens {
+            if let Some(bitmap) = self.allergens.get(label) {
+                result = result.and_not(bitmap);
+            }
+        }
+
+        result
+    }
+
+    // checked against the pie itself rather than the bitmaps, as the last word
+    // before a pie is shown or recommended to someone who excluded something
+    pub fn allows(&self, pie: &pies::Pie, exclusions: &Exclusions) -> bool {
+        if exclusions.is_empty() {
+            return true;
+        }
+        if !exclusions.allergens.is_empty() && pie.contains.is_none() {
+            return false;
+        }
+
+        let expand = |values: &Option<Vec<String>>, labels: fn(&taxonomy::Taxonomy, &str) -> Vec<String>| -> Vec<String> {
+            values.iter()
+                .flat_map(|values| values.iter())
+                .flat_map(|value| labels(&self.taxonomy, value))
+                .collect()
+        };
+
+        let mut allergens = expand(&pie.contains, allergen_labels);
+        allergens.extend(expand(&pie.may_contain, allergen_labels));
+        let certified = expand(&pie.certified, certified_labels);
+
+        exclusions.allergens.iter().all(|label| !allergens.contains(label)) &&
+            exclusions.certified.iter().all(|label| certified.contains(label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::DietIndex;
+    use index::Bitmap;
+    use pies;
+    use taxonomy;
+
+    fn nut_taxonomy() -> taxonomy::Taxonomy {
+        let mut children = HashMap::new();
+        children.insert("nuts".to_string(), vec!["almond".to_string(), "walnut".to_string()]);
+        let mut synonyms = HashMap::new();
+        synonyms.insert("gf".to_string(), "gluten-free".to_string());
+        taxonomy::Taxonomy::new(&taxonomy::TaxonomyConfig { children: Some(children), synonyms: Some(synonyms) })
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    // positions 0 to 5: contains, may contain and certified for each
+    fn catalog() -> Vec<pies::Pie> {
+        let lists: Vec<(Option<&[&str]>, &[&str], &[&str])> = vec![
+            (Some(&["almond"]), &[], &["gluten-free"]),
+            (Some(&[]), &["walnut"], &[]),
+            (Some(&["nuts"]), &[], &["gluten-free"]),
+            (Some(&["dairy"]), &[], &[]),
+            (Some(&[]), &[], &["gluten-free", "vegan"]),
+            (None, &[], &["vegan"])
+        ];
+        lists.into_iter().enumerate().map(|(i, (contains, may_contain, certified))| pies::Pie {
+            id: i as u64 + 1,
+            name: format!("pie {}", i + 1),
+            image_url: String::new(),
+            price_per_slice: 1.0,
+            slices: 8,
+            labels: vec![],
+            contains: contains.map(strings),
+            may_contain: Some(strings(may_contain)),
+            certified: Some(strings(certified))
+        }).collect()
+    }
+
+    #[test]
+    fn nothing_excluded() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let exclusions = index.parse(&vec![], &vec![]);
+        let everything = Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]);
+        assert_eq!(index.filter(&everything, &exclusions).to_vec(), vec![0, 1, 2, 3, 4, 5]);
+        assert!(pies.iter().all(|pie| index.allows(pie, &exclusions)));
+    }
+
+    #[test]
+    fn excludes_contains_and_may_contain() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let everything = Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]);
+
+        let walnut = index.parse(&strings(&["walnut"]), &vec![]);
+        assert_eq!(index.filter(&everything, &walnut).to_vec(), vec![0, 3, 4]);
+        assert!(!index.allows(&pies[1], &walnut));
+        assert!(index.allows(&pies[3], &walnut));
+
+        let dairy = index.parse(&strings(&["dairy"]), &vec![]);
+        assert_eq!(index.filter(&everything, &dairy).to_vec(), vec![0, 1, 2, 4]);
+        assert!(!index.allows(&pies[3], &dairy));
+    }
+
+    #[test]
+    fn excluding_a_parent_excludes_its_children() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let nuts = index.parse(&strings(&["nuts"]), &vec![]);
+        assert_eq!(index.filter(&Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]), &nuts).to_vec(), vec![3, 4]);
+        assert!(!index.allows(&pies[0], &nuts));
+        assert!(!index.allows(&pies[1], &nuts));
+    }
+
+    #[test]
+    fn excluding_a_child_excludes_pies_tagged_with_its_parent() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let almond = index.parse(&strings(&["almond"]), &vec![]);
+        assert_eq!(index.filter(&Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]), &almond).to_vec(), vec![1, 3, 4]);
+        assert!(!index.allows(&pies[2], &almond));
+        assert!(index.allows(&pies[1], &almond));
+    }
+
+    #[test]
+    fn a_pie_without_a_contains_list_is_excluded_for_any_allergen() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let everything = Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]);
+
+        let sesame = index.parse(&strings(&["sesame"]), &vec![]);
+        assert_eq!(index.filter(&everything, &sesame).to_vec(), vec![0, 1, 2, 3, 4]);
+        assert!(!index.allows(&pies[5], &sesame));
+        // certifications alone say nothing about allergens
+        let vegan = index.parse(&vec![], &strings(&["vegan"]));
+        assert_eq!(index.filter(&everything, &vegan).to_vec(), vec![4, 5]);
+        assert!(index.allows(&pies[5], &vegan));
+    }
+
+    #[test]
+    fn requires_every_certification() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let everything = Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]);
+
+        let gluten_free = index.parse(&vec![], &strings(&["gluten-free"]));
+        assert_eq!(index.filter(&everything, &gluten_free).to_vec(), vec![0, 2, 4]);
+        assert!(!index.allows(&pies[1], &gluten_free));
+
+        let both = index.parse(&vec![], &strings(&["gluten-free", "vegan"]));
+        assert_eq!(index.filter(&everything, &both).to_vec(), vec![4]);
+        assert!(!index.allows(&pies[0], &both));
+
+        let kosher = index.parse(&vec![], &strings(&["kosher"]));
+        assert!(index.filter(&everything, &kosher).is_empty());
+        assert!(!index.allows(&pies[4], &kosher));
+    }
+
+    #[test]
+    fn understands_synonyms_and_spelling() {
+        let pies = catalog();
+        let index = DietIndex::new(&pies, &nut_taxonomy());
+        let exclusions = index.parse(&strings(&[" Almond "]), &strings(&["GF"]));
+        assert_eq!(exclusions.allergens, vec!["almond"]);
+        assert_eq!(exclusions.certified, vec!["gluten-free"]);
+        assert_eq!(index.filter(&Bitmap::from_sorted(&[0, 1, 2, 3, 4, 5]), &exclusions).to_vec(), vec![4]);
+        assert!(index.allows(&pies[4], &exclusions));
+    }
+}
@@ -1,5 +1,9 @@
 extern crate iron;
 use iron::prelude::*;
+use iron::status;
+use iron::method::Method;
+use iron::headers::{ContentType, IfNoneMatch};
+use iron::mime::{Mime, TopLevel, SubLevel};
 
 extern crate router;
 use router::Router;
@@ -13,9 +17,7 @@ extern crate rustc_serialize;
 use rustc_serialize::json;
 
 use std::str::FromStr;
-use std::str;
-
-extern crate mustache;
+use std::io::Read as io_read;
 
 use response::core::borrow::Borrow;
 
@@ -23,63 +25,175 @@ use response;
 use pies;
 use pie_state;
 use cache;
+use experiments;
+use search;
+use index;
+use templates;
+use forms;
+use params;
+use snapshot;
+use images;
+use routes;
+use accounts;
+use audit;
+use idempotency;
+use access_log;
+use health;
+use store;
 
 pub fn hello_world(_: &mut Request) -> IronResult<Response> {
     response::text("Hello, World!".to_string())
 }
 
 pub fn pies(req: &mut Request) -> IronResult<Response> {
+    let templates = req.get::<Read<cache::Templates>>().unwrap();
+    let pies = show_pies(req);
+    response::html(templates.render("pies", "Pies", &pies::ShowPies { pies: pies }))
+}
 
+// the catalog filtered by ?labels, ?exclude and ?certified, with live remaining counts
+pub fn show_pies(req: &mut Request) -> Vec<pies::ShowPie> {
     let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
     let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let label_index = req.get::<Read<cache::LabelIndex>>().unwrap();
+    let diet_index = req.get::<Read<cache::DietIndex>>().unwrap();
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+    let images = req.get::<Read<cache::Images>>().unwrap();
+
+    let url = req.url.clone().into_generic_url();
+
+    let mut labels = vec![];
+    let mut exclude = vec![];
+    let mut certified = vec![];
+
+    for (key, value) in url.query_pairs() {
+        let list = match key.borrow() {
+            "labels" => &mut labels,
+            "exclude" => &mut exclude,
+            "certified" => &mut certified,
+            _ => continue
+        };
+        for item in value.split(",") {
+            list.push(String::from(item));
+        }
+    };
+
+    let exclusions = diet_index.parse(&exclude, &certified);
+
+    // an unknown label matches nothing rather than being ignored
+    let matching = if labels.len() > 0 {
+        Some(label_index.matching(&labels).unwrap_or(index::Bitmap::from_sorted(&[])))
+    } else {
+        None
+    };
 
     let mut pies = vec![];
-    let mut bytes = vec![];
     let mut ids = vec![];
 
     for (_id, tuple) in id_index.iter() {
+        if let Some(ref bitmap) = matching {
+            if !bitmap.contains(tuple.1 as u32) {
+                continue;
+            }
+        }
+        if !diet_index.allows(&tuple.0, &exclusions) {
+            continue;
+        }
         let show_pie = pies::ShowPie {
             id: tuple.0.id.clone(),
             name: tuple.0.name.clone(),
             image_url: tuple.0.image_url.clone(),
             price_per_slice: tuple.0.price_per_slice.clone(),
-            remaining_slices: 0,
-            purchases: vec![]
+            remaining_slices: None,
+            degraded: false,
+            purchases: vec![],
+            contains: tuple.0.contains.clone().unwrap_or(vec![]),
+            may_contain: tuple.0.may_contain.clone().unwrap_or(vec![]),
+            certified: tuple.0.certified.clone().unwrap_or(vec![]),
+            url: pie_path(&routes, "pie", tuple.0.id),
+            image_src: image_src(&routes, &images, &tuple.0),
+            purchase_url: pie_path(&routes, "purchase", tuple.0.id)
         };
         ids.push(&tuple.0.id);
         pies.push(show_pie);
     }
 
-    let all_remaining = pie_state::get_all_remaining(&redis, &ids);
+    // without redis the catalog is still worth showing, only without counts
+    match pie_state::get_all_remaining(&redis, &ids) {
+        Ok(all_remaining) => for (remaining, pie) in all_remaining.iter().zip(pies.iter_mut()) {
+            pie.remaining_slices = Some(remaining.clone());
+        },
+        Err(_) => for pie in pies.iter_mut() {
+            pie.degraded = true;
+        }
+    }
 
-    for (remaining, pie) in all_remaining.iter().zip(pies.iter_mut()) {
-        pie.remaining_slices = remaining.clone();
+    pies
+}
+
+// one pie with its live remaining count, and whichever purchases the viewer may see
+// degraded, without either, while redis is unavailable
+pub fn show_pie(redis: &store::Store,
+                routes: &routes::Routes,
+                images: &images::Images,
+                pie: &pies::Pie,
+                viewer: Option<&accounts::User>) -> pies::ShowPie {
+    let remaining = pie_state::get_remaining(redis, pie).ok();
+    let purchases = match (viewer, remaining) {
+        (Some(user), Some(_)) => pie_state::pie_purchases(redis, pie).unwrap_or(vec![]).into_iter()
+            .filter(|purchase| user.can_see_purchases_of(&purchase.username))
+            .collect(),
+        _ => vec![]
+    };
+
+    pies::ShowPie {
+        id: pie.id.clone(),
+        name: pie.name.clone(),
+        image_url: pie.image_url.clone(),
+        price_per_slice: pie.price_per_slice.clone(),
+        remaining_slices: remaining,
+        degraded: remaining.is_none(),
+        purchases: purchases,
+        contains: pie.contains.clone().unwrap_or(vec![]),
+        may_contain: pie.may_contain.clone().unwrap_or(vec![]),
+        certified: pie.certified.clone().unwrap_or(vec![]),
+        url: pie_path(routes, "pie", pie.id),
+        image_src: image_src(routes, images, pie),
+        purchase_url: pie_path(routes, "purchase", pie.id)
     }
+}
 
-    pie_template().render(&mut bytes, &pies::ShowPies { pies: pies }).unwrap();
-    response::html(format!("<html>{}</html>", str::from_utf8(&bytes).unwrap()))
+fn pie_path(routes: &routes::Routes, route: &str, pie_id: u64) -> String {
+    routes.path(route, &[("pie_id", &pie_id.to_string())])
 }
 
-fn pie_template() -> mustache::Template {
-    mustache::compile_str("
-    {{#pies}}
-    <h1><a href=\"/pies/{{id}}\">{{name}}</a></h1>
-    <img src=\"{{image_url}}\" width=\"50%\"></img>
-    <p>price: {{price_per_slice}}</p>
-    <p>remaining: {{remaining_slices}}</p>
-    <p> {{#purchases}}
-        <p>{{username}} purchased {{slices}}<p>
-        {{/purchases}}
-    </p>
-    {{/pies}}
-    ")
+// the resized local image when there is one, otherwise the catalog's own image_url,
+// and the placeholder only for a pie with neither
+fn image_src(routes: &routes::Routes, images: &images::Images, pie: &pies::Pie) -> String {
+    if images.has_image(pie.id) || pie.image_url.trim().is_empty() {
+        format!("{}?size=medium", pie_path(routes, "image", pie.id))
+    } else {
+        pie.image_url.clone()
+    }
+}
+
+fn error_page(templates: &templates::Templates, status: status::Status, message: &str) -> IronResult<Response> {
+    let page = templates::ErrorPage {
+        status: status.to_u16(),
+        message: message.to_string()
+    };
+    response::html_status(status, templates.render("error", message, &page))
 }
 
 pub fn pie(req: &mut Request) -> IronResult<Response> {
 
     let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
     let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let templates = req.get::<Read<cache::Templates>>().unwrap();
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+    let images = req.get::<Read<cache::Images>>().unwrap();
     let url_path = req.url.path();
+    let wants_json = url_path.last().map_or(false, |x| x.ends_with("json"));
     let url_end = url_path.last();
 
     // req.extensions must go last because borrow checker is dumb
@@ -97,20 +211,14 @@ pub fn pie(req: &mut Request) -> IronResult<Response> {
     // return if we can't find pie in cache
     let (pie, _bitvec_pos) = if let Some(x) = id_index.get(&pie_id) {
         x.clone()
-    } else {
+    } else if wants_json {
         return response::not_found()
+    } else {
+        return error_page(&templates, status::NotFound, "No such pie.")
     };
 
-    let remaining = pie_state::get_remaining(&redis, &pie);
-
-    let show_pie = pies::ShowPie {
-        id: pie.id.clone(),
-        name: pie.name.clone(),
-        image_url: pie.image_url.clone(),
-        price_per_slice: pie.price_per_slice.clone(),
-        remaining_slices: remaining,
-        purchases: pie_state::pie_purchases(&redis, &pie)
-    };
+    let viewer = accounts::current_user(req);
+    let show_pie = show_pie(&redis, &routes, &images, &pie, viewer.as_ref());
 
     match url_end {
         Some(x) if x.ends_with("json") => {
@@ -118,26 +226,119 @@ pub fn pie(req: &mut Request) -> IronResult<Response> {
             response::json(data)
         },
         Some(_) => {
-            let mut bytes = vec![];
-            let mut pies = vec![];
-            pies.push(show_pie);
-            pie_template().render(&mut bytes, &pies::ShowPies { pies: pies }).unwrap();
-
-            response::html(format!("<html>{}</html>", str::from_utf8(&bytes).unwrap()))
+            let token = forms::csrf_cookie(req).unwrap_or_else(forms::new_csrf_token);
+            let page = pies::PiePage {
+                pie: show_pie,
+                csrf_token: token.clone(),
+                username: viewer.map(|user| user.username),
+                login_url: routes.path("login_page", &[])
+            };
+            let mut res = try!(response::html(templates.render("pie", &page.pie.name, &page)));
+            forms::set_csrf_cookie(&mut res, &token);
+            Ok(res)
         },
         _ => response::not_found()
     }
 }
 
+pub enum PurchaseOutcome {
+    Bought,
+    Glutton,
+    Gone,
+    BadMath,
+    Invalid(Vec<params::FieldError>),
+    Forbidden,
+    Unauthorized,
+    // a retry, by its Idempotency-Key, of an order still being bought
+    InProgress,
+    // an Idempotency-Key sent before with a different order
+    KeyReused,
+    // redis couldn't be reached, so nothing was bought
+    Unavailable(store::Unavailable)
+}
+
+impl PurchaseOutcome {
+    // for the access log; the first four are pie_state's PurchaseStatus
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PurchaseOutcome::Bought => "success",
+            PurchaseOutcome::Glutton => "fatty",
+            PurchaseOutcome::Gone => "gone",
+            PurchaseOutcome::BadMath => "bad_math",
+            PurchaseOutcome::Invalid(_) => "invalid",
+            PurchaseOutcome::Forbidden => "forbidden",
+            PurchaseOutcome::Unauthorized => "unauthorized",
+            PurchaseOutcome::InProgress => "in_progress",
+            PurchaseOutcome::KeyReused => "key_reused",
+            PurchaseOutcome::Unavailable(_) => "unavailable"
+        }
+    }
+}
+
+fn purchase_json(outcome: PurchaseOutcome) -> IronResult<Response> {
+    match outcome {
+        PurchaseOutcome::Bought => response::purchased(),
+        PurchaseOutcome::Glutton => response::glutton(),
+        PurchaseOutcome::Gone => response::gone(),
+        PurchaseOutcome::BadMath => response::bad_math(),
+        PurchaseOutcome::Invalid(ref errors) => response::invalid(errors),
+        PurchaseOutcome::Forbidden => response::forbidden(),
+        PurchaseOutcome::Unauthorized => response::unauthorized(),
+        PurchaseOutcome::InProgress => response::in_progress(),
+        PurchaseOutcome::KeyReused => response::key_reused(),
+        PurchaseOutcome::Unavailable(unavailable) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+// the same outcomes and status codes as the json responses, as a page for the html form
+fn purchase_page(templates: &templates::Templates, routes: &routes::Routes,
+                 outcome: PurchaseOutcome, pie: &pies::Pie) -> IronResult<Response> {
+    let retry_after = match outcome {
+        PurchaseOutcome::Unavailable(unavailable) => Some(unavailable.retry_after),
+        _ => None
+    };
+    let (status, message, errors) = match outcome {
+        PurchaseOutcome::Bought => (status::Created, "You bought some pie.", vec![]),
+        PurchaseOutcome::Glutton => (status::TooManyRequests, "Gluttony is discouraged.", vec![]),
+        PurchaseOutcome::Gone => (status::Gone, "No more of that pie.  Try something else.", vec![]),
+        PurchaseOutcome::BadMath => (status::PaymentRequired, "You did math wrong.", vec![]),
+        PurchaseOutcome::Invalid(errors) => (status::BadRequest, "Please check your order.", errors),
+        PurchaseOutcome::Forbidden => (status::Forbidden, "Your session expired, please try again.", vec![]),
+        PurchaseOutcome::Unauthorized => (status::Unauthorized, "Please sign in to buy pie.", vec![]),
+        PurchaseOutcome::InProgress => (status::Conflict, "That order is still being placed.", vec![]),
+        PurchaseOutcome::KeyReused => (status::UnprocessableEntity, "That Idempotency-Key was used for a different order.", vec![]),
+        PurchaseOutcome::Unavailable(_) => (status::ServiceUnavailable, "Pie is unavailable right now, please try again shortly.", vec![])
+    };
+
+    let result = pies::PurchaseResult {
+        status: status.to_u16(),
+        message: message.to_string(),
+        success: status == status::Created,
+        errors: errors,
+        pie_id: pie.id,
+        pie_name: pie.name.clone(),
+        pie_url: pie_path(routes, "pie", pie.id)
+    };
+    let mut res = try!(response::html_status(status, templates.render("purchase", message, &result)));
+    if let Some(retry_after) = retry_after {
+        res.headers.set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+    }
+    Ok(res)
+}
+
 pub fn purchase(req: &mut Request) -> IronResult<Response> {
     let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
     let redis = req.get::<Read<cache::Redis>>().unwrap();
-
-    let extensions = req.extensions.get::<Router>()
-        .unwrap();
+    let experiment = req.get::<Read<cache::Experiment>>().unwrap();
+    let templates = req.get::<Read<cache::Templates>>().unwrap();
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
 
     // return if we can't find pie_id
-    let pie_id = u64::from_str(extensions.find("pie_id").unwrap()).unwrap();
+    let pie_id = {
+        let extensions = req.extensions.get::<Router>()
+            .unwrap();
+        u64::from_str(extensions.find("pie_id").unwrap()).unwrap()
+    };
 
     let (pie, bitvec_pos) = if let Some(x) = id_index.get(&pie_id) {
         x.clone()
@@ -145,118 +346,678 @@ pub fn purchase(req: &mut Request) -> IronResult<Response> {
         return response::not_found()
     };
 
-    let iron_url = req.url.clone();
-    let url = iron_url.into_generic_url();
+    let params = match params::read(req) {
+        Ok(params) => params,
+        Err(errors) => return purchase_json(PurchaseOutcome::Invalid(errors))
+    };
 
-    let mut username = None;
-    let mut amount = None;
-    let mut slices = Some(1);
+    // html form posts answer with a page, and must carry the csrf token
+    let is_form = params.source == params::Source::Form;
+    let respond = |outcome| if is_form {
+        purchase_page(&templates, &routes, outcome, &pie)
+    } else {
+        purchase_json(outcome)
+    };
 
-    for (key, value) in url.query_pairs() {
-        match key.borrow() {
-            "username" => {
-                username = Some(value);
-            },
-            "amount" => {
-                amount = f64::from_str(&value).ok();
-            },
-            "slices" => {
-                slices = i64::from_str(&value).ok();
-            }
-            _ => {}
+    let order = match params::purchase(&params) {
+        Ok(order) => order,
+        Err(errors) => return respond(PurchaseOutcome::Invalid(errors))
+    };
+
+    if is_form && !forms::check_csrf(req, order.csrf_token.as_ref()) {
+        return respond(PurchaseOutcome::Forbidden);
+    }
+
+    // any page can link or embed a get, so a legacy get purchase needs a bearer token or api key
+    if req.method == Method::Get && accounts::by_cookie(req) {
+        return respond(PurchaseOutcome::Unauthorized);
+    }
+
+    let user = match buyer(req, &order) {
+        Ok(user) => user,
+        Err(outcome) => return respond(outcome)
+    };
+
+    // the form's amount is filled in by script; without it, the form means what it shows
+    let amount = if is_form {
+        order.amount.or(Some(pie.price_per_slice * order.slices as f64))
+    } else {
+        order.amount
+    };
+
+    let fingerprint = format!("{} pie={} slices={} amount={:?}",
+                              if is_form { "form" } else { "json" }, pie.id, order.slices, amount);
+    match idempotency::begin(req, &redis, &user.username, fingerprint) {
+        Ok(idempotency::Begin::Fresh(claim)) => {
+            let outcome = buy(&redis, &experiment, &pie, bitvec_pos, &user.username, order.slices, amount);
+            note_purchase(req, outcome.name(), pie.id, order.slices);
+            claim.finish(&redis, respond(outcome))
+        },
+        Ok(idempotency::Begin::Replay(res)) => {
+            note_purchase(req, "replayed", pie.id, order.slices);
+            Ok(res)
+        },
+        Err(refusal) => {
+            let outcome = refused(refusal);
+            note_purchase(req, outcome.name(), pie.id, order.slices);
+            respond(outcome)
         }
+    }
+}
+
+// for the access log and metrics; "replayed" for an idempotent retry
+pub fn note_purchase(req: &mut Request, outcome: &'static str, pie_id: u64, slices: u64) {
+    access_log::outcome(req, outcome);
+    let metrics = req.get::<Read<cache::Metrics>>().unwrap();
+    metrics.purchase(outcome, pie_id, slices);
+}
+
+pub fn refused(refusal: idempotency::Refusal) -> PurchaseOutcome {
+    match refusal {
+        idempotency::Refusal::Invalid(error) => PurchaseOutcome::Invalid(vec![error]),
+        idempotency::Refusal::InProgress => PurchaseOutcome::InProgress,
+        idempotency::Refusal::Reused => PurchaseOutcome::KeyReused,
+        idempotency::Refusal::Unavailable(unavailable) => PurchaseOutcome::Unavailable(unavailable)
+    }
+}
+
+// the signed in user, unless the order names somebody else
+pub fn buyer(req: &Request, order: &params::PurchaseRequest) -> Result<accounts::User, PurchaseOutcome> {
+    let user = match accounts::current_user(req) {
+        Some(user) => user,
+        None => return Err(PurchaseOutcome::Unauthorized)
     };
+    match order.username {
+        Some(ref username) if *username != user.username => Err(PurchaseOutcome::Invalid(vec![params::FieldError {
+            field: "username".to_string(),
+            message: "must be the signed in user".to_string()
+        }])),
+        _ => Ok(user)
+    }
+}
 
-    match (username, amount, slices) {
-        (Some(u), Some(a), Some(s)) => {
-            let price = pie.price_per_slice * s as f64;
+// checks the amount against the price and takes the slices if it adds up
+pub fn buy(redis: &store::Store,
+           experiment: &experiments::Experiment,
+           pie: &pies::Pie,
+           bitvec_pos: usize,
+           username: &String,
+           slices: u64,
+           amount: Option<f64>) -> PurchaseOutcome {
+    match amount {
+        Some(a) => {
+            let price = pie.price_per_slice * slices as f64;
 
             if (price - a).abs() > 1e-5 {
-                response::bad_math()
+                PurchaseOutcome::BadMath
             } else {
-                match pie_state::purchase_pie(&redis, &pie, bitvec_pos, &u.into_owned(), s as isize) {
-                    pie_state::PurchaseStatus::Success => {
-                        response::purchased()
-
+                match pie_state::purchase_pie(redis, pie, bitvec_pos, username, slices) {
+                    Ok(pie_state::PurchaseStatus::Success) => {
+                        // the pie is bought either way, so a missed count is only logged
+                        if experiments::record_conversion(redis, experiment, username, pie, slices).is_err() {
+                            warn!("could not count the purchase of pie {} toward {}", pie.id, experiment.name);
+                        }
+                        PurchaseOutcome::Bought
                     }
-                    pie_state::PurchaseStatus::Fatty => {
-                        response::glutton()
-
+                    Ok(pie_state::PurchaseStatus::Fatty) => {
+                        PurchaseOutcome::Glutton
                     }
-                    pie_state::PurchaseStatus::Gone => {
-                        response::gone()
-
+                    Ok(pie_state::PurchaseStatus::Gone) => {
+                        PurchaseOutcome::Gone
+                    }
+                    Err(unavailable) => {
+                        PurchaseOutcome::Unavailable(unavailable)
                     }
                 }
             }
         },
-        (Some(_u), None, _) => {
-            response::bad_math()
-        },
-        (_, _, _) => {
-            response::error()
+        None => {
+            PurchaseOutcome::BadMath
         }
     }
-
 }
 
 pub fn recommend(req: &mut Request) -> IronResult<Response> {
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+
+    match recommended_pie(req) {
+        Ok(Some(pie)) => response::recommend(routes.url(req, "pie", &[("pie_id", &pie.id.to_string())])),
+        Ok(None) => response::no_recommends(),
+        Err(NotRecommended::Invalid(errors)) => response::invalid(&errors),
+        Err(NotRecommended::Unavailable(unavailable)) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+pub enum NotRecommended {
+    Invalid(Vec<params::FieldError>),
+    Unavailable(store::Unavailable)
+}
+
+// picks a pie with the caller's experiment arm and records that it was shown
+pub fn recommended_pie(req: &mut Request) -> Result<Option<pies::Pie>, NotRecommended> {
     let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let experiment = req.get::<Read<cache::Experiment>>().unwrap();
 
-    let label_bitvecs = req.get::<Read<cache::LabelBitVec>>().unwrap();
+    let label_index = req.get::<Read<cache::LabelIndex>>().unwrap();
+    let diet_index = req.get::<Read<cache::DietIndex>>().unwrap();
     let sorted_pies = req.get::<Read<cache::SortedPies>>().unwrap();
+    let metrics = req.get::<Read<cache::Metrics>>().unwrap();
+
+    let query = try!(params::read(req).and_then(|p| params::recommend(&p)).map_err(NotRecommended::Invalid));
+
+    let exclusions = diet_index.parse(&query.exclude, &query.certified);
+
+    let arm = experiment.assign(&query.username);
+    let pie_opt = try!(pie_state::recommend(
+        &redis,
+        &query.labels,
+        &sorted_pies,
+        &label_index,
+        &diet_index,
+        &exclusions,
+        &query.username,
+        &query.budget,
+        &arm.strategy
+    ).map_err(NotRecommended::Unavailable));
+    debug!("recommending pie {:?}", pie_opt.map(|pie| pie.id));
+    metrics.recommendation(pie_opt.is_some());
+    if let Some(pie) = pie_opt {
+        if experiments::record_recommendation(&redis, &experiment, arm, &query.username, pie).is_err() {
+            warn!("could not count the recommendation of pie {} toward {}", pie.id, experiment.name);
+        }
+    }
+    Ok(pie_opt.cloned())
+}
 
-    let url = req.url.clone().into_generic_url();
+const SEARCH_PER_PAGE: usize = 10;
+const SEARCH_MAX_PER_PAGE: usize = 50;
 
-    let mut labels = vec![];
+pub fn search(req: &mut Request) -> IronResult<Response> {
+    match search_page(req) {
+        Ok(page) => response::json(json::encode(&page).unwrap()),
+        Err(errors) => response::invalid(&errors)
+    }
+}
+
+// ?q is required; ?page and ?per_page must be positive when given
+pub fn search_page(req: &mut Request) -> Result<search::SearchResults, Vec<params::FieldError>> {
+    let search_index = req.get::<Read<cache::SearchIndex>>().unwrap();
+
+    let url = req.url.clone().into_generic_url();
 
-    let mut username = None;
-    let mut budget = None;
+    let mut query = None;
+    let mut page = Some(1);
+    let mut per_page = Some(SEARCH_PER_PAGE);
 
     for (key, value) in url.query_pairs() {
         match key.borrow() {
-            "username" => {
-                username = Some(value.clone());
+            "q" => {
+                query = Some(value.into_owned());
             },
-            "budget" => {
-                budget = Some(value.clone());
+            "page" => {
+                page = usize::from_str(&value).ok();
             },
-            "labels" => {
-                for label in value.split(",") {
-                    labels.push(String::from(label));
-                }
+            "per_page" => {
+                per_page = usize::from_str(&value).ok();
             }
             _ => {}
         }
     };
 
-    match (username, budget) {
-        (Some(u), Some(b)) => {
-            if labels.len() > 0 {
-                let pie_opt = pie_state::recommend(
-                    &redis,
-                    &labels,
-                    &sorted_pies,
-                    &label_bitvecs,
-                    &u.into_owned(),
-                    &b.into_owned()
-                );
-//                println!("recommending pie {:?}", pie_opt);
-                match pie_opt {
-                    Some(pie) => {
-                        return response::recommend(pie.id as usize);
-                    }
-                    None => {
-                        return response::no_recommends();
-                    }
-                }
-            } else {
-                return response::error()
+    let mut errors = vec![];
+    let positive = |value: Option<usize>| value.and_then(|n| if n > 0 { Some(n) } else { None });
+    if query.is_none() {
+        errors.push(params::FieldError { field: "q".to_string(), message: "is required".to_string() });
+    }
+    if positive(page).is_none() {
+        errors.push(params::FieldError { field: "page".to_string(), message: "must be a positive integer".to_string() });
+    }
+    if positive(per_page).is_none() {
+        errors.push(params::FieldError { field: "per_page".to_string(), message: "must be a positive integer".to_string() });
+    }
+
+    match (query, positive(page), positive(per_page)) {
+        (Some(q), Some(p), Some(n)) => {
+            let results = search_index.search(&q);
+            Ok(search::paginate(&q, results, p, n.min(SEARCH_MAX_PER_PAGE)))
+        },
+        (_, _, _) => Err(errors)
+    }
+}
+
+// what's wrong with a new account's username and password, if anything
+pub fn credential_errors(credentials: &params::Credentials) -> Vec<params::FieldError> {
+    let mut errors = vec![];
+    if let Some(message) = accounts::check_username(&credentials.username) {
+        errors.push(params::FieldError { field: "username".to_string(), message: message.to_string() });
+    }
+    if let Some(message) = accounts::check_password(&credentials.password) {
+        errors.push(params::FieldError { field: "password".to_string(), message: message.to_string() });
+    }
+    errors
+}
+
+fn login_page_with(req: &mut Request, status: status::Status, message: Option<&str>) -> IronResult<Response> {
+    let templates = req.get::<Read<cache::Templates>>().unwrap();
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+
+    let token = forms::csrf_cookie(req).unwrap_or_else(forms::new_csrf_token);
+    let page = templates::LoginPage {
+        csrf_token: token.clone(),
+        message: message.map(|m| m.to_string()),
+        username: accounts::current_user(req).map(|user| user.username),
+        login_url: routes.path("login", &[]),
+        register_url: routes.path("register", &[]),
+        logout_url: routes.path("logout", &[])
+    };
+    let mut res = try!(response::html_status(status, templates.render("login", "Sign in", &page)));
+    forms::set_csrf_cookie(&mut res, &token);
+    Ok(res)
+}
+
+pub fn login_page(req: &mut Request) -> IronResult<Response> {
+    login_page_with(req, status::Ok, None)
+}
+
+// signs in and goes back to the pies with the session cookie set
+fn signed_in(req: &mut Request, token: &str) -> IronResult<Response> {
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+    let mut res = try!(response::see_other(routes.path("pies", &[])));
+    accounts::set_session_cookie(&mut res, token);
+    Ok(res)
+}
+
+// the username and password from a login or register form, or the page to show instead
+fn login_form(req: &mut Request) -> Result<params::Credentials, IronResult<Response>> {
+    let credentials = match params::read(req).and_then(|p| params::credentials(&p)) {
+        Ok(credentials) => credentials,
+        Err(_) => return Err(login_page_with(req, status::BadRequest, Some("Please fill in a username and password.")))
+    };
+    if !forms::check_csrf(req, credentials.csrf_token.as_ref()) {
+        return Err(login_page_with(req, status::Forbidden, Some("Your session expired, please try again.")));
+    }
+    Ok(credentials)
+}
+
+pub fn login(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+
+    let credentials = match login_form(req) {
+        Ok(credentials) => credentials,
+        Err(page) => return page
+    };
+
+    match accounts::login(&redis, &credentials.username, &credentials.password) {
+        Ok(Some(token)) => signed_in(req, &token),
+        Ok(None) => login_page_with(req, status::Unauthorized, Some("Wrong username or password.")),
+        Err(_) => login_page_with(req, status::ServiceUnavailable, Some("Pie is unavailable right now, please try again shortly."))
+    }
+}
+
+pub fn register(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+
+    let credentials = match login_form(req) {
+        Ok(credentials) => credentials,
+        Err(page) => return page
+    };
+
+    let errors = credential_errors(&credentials);
+    if !errors.is_empty() {
+        let message = errors.iter()
+            .map(|e| format!("{} {}.", e.field, e.message))
+            .collect::<Vec<String>>()
+            .join(" ");
+        return login_page_with(req, status::BadRequest, Some(&message));
+    }
+
+    match accounts::register(&redis, &credentials.username, &credentials.password) {
+        // the account exists even if signing in fails, so the page says to sign in
+        Ok(accounts::Registration::Created) => match accounts::login(&redis, &credentials.username, &credentials.password) {
+            Ok(Some(token)) => signed_in(req, &token),
+            _ => login_page_with(req, status::ServiceUnavailable, Some("Your account was made, but signing in failed. Please sign in."))
+        },
+        Ok(accounts::Registration::Taken) => login_page_with(req, status::Conflict, Some("That username is taken.")),
+        Err(_) => login_page_with(req, status::ServiceUnavailable, Some("Pie is unavailable right now, please try again shortly."))
+    }
+}
+
+pub fn logout(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let routes = req.get::<Read<cache::Routes>>().unwrap();
+
+    let submitted = params::read(req).ok().and_then(|p| p.text("csrf_token").ok()).and_then(|t| t);
+    if !forms::check_csrf(req, submitted.as_ref()) {
+        return login_page_with(req, status::Forbidden, Some("Your session expired, please try again."));
+    }
+
+    if let Some(token) = accounts::token(req) {
+        if accounts::logout(&redis, &token).is_err() {
+            return login_page_with(req, status::ServiceUnavailable, Some("Pie is unavailable right now, please try again shortly."));
+        }
+    }
+    let mut res = try!(response::see_other(routes.path("login_page", &[])));
+    accounts::clear_session_cookie(&mut res);
+    Ok(res)
+}
+
+pub fn openapi(req: &mut Request) -> IronResult<Response> {
+    let document = req.get::<Read<cache::OpenApi>>().unwrap();
+    response::json((*document).clone())
+}
+
+pub fn healthz(_: &mut Request) -> IronResult<Response> {
+    response::json(json::encode(&health::live()).unwrap())
+}
+
+// 503 until every check passes, so a load balancer stops sending traffic
+pub fn readyz(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let sorted_pies = req.get::<Read<cache::SortedPies>>().unwrap();
+
+    let readiness = health::ready(&redis, &sorted_pies);
+    let status = if readiness.ready { status::Ok } else { status::ServiceUnavailable };
+    response::json_status(status, json::encode(&readiness).unwrap())
+}
+
+pub fn metrics(req: &mut Request) -> IronResult<Response> {
+    let metrics = req.get::<Read<cache::Metrics>>().unwrap();
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let sorted_pies = req.get::<Read<cache::SortedPies>>().unwrap();
+    response::prometheus(metrics.render(&redis, &sorted_pies))
+}
+
+pub fn experiment_stats(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let experiment = req.get::<Read<cache::Experiment>>().unwrap();
+
+    match experiments::stats(&redis, &experiment) {
+        Ok(stats) => response::json(json::encode(&stats).unwrap()),
+        Err(unavailable) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+// ?format=csv for a spreadsheet, json otherwise
+pub fn export(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let sorted_pies = req.get::<Read<cache::SortedPies>>().unwrap();
+
+    let url = req.url.clone().into_generic_url();
+    let as_csv = url.query_pairs().any(|(key, value)| key == "format" && value == "csv");
+
+    let snapshot = match snapshot::take(&redis, &sorted_pies) {
+        Ok(snapshot) => snapshot,
+        Err(unavailable) => return response::unavailable(unavailable.retry_after)
+    };
+    if as_csv {
+        response::csv(snapshot::to_csv(&snapshot))
+    } else {
+        response::json(json::encode(&snapshot).unwrap())
+    }
+}
+
+// takes what export produces, as json or as text/csv
+pub fn import(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let user = admin_user(req);
+    let sorted_pies = req.get::<Read<cache::SortedPies>>().unwrap();
+
+    let is_csv = match req.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Text, SubLevel::Ext(ref ext), _))) => ext == "csv",
+        _ => false
+    };
+
+    let mut body = String::new();
+    if req.body.read_to_string(&mut body).is_err() {
+        return response::invalid(&vec![params::FieldError {
+            field: "body".to_string(),
+            message: "could not be read as utf-8".to_string()
+        }]);
+    }
+
+    let restore = if is_csv {
+        snapshot::from_csv(&body)
+    } else {
+        json::decode::<snapshot::Restore>(&body).map_err(|e| e.to_string())
+    };
+
+    match restore.map_err(snapshot::RestoreError::Invalid)
+        .and_then(|restore| snapshot::restore(&redis, &sorted_pies, &restore)) {
+        Ok(summary) => {
+            if audit::record(&redis, &user, audit::Action::Import, None,
+                             format!("{} restored, {} reset, {} skipped",
+                                     summary.restored, summary.reset, summary.skipped.len())).is_err() {
+                warn!("could not record the import in the audit log");
+            }
+            response::json(json::encode(&summary).unwrap())
+        }
+        Err(snapshot::RestoreError::Invalid(message)) => {
+            response::invalid(&vec![params::FieldError {
+                field: "body".to_string(),
+                message: message
+            }])
+        }
+        Err(snapshot::RestoreError::Unavailable(unavailable)) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+// the pie named in the path and the request's parameters, with the csrf token checked on
+// form posts, for the admin routes that change one pie
+fn admin_request(req: &mut Request) -> Result<((pies::Pie, usize), params::Params), IronResult<Response>> {
+    let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
+
+    let pie_id = req.extensions.get::<Router>()
+        .and_then(|router| router.find("pie_id"))
+        .and_then(|id| u64::from_str(id).ok());
+    let found = match pie_id.and_then(|id| id_index.get(&id)) {
+        Some(found) => found.clone(),
+        None => return Err(response::not_found())
+    };
+
+    let params = match params::read(req) {
+        Ok(params) => params,
+        Err(errors) => return Err(response::invalid(&errors))
+    };
+    try!(admin_csrf(req, &params));
+
+    Ok((found, params))
+}
+
+// a session cookie is only let through on form posts, which another site can submit, so
+// those and anything else signed in by the cookie must send back the csrf token
+fn csrf_refused(source: &params::Source, by_cookie: bool, cookie: Option<String>, submitted: Option<&String>) -> bool {
+    (*source == params::Source::Form || by_cookie) && !forms::csrf_matches(cookie, submitted)
+}
+
+fn admin_csrf(req: &Request, params: &params::Params) -> Result<(), IronResult<Response>> {
+    let token = params.text("csrf_token").ok().and_then(|t| t);
+    if csrf_refused(&params.source, accounts::by_cookie(req), forms::csrf_cookie(req), token.as_ref()) {
+        return Err(response::forbidden());
+    }
+    Ok(())
+}
+
+// the role check in front of every admin route has already found who this is
+fn admin_user(req: &Request) -> accounts::User {
+    accounts::current_user(req).expect("admin routes are only reached signed in")
+}
+
+pub fn restock(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let user = admin_user(req);
+
+    let ((pie, bitvec_pos), params) = match admin_request(req) {
+        Ok(found) => found,
+        Err(res) => return res
+    };
+    let slices = match params::restock(&params) {
+        Ok(slices) => slices,
+        Err(errors) => return response::invalid(&errors)
+    };
+
+    match pie_state::restock(&redis, &pie, bitvec_pos, slices) {
+        Ok(pie_state::RestockStatus::Restocked(remaining)) => {
+            if audit::record(&redis, &user, audit::Action::Restock, Some(pie.id),
+                             format!("added {} slices, {} remaining", slices, remaining)).is_err() {
+                warn!("could not record the restock of pie {} in the audit log", pie.id);
             }
+            response::json(json::encode(&pies::Inventory { pie_id: pie.id, remaining_slices: remaining }).unwrap())
         }
-        (_, _) => {
-            return response::error()
+        Ok(pie_state::RestockStatus::TooMany(remaining)) => response::invalid(&vec![params::FieldError {
+            field: "slices".to_string(),
+            message: format!("would put more than the pie's {} slices on sale, with {} remaining", pie.slices, remaining)
+        }]),
+        Err(unavailable) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+pub fn refund(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let user = admin_user(req);
+
+    let ((pie, bitvec_pos), params) = match admin_request(req) {
+        Ok(found) => found,
+        Err(res) => return res
+    };
+    let order = match params::refund(&params) {
+        Ok(order) => order,
+        Err(errors) => return response::invalid(&errors)
+    };
+
+    match pie_state::refund(&redis, &pie, bitvec_pos, &order.username, order.slices) {
+        Ok(pie_state::RefundStatus::Refunded(remaining)) => {
+            if audit::record(&redis, &user, audit::Action::Refund, Some(pie.id),
+                             format!("refunded {} slices to {}", order.slices, order.username)).is_err() {
+                warn!("could not record the refund of pie {} in the audit log", pie.id);
+            }
+            response::json(json::encode(&pies::Inventory { pie_id: pie.id, remaining_slices: remaining }).unwrap())
+        }
+        Ok(pie_state::RefundStatus::NotPurchased) => response::invalid(&vec![params::FieldError {
+            field: "slices".to_string(),
+            message: format!("is more than {} bought", order.username)
+        }]),
+        Err(unavailable) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+// every pie back to full, with no purchases, blacklists or sold out flags
+pub fn reset(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+    let user = admin_user(req);
+    let sorted_pies = req.get::<Read<cache::SortedPies>>().unwrap();
+
+    let params = match params::read(req) {
+        Ok(params) => params,
+        Err(errors) => return response::invalid(&errors)
+    };
+    if let Err(res) = admin_csrf(req, &params) {
+        return res;
+    }
+
+    // nothing to check against with no rows, so only redis can refuse
+    let summary = match snapshot::restore(&redis, &sorted_pies, &snapshot::Restore { pies: vec![] }) {
+        Ok(summary) => summary,
+        Err(snapshot::RestoreError::Unavailable(unavailable)) => return response::unavailable(unavailable.retry_after),
+        Err(snapshot::RestoreError::Invalid(message)) => return response::invalid(&vec![params::FieldError {
+            field: "body".to_string(),
+            message: message
+        }])
+    };
+    if audit::record(&redis, &user, audit::Action::Reset, None, format!("{} pies reset", summary.reset)).is_err() {
+        warn!("could not record the reset in the audit log");
+    }
+    response::json(json::encode(&summary).unwrap())
+}
+
+const AUDIT_LIMIT: usize = 100;
+const AUDIT_MAX_LIMIT: usize = 1000;
+
+// newest first; ?limit for more or fewer
+pub fn audit_log(req: &mut Request) -> IronResult<Response> {
+    let redis = req.get::<Read<cache::Redis>>().unwrap();
+
+    let limit = match params::read(req).and_then(|params| params.positive_integer("limit").map_err(|e| vec![e])) {
+        Ok(limit) => limit.map(|n| n as usize).unwrap_or(AUDIT_LIMIT).min(AUDIT_MAX_LIMIT),
+        Err(errors) => return response::invalid(&errors)
+    };
+
+    match audit::recent(&redis, limit) {
+        Ok(entries) => response::json(json::encode(&entries).unwrap()),
+        Err(unavailable) => response::unavailable(unavailable.retry_after)
+    }
+}
+
+// ?size=thumb, small or medium for a resized copy, the original otherwise
+pub fn image(req: &mut Request) -> IronResult<Response> {
+    let id_index = req.get::<Read<cache::IdIndex>>().unwrap();
+    let store = req.get::<Read<cache::Images>>().unwrap();
+
+    let url = req.url.clone().into_generic_url();
+    let mut size_name = None;
+    for (key, value) in url.query_pairs() {
+        if key == "size" {
+            size_name = Some(value.into_owned());
         }
     }
-    response::error()
-}
\ No newline at end of file
+
+    let size = match images::Size::from_name(size_name.as_ref().map(|s| s.as_str())) {
+        Some(size) => size,
+        None => return response::not_found()
+    };
+
+    let pie_id = match req.extensions.get::<Router>().unwrap().find("pie_id").map(u64::from_str) {
+        Some(Ok(id)) => id,
+        _ => return response::not_found()
+    };
+
+    if !id_index.contains_key(&pie_id) {
+        return response::not_found();
+    }
+
+    let image = store.get(pie_id, size);
+    let etag = match image {
+        images::Image::File(ref path) => images::etag(path).unwrap_or_default(),
+        images::Image::Placeholder => "placeholder".to_string()
+    };
+
+    let cached = match req.headers.get::<IfNoneMatch>() {
+        Some(&IfNoneMatch::Any) => true,
+        Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|tag| tag.tag() == etag),
+        None => false
+    };
+    if cached && !etag.is_empty() {
+        return response::not_modified(etag);
+    }
+
+    match image {
+        images::Image::File(path) => response::image(path, etag),
+        images::Image::Placeholder => response::svg(images::PLACEHOLDER_SVG.to_string(), etag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use params::Source;
+
+    use super::csrf_refused;
+
+    #[test]
+    fn cookie_sessions_need_the_csrf_token() {
+        let token = "abc".to_string();
+        // a cross-site form post carries the session cookie but can't know the token
+        assert!(csrf_refused(&Source::Form, true, Some(token.clone()), None));
+        assert!(csrf_refused(&Source::Query, true, Some(token.clone()), None));
+        assert!(csrf_refused(&Source::Query, true, None, Some(&token)));
+        assert!(csrf_refused(&Source::Form, true, Some("other".to_string()), Some(&token)));
+        assert!(csrf_refused(&Source::Form, true, Some(String::new()), Some(&String::new())));
+        assert!(!csrf_refused(&Source::Form, true, Some(token.clone()), Some(&token)));
+    }
+
+    #[test]
+    fn bearer_and_api_key_requests_need_no_token() {
+        assert!(!csrf_refused(&Source::Json, false, None, None));
+        assert!(!csrf_refused(&Source::Query, false, None, None));
+        // unless they post a form, which is checked whoever sends it
+        assert!(csrf_refused(&Source::Form, false, None, None));
+    }
+}
@@ -0,0 +1,303 @@
+extern crate redis;
+
+use redis::Commands;
+
+use std::collections::HashSet;
+
+use pies;
+use store;
+
+macro_rules! recommended_key { ($x:expr) => (format!("user-{}-recommended", $x)) }
+macro_rules! arm_recommends_key { ($x:expr, $y:expr) => (format!("experiment-{}-{}-recommends", $x, $y)) }
+macro_rules! arm_conversions_key { ($x:expr, $y:expr) => (format!("experiment-{}-{}-conversions", $x, $y)) }
+macro_rules! arm_slices_key { ($x:expr, $y:expr) => (format!("experiment-{}-{}-slices", $x, $y)) }
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Strategy {
+    // cheapest or most expensive matching pie, the original behaviour
+    Price,
+    // of the few pies closest to the budget end, the one with the fewest slices left
+    Scarcity
+}
+
+impl Strategy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Strategy::Price => "price",
+            Strategy::Scarcity => "scarcity"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        match name {
+            "price" => Some(Strategy::Price),
+            "scarcity" => Some(Strategy::Scarcity),
+            _ => None
+        }
+    }
+}
+
+// the experiment recommend runs, from the config's experiment section
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct ExperimentConfig {
+    // counts are kept per name, so a new name starts them from zero
+    pub name: String,
+    pub arms: Vec<ArmConfig>
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+pub struct ArmConfig {
+    pub name: String,
+    // price or scarcity
+    pub strategy: String,
+    // a share of users relative to the other arms
+    pub weight: u64
+}
+
+// without a section, users are split evenly between the price and scarcity strategies
+impl Default for ExperimentConfig {
+    fn default() -> ExperimentConfig {
+        ExperimentConfig {
+            name: "recommend-v1".to_string(),
+            arms: vec![
+                ArmConfig { name: "control".to_string(), strategy: "price".to_string(), weight: 50 },
+                ArmConfig { name: "scarcity".to_string(), strategy: "scarcity".to_string(), weight: 50 }
+            ]
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Arm {
+    pub name: String,
+    pub strategy: Strategy,
+    pub weight: u64
+}
+
+#[derive(Clone, Debug)]
+pub struct Experiment {
+    pub name: String,
+    pub arms: Vec<Arm>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct ExperimentStats {
+    pub experiment: String,
+    pub arms: Vec<ArmStats>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct ArmStats {
+    pub arm: String,
+    pub strategy: String,
+    pub recommendations: u64,
+    pub conversions: u64,
+    pub slices: u64,
+    pub conversion_rate: f64
+}
+
+// Err says what's wrong with the config
+pub fn new(config: &ExperimentConfig) -> Result<Experiment, String> {
+    if config.name.trim().is_empty() {
+        return Err("experiment needs a name".to_string());
+    }
+
+    let mut names = HashSet::new();
+    let mut arms = vec![];
+    for arm in &config.arms {
+        if !names.insert(arm.name.clone()) {
+            return Err(format!("experiment {} has two arms named {}", config.name, arm.name));
+        }
+        let strategy = match Strategy::from_name(&arm.strategy) {
+            Some(strategy) => strategy,
+            None => return Err(format!("arm {} has unknown strategy {}", arm.name, arm.strategy))
+        };
+        arms.push(Arm { name: arm.name.clone(), strategy: strategy, weight: arm.weight });
+    }
+    if arms.iter().map(|arm| arm.weight).sum::<u64>() == 0 {
+        return Err(format!("experiment {} needs an arm with a weight above 0", config.name));
+    }
+
+    Ok(Experiment {
+        name: config.name.clone(),
+        arms: arms
+    })
+}
+
+// FNV-1a, so a user lands in the same arm across restarts and rust versions
+fn hash_username(user: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in user.bytes() {
+        hash = hash ^ (byte as u64);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl Experiment {
+    pub fn assign(&self, user: &str) -> &Arm {
+        let total: u64 = self.arms.iter().map(|arm| arm.weight).sum();
+        if total == 0 {
+            return &self.arms[0];
+        }
+
+        let mut bucket = hash_username(&format!("{}:{}", self.name, user)) % total;
+        for arm in &self.arms {
+            if bucket < arm.weight {
+                return arm;
+            }
+            bucket -= arm.weight;
+        }
+        &self.arms[self.arms.len() - 1]
+    }
+
+    fn find(&self, name: &str) -> Option<&Arm> {
+        self.arms.iter().find(|arm| arm.name == name)
+    }
+}
+
+pub fn record_recommendation(store: &store::Store,
+                             experiment: &Experiment,
+                             arm: &Arm,
+                             user: &String,
+                             pie: &pies::Pie) -> Result<(), store::Unavailable> {
+    store.write(|conn| redis::pipe().atomic()
+        .cmd("HSET").arg(recommended_key!(user)).arg(pie.id).arg(&arm.name).ignore()
+        .cmd("INCR").arg(arm_recommends_key!(experiment.name, arm.name)).ignore()
+        .query(conn))
+}
+
+// a purchase only counts once, for the arm that last recommended that pie to that user
+pub fn record_conversion(store: &store::Store,
+                         experiment: &Experiment,
+                         user: &String,
+                         pie: &pies::Pie,
+                         slices: u64) -> Result<(), store::Unavailable> {
+    let arm_name : Option<String> = try!(store.read(|conn| conn.hget(recommended_key!(user), pie.id)));
+
+    let arm = match arm_name.as_ref().and_then(|name| experiment.find(name)) {
+        Some(arm) => arm,
+        None => return Ok(())
+    };
+
+    store.write(|conn| redis::pipe().atomic()
+        .cmd("HDEL").arg(recommended_key!(user)).arg(pie.id).ignore()
+        .cmd("INCR").arg(arm_conversions_key!(experiment.name, arm.name)).ignore()
+        .cmd("INCRBY").arg(arm_slices_key!(experiment.name, arm.name)).arg(slices).ignore()
+        .query(conn))
+}
+
+pub fn stats(store: &store::Store, experiment: &Experiment) -> Result<ExperimentStats, store::Unavailable> {
+    let mut arms = vec![];
+    for arm in &experiment.arms {
+        let (recommendations, conversions, slices) : (Option<u64>, Option<u64>, Option<u64>) =
+            try!(store.read(|conn| redis::cmd("MGET")
+                .arg(arm_recommends_key!(experiment.name, arm.name))
+                .arg(arm_conversions_key!(experiment.name, arm.name))
+                .arg(arm_slices_key!(experiment.name, arm.name))
+                .query(conn)));
+
+        let recommendations = recommendations.unwrap_or(0);
+        let conversions = conversions.unwrap_or(0);
+        let conversion_rate = if recommendations > 0 {
+            conversions as f64 / recommendations as f64
+        } else {
+            0.0
+        };
+
+        arms.push(ArmStats {
+            arm: arm.name.clone(),
+            strategy: arm.strategy.name().to_string(),
+            recommendations: recommendations,
+            conversions: conversions,
+            slices: slices.unwrap_or(0),
+            conversion_rate: conversion_rate
+        });
+    }
+
+    Ok(ExperimentStats {
+        experiment: experiment.name.clone(),
+        arms: arms
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new, ArmConfig, ExperimentConfig};
+
+    fn config(weights: &[(&str, u64)]) -> ExperimentConfig {
+        ExperimentConfig {
+            name: "test".to_string(),
+            arms: weights.iter().map(|&(name, weight)| ArmConfig {
+                name: name.to_string(),
+                strategy: "price".to_string(),
+                weight: weight
+            }).collect()
+        }
+    }
+
+    fn counts(config: &ExperimentConfig, users: usize) -> Vec<usize> {
+        let experiment = new(config).unwrap();
+        let mut counts = vec![0; experiment.arms.len()];
+        for i in 0..users {
+            let arm = experiment.assign(&format!("user-{}", i));
+            counts[experiment.arms.iter().position(|a| a.name == arm.name).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn default_splits_price_and_scarcity() {
+        let experiment = new(&ExperimentConfig::default()).unwrap();
+        assert_eq!(experiment.name, "recommend-v1");
+        let strategies: Vec<&str> = experiment.arms.iter().map(|arm| arm.strategy.name()).collect();
+        assert_eq!(strategies, vec!["price", "scarcity"]);
+    }
+
+    #[test]
+    fn a_user_stays_in_one_arm() {
+        let first = new(&ExperimentConfig::default()).unwrap();
+        let second = new(&ExperimentConfig::default()).unwrap();
+        for i in 0..1000 {
+            let user = format!("user-{}", i);
+            assert_eq!(first.assign(&user).name, second.assign(&user).name);
+        }
+    }
+
+    #[test]
+    fn the_hash_does_not_change() {
+        // FNV-1a's published test vectors; a different hash would move every user
+        assert_eq!(super::hash_username(""), 0xcbf29ce484222325);
+        assert_eq!(super::hash_username("a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(super::hash_username("foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn splits_roughly_evenly() {
+        let counts = counts(&config(&[("a", 50), ("b", 50)]), 10000);
+        assert!(counts.iter().all(|&n| n > 4700 && n < 5300), "{:?}", counts);
+    }
+
+    #[test]
+    fn splits_by_weight() {
+        let counts = counts(&config(&[("a", 90), ("b", 10)]), 10000);
+        assert!(counts[1] > 800 && counts[1] < 1200, "{:?}", counts);
+    }
+
+    #[test]
+    fn an_arm_without_weight_gets_nobody() {
+        let counts = counts(&config(&[("a", 1), ("b", 0)]), 1000);
+        assert_eq!(counts, vec![1000, 0]);
+    }
+
+    #[test]
+    fn refuses_bad_configs() {
+        assert!(new(&config(&[])).is_err());
+        assert!(new(&config(&[("a", 0), ("b", 0)])).is_err());
+        assert!(new(&config(&[("a", 1), ("a", 1)])).is_err());
+
+        let mut unknown = config(&[("a", 1)]);
+        unknown.arms[0].strategy = "random".to_string();
+        assert!(new(&unknown).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+extern crate rustc_serialize;
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json};
+
+extern crate csv;
+extern crate toml;
+extern crate yaml_rust;
+use yaml_rust::{Yaml, YamlLoader};
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use pies;
+
+// every format decodes to the same Vec<pies::Pie> and goes through the same validation
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Yaml,
+    Toml
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "toml" => Some(Format::Toml),
+            _ => None
+        }
+    }
+
+    // by extension, ignoring any query string on a url; json when there is no telling
+    pub fn detect(source: &str) -> Format {
+        let path = source.split('?').next().unwrap_or(source);
+        path.rsplit('.').next()
+            .and_then(Format::from_name)
+            .unwrap_or(Format::Json)
+    }
+}
+
+pub fn decode(text: &str, format: Format, list_delimiter: &str) -> Result<Vec<pies::Pie>, String> {
+    match format {
+        Format::Json => pies::new(text.to_string()).map_err(|e| e.to_string()),
+        Format::Csv => decode_csv(text, list_delimiter),
+        Format::Yaml => decode_yaml(text),
+        Format::Toml => decode_toml(text)
+    }
+}
+
+// one pie per row; labels and the allergen columns hold several values split on the delimiter
+fn decode_csv(text: &str, list_delimiter: &str) -> Result<Vec<pies::Pie>, String> {
+    let mut reader = csv::Reader::from_string(text).has_headers(true);
+    let headers = try!(reader.headers().map_err(|e| e.to_string()));
+    let column = |name: &str| headers.iter().position(|header| header.trim() == name);
+
+    let required = ["id", "name", "image_url", "price_per_slice", "slices", "labels"];
+    for name in required.iter() {
+        if column(name).is_none() {
+            return Err(format!("csv is missing the {} column", name));
+        }
+    }
+
+    let mut pies = vec![];
+    for (n, record) in reader.records().enumerate() {
+        // header is line 1
+        let line = n + 2;
+        let row = try!(record.map_err(|e| format!("line {}: {}", line, e)));
+        let cell = |name: &str| column(name).and_then(|i| row.get(i)).map(|value| value.trim()).unwrap_or("");
+        let list = |name: &str| -> Vec<String> {
+            cell(name).split(list_delimiter)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+        let optional_list = |name: &str| if column(name).is_some() { Some(list(name)) } else { None };
+
+        pies.push(pies::Pie {
+            id: try!(u64::from_str(cell("id")).map_err(|e| format!("line {}: id: {}", line, e))),
+            name: cell("name").to_string(),
+            image_url: cell("image_url").to_string(),
+            price_per_slice: try!(f64::from_str(cell("price_per_slice"))
+                                  .map_err(|e| format!("line {}: price_per_slice: {}", line, e))),
+            slices: try!(u64::from_str(cell("slices")).map_err(|e| format!("line {}: slices: {}", line, e))),
+            labels: list("labels"),
+            contains: optional_list("contains"),
+            may_contain: optional_list("may_contain"),
+            certified: optional_list("certified")
+        });
+    }
+
+    Ok(pies)
+}
+
+fn yaml_to_json(yaml: &Yaml) -> Result<Json, String> {
+    match *yaml {
+        Yaml::Hash(ref hash) => {
+            let mut object = BTreeMap::new();
+            for (key, value) in hash {
+                let key = match *key {
+                    Yaml::String(ref s) => s.clone(),
+                    _ => return Err(format!("yaml keys must be strings, found {:?}", key))
+                };
+                object.insert(key, try!(yaml_to_json(value)));
+            }
+            Ok(Json::Object(object))
+        }
+        Yaml::Array(ref items) => {
+            let mut array = vec![];
+            for item in items {
+                array.push(try!(yaml_to_json(item)));
+            }
+            Ok(Json::Array(array))
+        }
+        Yaml::String(ref s) => Ok(Json::String(s.clone())),
+        Yaml::Integer(n) => Ok(Json::I64(n)),
+        Yaml::Real(ref s) => f64::from_str(s).map(Json::F64).map_err(|e| e.to_string()),
+        Yaml::Boolean(b) => Ok(Json::Boolean(b)),
+        Yaml::Null => Ok(Json::Null),
+        _ => Err(format!("unsupported yaml value {:?}", yaml))
+    }
+}
+
+// the same {pies: [...]} shape as the json catalog
+fn decode_yaml(text: &str) -> Result<Vec<pies::Pie>, String> {
+    let docs = try!(YamlLoader::load_from_str(text).map_err(|e| e.to_string()));
+    let doc = try!(docs.first().ok_or("yaml catalog is empty".to_string()));
+    let json = try!(yaml_to_json(doc));
+
+    let mut decoder = json::Decoder::new(json);
+    let decoded: pies::Pies = try!(Decodable::decode(&mut decoder).map_err(|e: json::DecoderError| e.to_string()));
+    Ok(decoded.pies)
+}
+
+// [[pies]] tables with the same fields as the json catalog
+fn decode_toml(text: &str) -> Result<Vec<pies::Pie>, String> {
+    let decoded: pies::Pies = try!(toml::decode_str(text).ok_or("toml catalog could not be decoded".to_string()));
+    Ok(decoded.pies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Format};
+
+    const JSON: &'static str = include_str!("../tests/fixtures/catalog.json");
+    const CSV: &'static str = include_str!("../tests/fixtures/catalog.csv");
+    const YAML: &'static str = include_str!("../tests/fixtures/catalog.yaml");
+    const TOML: &'static str = include_str!("../tests/fixtures/catalog.toml");
+
+    #[test]
+    fn names_and_extensions() {
+        assert_eq!(Format::from_name("YML"), Some(Format::Yaml));
+        assert_eq!(Format::from_name("xml"), None);
+        assert_eq!(Format::detect("pies.toml"), Format::Toml);
+        assert_eq!(Format::detect("http://example.com/pies.csv?v=2"), Format::Csv);
+        assert_eq!(Format::detect("http://example.com/pies"), Format::Json);
+    }
+
+    #[test]
+    fn decodes_json() {
+        let pies = decode(JSON, Format::Json, ";").unwrap();
+        assert_eq!(pies.len(), 2);
+        assert_eq!(pies[0].name, "Apple");
+        assert_eq!(pies[0].price_per_slice, 3.5);
+        assert_eq!(pies[0].labels, vec!["fruit", "sweet"]);
+        assert_eq!(pies[0].contains, Some(vec![]));
+        assert_eq!(pies[0].may_contain, Some(vec!["nuts".to_string()]));
+        assert_eq!(pies[1].price_per_slice, 4.0);
+        assert_eq!(pies[1].contains, Some(vec!["pecans".to_string(), "dairy".to_string()]));
+    }
+
+    #[test]
+    fn every_format_decodes_the_same_catalog() {
+        let json = format!("{:?}", decode(JSON, Format::Json, ";").unwrap());
+        assert_eq!(format!("{:?}", decode(CSV, Format::Csv, ";").unwrap()), json);
+        assert_eq!(format!("{:?}", decode(YAML, Format::Yaml, ";").unwrap()), json);
+        assert_eq!(format!("{:?}", decode(TOML, Format::Toml, ";").unwrap()), json);
+    }
+
+    #[test]
+    fn csv_needs_the_required_columns() {
+        assert_eq!(decode("id,name,image_url,price_per_slice,slices\n1,Apple,a.jpg,1,1\n", Format::Csv, ";").unwrap_err(),
+                   "csv is missing the labels column");
+    }
+
+    #[test]
+    fn csv_without_allergen_columns_declares_nothing() {
+        let pies = decode("id,name,image_url,price_per_slice,slices,labels\n1,Apple,a.jpg,1,1,fruit|sweet\n", Format::Csv, "|").unwrap();
+        assert_eq!(pies[0].labels, vec!["fruit", "sweet"]);
+        assert_eq!(pies[0].contains, None);
+        assert_eq!(pies[0].certified, None);
+    }
+
+    #[test]
+    fn csv_errors_name_the_line() {
+        let text = "id,name,image_url,price_per_slice,slices,labels\n1,Apple,a.jpg,1,1,fruit\n2,Pecan,b.jpg,cheap,1,nuts\n";
+        assert!(decode(text, Format::Csv, ";").unwrap_err().starts_with("line 3: price_per_slice:"));
+    }
+
+    #[test]
+    fn yaml_must_hold_a_catalog() {
+        assert_eq!(decode("", Format::Yaml, ";").unwrap_err(), "yaml catalog is empty");
+        assert!(decode("pies:\n  - 1: one\n", Format::Yaml, ";").unwrap_err().starts_with("yaml keys must be strings"));
+        assert!(decode("pies:\n  - id: 1\n", Format::Yaml, ";").is_err());
+    }
+
+    #[test]
+    fn toml_must_hold_a_catalog() {
+        assert_eq!(decode("pies = 1", Format::Toml, ";").unwrap_err(), "toml catalog could not be decoded");
+        assert!(decode("[[pies]]\nid = 1\n", Format::Toml, ";").is_err());
+    }
+
+    #[test]
+    fn json_must_hold_a_catalog() {
+        assert!(decode("{\"pies\": [{\"id\": 1}]}", Format::Json, ";").is_err());
+        assert!(decode("[]", Format::Json, ";").is_err());
+    }
+}
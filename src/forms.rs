@@ -0,0 +1,46 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::headers::{Cookie, SetCookie, CookiePair};
+
+extern crate rand;
+use rand::{Rng, OsRng};
+
+extern crate rustc_serialize;
+use rustc_serialize::hex::ToHex;
+
+// double submit: the token lives in a cookie and in a hidden form field, and a
+// form post is only accepted when they agree, which another site cannot arrange
+const CSRF_COOKIE: &'static str = "bakeoff_csrf";
+
+pub fn new_csrf_token() -> String {
+    let mut rng = OsRng::new().expect("no os randomness");
+    let mut bytes = [0u8; 16];
+    rng.fill_bytes(&mut bytes);
+    bytes.to_hex()
+}
+
+pub fn csrf_cookie(req: &Request) -> Option<String> {
+    req.headers.get::<Cookie>().and_then(|cookies| {
+        cookies.iter()
+            .find(|cookie| cookie.name == CSRF_COOKIE)
+            .map(|cookie| cookie.value.clone())
+    })
+}
+
+pub fn set_csrf_cookie(res: &mut Response, token: &str) {
+    let mut cookie = CookiePair::new(CSRF_COOKIE.to_string(), token.to_string());
+    cookie.path = Some("/".to_string());
+    cookie.httponly = true;
+    res.headers.set(SetCookie(vec![cookie]));
+}
+
+pub fn csrf_matches(cookie: Option<String>, submitted: Option<&String>) -> bool {
+    match (cookie, submitted) {
+        (Some(ref cookie), Some(field)) => !cookie.is_empty() && cookie == field,
+        _ => false
+    }
+}
+
+pub fn check_csrf(req: &Request, submitted: Option<&String>) -> bool {
+    csrf_matches(csrf_cookie(req), submitted)
+}
@@ -0,0 +1,79 @@
+extern crate r2d2;
+extern crate r2d2_redis;
+
+use std::time::Instant;
+
+use pies;
+use store;
+
+// /healthz says the process is up; /readyz whether it can serve pie, for a load
+// balancer to decide whether to send it traffic
+
+#[derive(RustcEncodable)]
+pub struct Liveness {
+    pub status: &'static str
+}
+
+#[derive(RustcEncodable)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String
+}
+
+#[derive(RustcEncodable)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>
+}
+
+pub fn live() -> Liveness {
+    Liveness { status: "ok" }
+}
+
+fn check(name: &'static str, ok: bool, detail: String) -> Check {
+    Check { name: name, ok: ok, detail: detail }
+}
+
+fn catalog(pies: &Vec<pies::Pie>) -> Check {
+    check("catalog", !pies.is_empty(), format!("{} pies loaded", pies.len()))
+}
+
+// every connection in use means a request would wait for one
+fn pool(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>) -> Check {
+    let state = pool.state();
+    let size = pool.config().pool_size();
+    let in_use = state.connections - state.idle_connections;
+    check("pool", in_use < size, format!("{} of {} connections in use", in_use, size))
+}
+
+// a round trip, timed; not tried while the breaker is open
+fn store(redis: &store::Store) -> Check {
+    if let Err(unavailable) = redis.available() {
+        return check("redis", false, format!("breaker open, trying again in {}s", unavailable.retry_after));
+    }
+    let started = Instant::now();
+    let pong = redis.ping();
+    let elapsed = started.elapsed();
+    let ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000;
+    if pong {
+        check("redis", true, format!("ping answered in {}ms", ms))
+    } else {
+        check("redis", false, format!("ping failed after {}ms", ms))
+    }
+}
+
+pub fn ready(redis: &store::Store, pies: &Vec<pies::Pie>) -> Readiness {
+    let pool = pool(redis);
+    // with no connection free the ping would only wait for one
+    let store = if pool.ok {
+        store(redis)
+    } else {
+        check("redis", false, "not checked while the pool is exhausted".to_string())
+    };
+    let checks = vec![catalog(pies), store, pool];
+    Readiness {
+        ready: checks.iter().all(|check| check.ok),
+        checks: checks
+    }
+}
@@ -0,0 +1,238 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::status;
+use iron::headers::ContentType;
+use iron::response::{ResponseBody, WriteBody};
+
+extern crate redis;
+use redis::Commands;
+
+extern crate rustc_serialize;
+use rustc_serialize::json;
+
+use std::str;
+
+use params;
+use store;
+
+// a client retrying a purchase sends the same Idempotency-Key each time; the first
+// try's status and body are kept and replayed for the rest, so an order is bought at
+// most once. keys belong to a user, so two users can't collide
+macro_rules! idempotency_key { ($user:expr, $key:expr) => (format!("idempotency-{}-{}", $user, $key)) }
+
+const HEADER: &'static str = "Idempotency-Key";
+// set on a response that was replayed rather than made
+const REPLAYED_HEADER: &'static str = "Idempotent-Replayed";
+const MAX_LEN: usize = 255;
+// long enough for any client's retries
+const TTL: usize = 24 * 60 * 60;
+// while the first try runs; if that server dies the key frees itself well before TTL
+const PENDING_TTL: usize = 60;
+
+// what the store holds under a key; no status yet while the first try is running
+#[derive(RustcDecodable, RustcEncodable)]
+struct Record {
+    // what was ordered, so the key can't be reused for a different order
+    fingerprint: String,
+    status: Option<u16>,
+    content_type: Option<String>,
+    body: Option<String>
+}
+
+pub enum Refusal {
+    Invalid(params::FieldError),
+    // the first try hasn't answered yet
+    InProgress,
+    // the key was first sent with a different order
+    Reused,
+    // redis couldn't be asked, so nothing is known about the key
+    Unavailable(store::Unavailable)
+}
+
+pub enum Begin {
+    // go ahead, and hand the response to the claim to keep
+    Fresh(Claim),
+    Replay(Response)
+}
+
+pub struct Claim {
+    // None when the request has no key, and there's nothing to keep
+    key: Option<String>,
+    fingerprint: String
+}
+
+// None without the header, an error when it's empty, too long or not printable ascii
+fn key(req: &Request) -> Result<Option<String>, params::FieldError> {
+    let value = match req.headers.get_raw(HEADER).and_then(|values| values.first()) {
+        Some(value) => value,
+        None => return Ok(None)
+    };
+    match str::from_utf8(value).map(|value| value.trim()) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_LEN && key.chars().all(|c| c.is_ascii_graphic()) =>
+            Ok(Some(key.to_string())),
+        _ => Err(params::FieldError {
+            field: HEADER.to_string(),
+            message: "must be 1 to 255 printable characters".to_string()
+        })
+    }
+}
+
+// the fingerprint says what the request asks for, e.g. the pie, slices and amount
+pub fn begin(req: &Request,
+             store: &store::Store,
+             username: &str,
+             fingerprint: String) -> Result<Begin, Refusal> {
+    let key = match key(req) {
+        Ok(Some(key)) => idempotency_key!(username, key),
+        Ok(None) => return Ok(Begin::Fresh(Claim { key: None, fingerprint: fingerprint })),
+        Err(error) => return Err(Refusal::Invalid(error))
+    };
+
+    let pending = json::encode(&Record { fingerprint: fingerprint.clone(), status: None, content_type: None, body: None }).unwrap();
+    // set only if absent, so of two retries racing each other only one goes ahead, and
+    // with its expiry in the same command; finish keeps the answer for the full TTL
+    let set: Option<String> = try!(store.write(|conn| redis::cmd("SET").arg(&key).arg(&pending)
+        .arg("NX").arg("EX").arg(PENDING_TTL)
+        .query(conn)).map_err(Refusal::Unavailable));
+    if set.is_some() {
+        return Ok(Begin::Fresh(Claim { key: Some(key), fingerprint: fingerprint }));
+    }
+
+    let stored: Option<String> = try!(store.read(|conn| conn.get(&key)).map_err(Refusal::Unavailable));
+    earlier(stored, &fingerprint)
+}
+
+// what to do about a key already taken, from what it holds
+fn earlier(stored: Option<String>, fingerprint: &str) -> Result<Begin, Refusal> {
+    let record: Record = match stored.and_then(|stored| json::decode(&stored).ok()) {
+        Some(record) => record,
+        // expired between the two calls
+        None => return Err(Refusal::InProgress)
+    };
+    if record.fingerprint != fingerprint {
+        return Err(Refusal::Reused);
+    }
+    match (record.status, record.body) {
+        (Some(code), Some(body)) => Ok(Begin::Replay(replay(code, record.content_type, body))),
+        _ => Err(Refusal::InProgress)
+    }
+}
+
+fn replay(code: u16, content_type: Option<String>, body: String) -> Response {
+    let mut res = Response::with((status::Status::from_u16(code), body));
+    if let Some(content_type) = content_type {
+        res.headers.set_raw("Content-Type", vec![content_type.into_bytes()]);
+    }
+    res.headers.set_raw(REPLAYED_HEADER, vec![b"true".to_vec()]);
+    res
+}
+
+impl Claim {
+    // keeps the response for the retries; an error or a 5xx lets the key go, so the
+    // order can be tried again. the response goes back either way, since the order has
+    // been dealt with: a key that can't be let go frees itself after PENDING_TTL, and
+    // one whose answer can't be kept leaves retries told it's in progress until then
+    pub fn finish(self, store: &store::Store, result: IronResult<Response>) -> IronResult<Response> {
+        let key = match self.key {
+            Some(key) => key,
+            None => return result
+        };
+        let mut res = match result {
+            Ok(res) => res,
+            Err(err) => {
+                release(store, &key);
+                return Err(err);
+            }
+        };
+        let code = res.status.unwrap_or(status::Ok).to_u16();
+        if code >= 500 {
+            release(store, &key);
+            return Ok(res);
+        }
+
+        // the body is written out once here, and put back for this response
+        let mut body: Vec<u8> = vec![];
+        if let Some(mut writer) = res.body.take() {
+            writer.write_body(&mut ResponseBody::new(&mut body)).expect("failed to buffer response");
+        }
+        let record = json::encode(&Record {
+            fingerprint: self.fingerprint,
+            status: Some(code),
+            content_type: res.headers.get::<ContentType>().map(|content_type| content_type.to_string()),
+            body: Some(String::from_utf8_lossy(&body).into_owned())
+        }).unwrap();
+        let kept: Result<(), store::Unavailable> = store.write(|conn| conn.set_ex(&key, &record, TTL));
+        if kept.is_err() {
+            warn!("could not keep the answer for {}", key);
+        }
+
+        res.body = Some(Box::new(body));
+        Ok(res)
+    }
+}
+
+fn release(store: &store::Store, key: &str) {
+    let released: Result<(), store::Unavailable> = store.write(|conn| conn.del(key));
+    if released.is_err() {
+        warn!("could not release {}, it expires in {} seconds", key, PENDING_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status;
+
+    use rustc_serialize::json;
+
+    use super::{earlier, Begin, Record, Refusal, REPLAYED_HEADER};
+
+    fn stored(fingerprint: &str, status: Option<u16>, body: Option<&str>) -> Option<String> {
+        Some(json::encode(&Record {
+            fingerprint: fingerprint.to_string(),
+            status: status,
+            content_type: status.map(|_| "application/json".to_string()),
+            body: body.map(|body| body.to_string())
+        }).unwrap())
+    }
+
+    #[test]
+    fn replays_a_finished_order() {
+        match earlier(stored("pie=1 slices=2", Some(201), Some("{}")), "pie=1 slices=2") {
+            Ok(Begin::Replay(res)) => {
+                assert_eq!(res.status, Some(status::Created));
+                assert!(res.headers.get_raw(REPLAYED_HEADER).is_some());
+                assert_eq!(res.headers.get_raw("Content-Type").unwrap()[0], b"application/json".to_vec());
+            }
+            _ => panic!("expected a replay")
+        }
+    }
+
+    #[test]
+    fn refuses_the_key_for_a_different_order() {
+        assert!(match earlier(stored("pie=1 slices=2", Some(201), Some("{}")), "pie=1 slices=3") {
+            Err(Refusal::Reused) => true,
+            _ => false
+        });
+        assert!(match earlier(stored("pie=1 slices=2", None, None), "pie=2 slices=2") {
+            Err(Refusal::Reused) => true,
+            _ => false
+        });
+    }
+
+    #[test]
+    fn waits_for_an_order_still_running() {
+        assert!(match earlier(stored("pie=1 slices=2", None, None), "pie=1 slices=2") {
+            Err(Refusal::InProgress) => true,
+            _ => false
+        });
+        // gone between SET NX and GET, or unreadable
+        assert!(match earlier(None, "pie=1 slices=2") {
+            Err(Refusal::InProgress) => true,
+            _ => false
+        });
+        assert!(match earlier(Some("not json".to_string()), "pie=1 slices=2") {
+            Err(Refusal::InProgress) => true,
+            _ => false
+        });
+    }
+}
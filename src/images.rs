@@ -0,0 +1,215 @@
+extern crate image;
+use image::FilterType;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::UNIX_EPOCH;
+
+// pie images live in one directory as <pie_id>.<ext>; resized copies are made on
+// first request and kept in a cache directory beside them
+
+use pies;
+
+const EXTENSIONS: &'static [&'static str] = &["jpg", "jpeg", "png", "gif"];
+const CACHE_DIR: &'static str = "cache";
+
+// numbers each resize's temporary file, so concurrent first requests don't share one
+static PARTIALS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// shown for pies without an image; scales to whatever size was asked for
+pub const PLACEHOLDER_SVG: &'static str = "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 100 100\">\
+<rect width=\"100\" height=\"100\" fill=\"#eee\"/>\
+<circle cx=\"50\" cy=\"50\" r=\"30\" fill=\"#d9b38c\"/>\
+</svg>";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Size {
+    Original,
+    Thumb,
+    Small,
+    Medium
+}
+
+impl Size {
+    pub fn from_name(name: Option<&str>) -> Option<Size> {
+        match name {
+            None | Some("original") => Some(Size::Original),
+            Some("thumb") => Some(Size::Thumb),
+            Some("small") => Some(Size::Small),
+            Some("medium") => Some(Size::Medium),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Size::Original => "original",
+            Size::Thumb => "thumb",
+            Size::Small => "small",
+            Size::Medium => "medium"
+        }
+    }
+
+    // the longest side in pixels
+    fn bound(&self) -> Option<u32> {
+        match *self {
+            Size::Original => None,
+            Size::Thumb => Some(150),
+            Size::Small => Some(300),
+            Size::Medium => Some(600)
+        }
+    }
+}
+
+pub enum Image {
+    File(PathBuf),
+    Placeholder
+}
+
+pub struct Images {
+    cache_dir: PathBuf,
+    // each pie's original, found once when the catalog loads rather than on every
+    // page, which would look for up to four files per pie
+    sources: HashMap<u64, PathBuf>
+}
+
+// changes whenever the file is rewritten, which is all a cache needs to know
+pub fn etag(path: &PathBuf) -> Option<String> {
+    fs::metadata(path).ok().and_then(|metadata| {
+        metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|age| format!("{:x}-{:x}", age.as_secs(), metadata.len()))
+    })
+}
+
+fn is_stale(cached: &PathBuf, source: &PathBuf) -> bool {
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(cached), modified(source)) {
+        (Some(cached), Some(source)) => cached < source,
+        _ => true
+    }
+}
+
+fn find_source(dir: &PathBuf, pie_id: u64) -> Option<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|ext| dir.join(format!("{}.{}", pie_id, ext)))
+        .find(|path| path.is_file())
+}
+
+impl Images {
+    pub fn new(dir: &str, pies: &Vec<pies::Pie>) -> Images {
+        let dir = PathBuf::from(dir);
+        let cache_dir = dir.join(CACHE_DIR);
+        if let Err(e) = fs::create_dir_all(&cache_dir) {
+            warn!("could not create image cache {:?}: {}", cache_dir, e);
+        }
+
+        let sources: HashMap<u64, PathBuf> = pies.iter()
+            .filter_map(|pie| find_source(&dir, pie.id).map(|path| (pie.id, path)))
+            .collect();
+        info!("found images for {} of {} pies", sources.len(), pies.len());
+
+        Images {
+            cache_dir: cache_dir,
+            sources: sources
+        }
+    }
+
+    fn resize(&self, source: &PathBuf, cached: &PathBuf, bound: u32) -> Result<(), String> {
+        let original = try!(image::open(source).map_err(|e| e.to_string()));
+        let resized = original.resize(bound, bound, FilterType::Triangle);
+
+        // written aside under a name no other request uses and renamed, so a concurrent
+        // request never serves half a file; whichever rename lands last wins, whole
+        let n = PARTIALS.fetch_add(1, Ordering::SeqCst);
+        let partial = cached.with_extension(format!("{}-{}.partial.png", process::id(), n));
+        let saved = resized.save(&partial).map_err(|e| e.to_string())
+            .and_then(|_| fs::rename(&partial, cached).map_err(|e| e.to_string()));
+        if saved.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        saved
+    }
+
+    // whether the directory has the pie's own image, rather than only the placeholder
+    pub fn has_image(&self, pie_id: u64) -> bool {
+        self.sources.contains_key(&pie_id)
+    }
+
+    // one stat here still, so an image deleted since startup gets the placeholder
+    pub fn get(&self, pie_id: u64, size: Size) -> Image {
+        let source = match self.sources.get(&pie_id) {
+            Some(source) if source.is_file() => source.clone(),
+            _ => return Image::Placeholder
+        };
+
+        let bound = match size.bound() {
+            Some(bound) => bound,
+            None => return Image::File(source)
+        };
+
+        let cached = self.cache_dir.join(format!("{}-{}.png", pie_id, size.name()));
+        if is_stale(&cached, &source) {
+            if let Err(e) = self.resize(&source, &cached, bound) {
+                warn!("could not resize {:?}: {}", source, e);
+                return Image::Placeholder;
+            }
+        }
+
+        Image::File(cached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use pies::Pie;
+
+    use super::{Image, Images, Size};
+
+    fn pie(id: u64) -> Pie {
+        Pie {
+            id: id,
+            name: format!("pie {}", id),
+            image_url: format!("http://example.com/{}.jpg", id),
+            price_per_slice: 1.0,
+            slices: 8,
+            labels: vec![],
+            contains: None,
+            may_contain: None,
+            certified: None
+        }
+    }
+
+    #[test]
+    fn finds_images_once_when_the_catalog_loads() {
+        let dir = env::temp_dir().join(format!("bakeoff-images-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1.png"), b"png").unwrap();
+        fs::write(dir.join("1.jpg"), b"jpg").unwrap();
+        fs::write(dir.join("2.gif"), b"gif").unwrap();
+
+        let images = Images::new(dir.to_str().unwrap(), &vec![pie(1), pie(2), pie(3)]);
+        assert!(images.has_image(1));
+        assert!(images.has_image(2));
+        assert!(!images.has_image(3));
+        match images.get(1, Size::Original) {
+            Image::File(path) => assert_eq!(path, dir.join("1.jpg")),
+            Image::Placeholder => panic!("expected 1.jpg")
+        }
+
+        // added after loading: not looked for until the next start
+        fs::write(dir.join("3.jpg"), b"jpg").unwrap();
+        assert!(!images.has_image(3));
+        // removed after loading: the placeholder rather than a missing file
+        fs::remove_file(dir.join("2.gif")).unwrap();
+        assert!(match images.get(2, Size::Original) { Image::Placeholder => true, _ => false });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
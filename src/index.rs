@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::iter;
+use std::slice;
+
+use pies;
+use taxonomy;
+
+// positions are split into 2^16 wide chunks, each stored as whichever
+// container is smaller: a sorted array of low bits or a full 8KB bitmap
+const CHUNK_WORDS: usize = 1024;
+const ARRAY_LIMIT: usize = 4096;
+
+#[derive(Clone, Debug)]
+enum Container {
+    Array(Vec<u16>),
+    Bitmap(Vec<u64>)
+}
+
+impl Container {
+    fn from_sorted(lows: Vec<u16>) -> Container {
+        if lows.len() <= ARRAY_LIMIT {
+            return Container::Array(lows);
+        }
+        let mut words = vec![0u64; CHUNK_WORDS];
+        for low in lows {
+            words[(low >> 6) as usize] |= 1 << (low & 63);
+        }
+        Container::Bitmap(words)
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Container::Array(ref lows) => lows.len(),
+            Container::Bitmap(ref words) => words.iter().map(|w| w.count_ones() as usize).sum()
+        }
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match *self {
+            Container::Array(ref lows) => lows.binary_search(&low).is_ok(),
+            Container::Bitmap(ref words) => words[(low >> 6) as usize] & (1 << (low & 63)) != 0
+        }
+    }
+
+    fn lows(&self) -> Lows {
+        match *self {
+            Container::Array(ref lows) => Lows::Array(lows.iter()),
+            Container::Bitmap(ref words) => Lows::Bits(Bits { words: words, front: 0, back: CHUNK_WORDS * 64 })
+        }
+    }
+
+    fn to_vec(&self) -> Vec<u16> {
+        match *self {
+            Container::Array(ref lows) => lows.clone(),
+            Container::Bitmap(ref words) => {
+                let mut lows = vec![];
+                for (i, &word) in words.iter().enumerate() {
+                    let mut w = word;
+                    while w != 0 {
+                        let bit = w.trailing_zeros() as usize;
+                        lows.push((i * 64 + bit) as u16);
+                        w &= w - 1;
+                    }
+                }
+                lows
+            }
+        }
+    }
+
+    fn and(&self, other: &Container) -> Container {
+        match (self, other) {
+            (&Container::Array(ref a), &Container::Array(ref b)) => {
+                let mut lows = Vec::with_capacity(a.len().min(b.len()));
+                let (mut i, mut j) = (0, 0);
+                while i < a.len() && j < b.len() {
+                    if a[i] < b[j] {
+                        i += 1;
+                    } else if a[i] > b[j] {
+                        j += 1;
+                    } else {
+                        lows.push(a[i]);
+                        i += 1;
+                        j += 1;
+                    }
+                }
+                Container::Array(lows)
+            }
+            (&Container::Array(ref a), bitmap @ &Container::Bitmap(_)) |
+            (bitmap @ &Container::Bitmap(_), &Container::Array(ref a)) => {
+                Container::Array(a.iter().cloned().filter(|&low| bitmap.contains(low)).collect())
+            }
+            (&Container::Bitmap(ref a), &Container::Bitmap(ref b)) => {
+                let words: Vec<u64> = a.iter().zip(b.iter()).map(|(x, y)| x & y).collect();
+                let container = Container::Bitmap(words);
+                if container.len() <= ARRAY_LIMIT {
+                    Container::Array(container.to_vec())
+                } else {
+                    container
+                }
+            }
+        }
+    }
+}
+
+// the set bits of a bitmap container between front and back, taken from either end
+struct Bits<'a> {
+    words: &'a [u64],
+    front: usize,
+    back: usize
+}
+
+impl<'a> Iterator for Bits<'a> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        while self.front < self.back {
+            let word = self.words[self.front >> 6] >> (self.front & 63);
+            if word == 0 {
+                // on to the start of the next word
+                self.front = (self.front | 63) + 1;
+                continue;
+            }
+            let low = self.front + word.trailing_zeros() as usize;
+            if low >= self.back {
+                break;
+            }
+            self.front = low + 1;
+            return Some(low as u16);
+        }
+        self.front = self.back;
+        None
+    }
+}
+
+impl<'a> DoubleEndedIterator for Bits<'a> {
+    fn next_back(&mut self) -> Option<u16> {
+        while self.front < self.back {
+            let last = self.back - 1;
+            // only the bits at or below last
+            let word = self.words[last >> 6] << (63 - (last & 63));
+            if word == 0 {
+                self.back = last & !63;
+                continue;
+            }
+            let low = last - word.leading_zeros() as usize;
+            if low < self.front {
+                break;
+            }
+            self.back = low;
+            return Some(low as u16);
+        }
+        self.back = self.front;
+        None
+    }
+}
+
+enum Lows<'a> {
+    Array(slice::Iter<'a, u16>),
+    Bits(Bits<'a>)
+}
+
+impl<'a> Iterator for Lows<'a> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        match *self {
+            Lows::Array(ref mut lows) => lows.next().cloned(),
+            Lows::Bits(ref mut bits) => bits.next()
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Lows<'a> {
+    fn next_back(&mut self) -> Option<u16> {
+        match *self {
+            Lows::Array(ref mut lows) => lows.next_back().cloned(),
+            Lows::Bits(ref mut bits) => bits.next_back()
+        }
+    }
+}
+
+// a bitmap's positions in order, or in reverse with rev(), decoded a chunk at a time
+pub struct Iter<'a> {
+    chunks: iter::Zip<slice::Iter<'a, u16>, slice::Iter<'a, Container>>,
+    // the chunks being read from each end, with their high bits
+    front: Option<(u32, Lows<'a>)>,
+    back: Option<(u32, Lows<'a>)>
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        loop {
+            if let Some((high, ref mut lows)) = self.front {
+                if let Some(low) = lows.next() {
+                    return Some(high | low as u32);
+                }
+            }
+            match self.chunks.next() {
+                Some((&key, container)) => self.front = Some(((key as u32) << 16, container.lows())),
+                // the rest, if any, is in the chunk the back has started on
+                None => return match self.back {
+                    Some((high, ref mut lows)) => lows.next().map(|low| high | low as u32),
+                    None => None
+                }
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<u32> {
+        loop {
+            if let Some((high, ref mut lows)) = self.back {
+                if let Some(low) = lows.next_back() {
+                    return Some(high | low as u32);
+                }
+            }
+            match self.chunks.next_back() {
+                Some((&key, container)) => self.back = Some(((key as u32) << 16, container.lows())),
+                None => return match self.front {
+                    Some((high, ref mut lows)) => lows.next_back().map(|low| high | low as u32),
+                    None => None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Bitmap {
+    keys: Vec<u16>,
+    containers: Vec<Container>
+}
+
+impl Bitmap {
+    pub fn from_sorted(positions: &[u32]) -> Bitmap {
+        let mut keys = vec![];
+        let mut containers = vec![];
+        let mut lows = vec![];
+
+        for &pos in positions {
+            let key = (pos >> 16) as u16;
+            if keys.last() != Some(&key) {
+                if !lows.is_empty() {
+                    containers.push(Container::from_sorted(lows));
+                    lows = vec![];
+                }
+                keys.push(key);
+            }
+            lows.push(pos as u16);
+        }
+        if !lows.is_empty() {
+            containers.push(Container::from_sorted(lows));
+        }
+
+        Bitmap { keys: keys, containers: containers }
+    }
+
+    pub fn len(&self) -> usize {
+        self.containers.iter().map(|c| c.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.iter().all(|c| c.len() == 0)
+    }
+
+    pub fn contains(&self, pos: u32) -> bool {
+        match self.keys.binary_search(&((pos >> 16) as u16)) {
+            Ok(i) => self.containers[i].contains(pos as u16),
+            Err(_) => false
+        }
+    }
+
+    pub fn and(&self, other: &Bitmap) -> Bitmap {
+        let mut keys = vec![];
+        let mut containers = vec![];
+        let (mut i, mut j) = (0, 0);
+
+        while i < self.keys.len() && j < other.keys.len() {
+            if self.keys[i] < other.keys[j] {
+                i += 1;
+            } else if self.keys[i] > other.keys[j] {
+                j += 1;
+            } else {
+                let container = self.containers[i].and(&other.containers[j]);
+                if container.len() > 0 {
+                    keys.push(self.keys[i]);
+                    containers.push(container);
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+
+        Bitmap { keys: keys, containers: containers }
+    }
+
+    pub fn and_not(&self, other: &Bitmap) -> Bitmap {
+        let positions: Vec<u32> = self.to_vec().into_iter()
+            .filter(|&pos| !other.contains(pos))
+            .collect();
+        Bitmap::from_sorted(&positions)
+    }
+
+    pub fn iter(&self) -> Iter {
+        Iter {
+            chunks: self.keys.iter().zip(self.containers.iter()),
+            front: None,
+            back: None
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        let mut positions = Vec::with_capacity(self.len());
+        for (key, container) in self.keys.iter().zip(self.containers.iter()) {
+            for low in container.to_vec() {
+                positions.push(((*key as u32) << 16) | low as u32);
+            }
+        }
+        positions
+    }
+}
+
+// bits in the same order redis SETBIT/GETBIT use: offset 0 is the high bit of the first byte
+pub fn is_set(bytes: &[u8], pos: usize) -> bool {
+    match bytes.get(pos / 8) {
+        Some(byte) => byte & (0x80 >> (pos % 8)) != 0,
+        None => false
+    }
+}
+
+// labels are interned to dense ids so each pie is visited once while building,
+// and indexed under their canonical name and every ancestor in the taxonomy
+#[derive(Clone, Debug)]
+pub struct LabelIndex {
+    label_ids: HashMap<String, usize>,
+    bitmaps: Vec<Bitmap>,
+    taxonomy: taxonomy::Taxonomy
+}
+
+impl LabelIndex {
+    pub fn new(pies: &Vec<pies::Pie>, taxonomy: &taxonomy::Taxonomy) -> LabelIndex {
+        let mut label_ids = HashMap::new();
+        let mut positions: Vec<Vec<u32>> = vec![];
+
+        for (i, pie) in pies.iter().enumerate() {
+            let expanded = pie.labels.iter().flat_map(|label| taxonomy.expand(label));
+            for label in expanded {
+                let next_id = positions.len();
+                let id = *label_ids.entry(label).or_insert(next_id);
+                if id == next_id {
+                    positions.push(vec![]);
+                }
+                // a label reached twice on the same pie must not be pushed twice
+                if positions[id].last() != Some(&(i as u32)) {
+                    positions[id].push(i as u32);
+                }
+            }
+        }
+
+        LabelIndex {
+            label_ids: label_ids,
+            bitmaps: positions.iter().map(|p| Bitmap::from_sorted(p)).collect(),
+            taxonomy: taxonomy.clone()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bitmaps.len()
+    }
+
+    // accepts any spelling or synonym of a label
+    pub fn get(&self, label: &str) -> Option<&Bitmap> {
+        self.label_ids.get(&self.taxonomy.canonical(label)).map(|&id| &self.bitmaps[id])
+    }
+
+    // pies carrying every one of the labels; None if any label is unknown
+    pub fn matching(&self, labels: &Vec<String>) -> Option<Bitmap> {
+        let mut bitmaps = vec![];
+        for label in labels {
+            match self.get(label) {
+                Some(bitmap) => bitmaps.push(bitmap),
+                None => return None
+            }
+        }
+
+        // intersect smallest first so the working set shrinks as fast as possible
+        bitmaps.sort_by_key(|bitmap| bitmap.len());
+        let mut iter = bitmaps.into_iter();
+        let mut result = match iter.next() {
+            Some(bitmap) => bitmap.clone(),
+            None => return None
+        };
+        for bitmap in iter {
+            if result.is_empty() {
+                break;
+            }
+            result = result.and(bitmap);
+        }
+        Some(result)
+    }
+}
+
+// matching positions not set in any of the exclusion bitmaps, cheapest
+// first for "cheap" and most expensive first otherwise. found one at a time, so
+// taking the first decodes no more of the bitmap than it has to
+pub struct Candidates<'a> {
+    positions: Iter<'a>,
+    excluded: &'a [&'a [u8]],
+    cheap: bool
+}
+
+impl<'a> Iterator for Candidates<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            let pos = match if self.cheap { self.positions.next_back() } else { self.positions.next() } {
+                Some(pos) => pos as usize,
+                None => return None
+            };
+            if !self.excluded.iter().any(|bytes| is_set(bytes, pos)) {
+                return Some(pos);
+            }
+        }
+    }
+}
+
+pub fn candidates<'a>(matching: &'a Bitmap, excluded: &'a [&'a [u8]], cheap: bool) -> Candidates<'a> {
+    Candidates {
+        positions: matching.iter(),
+        excluded: excluded,
+        cheap: cheap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bitmap, Container, ARRAY_LIMIT, candidates, is_set};
+
+    fn all(matching: &Bitmap, excluded: &[&[u8]], cheap: bool) -> Vec<usize> {
+        candidates(matching, excluded, cheap).collect()
+    }
+
+    fn is_array(container: &Container) -> bool {
+        match *container {
+            Container::Array(_) => true,
+            Container::Bitmap(_) => false
+        }
+    }
+
+    fn positions(n: u32, step: u32) -> Vec<u32> {
+        (0..n).map(|i| i * step).collect()
+    }
+
+    #[test]
+    fn stays_an_array_up_to_the_limit() {
+        let bitmap = Bitmap::from_sorted(&positions(ARRAY_LIMIT as u32, 1));
+        assert!(is_array(&bitmap.containers[0]));
+        assert_eq!(bitmap.len(), ARRAY_LIMIT);
+    }
+
+    #[test]
+    fn becomes_a_bitset_past_the_limit() {
+        let bitmap = Bitmap::from_sorted(&positions(ARRAY_LIMIT as u32 + 1, 1));
+        assert!(!is_array(&bitmap.containers[0]));
+        assert_eq!(bitmap.len(), ARRAY_LIMIT + 1);
+        assert!(bitmap.contains(ARRAY_LIMIT as u32));
+        assert!(!bitmap.contains(ARRAY_LIMIT as u32 + 1));
+    }
+
+    #[test]
+    fn splits_into_chunks() {
+        let bitmap = Bitmap::from_sorted(&[1, 65535, 65536, 200000]);
+        assert_eq!(bitmap.keys, vec![0, 1, 3]);
+        assert!(bitmap.contains(65536));
+        assert!(!bitmap.contains(65537));
+        assert_eq!(bitmap.to_vec(), vec![1, 65535, 65536, 200000]);
+    }
+
+    #[test]
+    fn bitset_and_bitset_shrinks_back_to_an_array() {
+        let evens = Bitmap::from_sorted(&positions(10000, 2));
+        let threes = Bitmap::from_sorted(&positions(10000, 3));
+        let both = evens.and(&threes);
+        assert!(is_array(&both.containers[0]));
+        assert_eq!(both.to_vec(), positions(3334, 6));
+    }
+
+    #[test]
+    fn bitset_and_bitset_stays_a_bitset_when_large() {
+        let all = Bitmap::from_sorted(&positions(20000, 1));
+        let evens = Bitmap::from_sorted(&positions(10000, 2));
+        let both = all.and(&evens);
+        assert!(!is_array(&both.containers[0]));
+        assert_eq!(both.to_vec(), positions(10000, 2));
+    }
+
+    #[test]
+    fn array_and_bitset() {
+        let all = Bitmap::from_sorted(&positions(5000, 1));
+        let few = Bitmap::from_sorted(&[3, 4999, 5000]);
+        assert_eq!(all.and(&few).to_vec(), vec![3, 4999]);
+        assert_eq!(few.and(&all).to_vec(), vec![3, 4999]);
+    }
+
+    #[test]
+    fn and_drops_empty_chunks() {
+        let a = Bitmap::from_sorted(&[1, 70000]);
+        let b = Bitmap::from_sorted(&[2, 70000]);
+        let both = a.and(&b);
+        assert_eq!(both.keys, vec![1]);
+        assert_eq!(both.to_vec(), vec![70000]);
+        assert!(a.and(&Bitmap::from_sorted(&[5])).is_empty());
+    }
+
+    #[test]
+    fn and_not() {
+        let a = Bitmap::from_sorted(&[1, 2, 3, 70000]);
+        let b = Bitmap::from_sorted(&[2, 70000, 80000]);
+        assert_eq!(a.and_not(&b).to_vec(), vec![1, 3]);
+    }
+
+    #[test]
+    fn iterates_in_order_from_a_bitset() {
+        let mut expected = positions(5000, 1);
+        expected.push(131072);
+        let bitmap = Bitmap::from_sorted(&expected);
+        assert_eq!(bitmap.to_vec(), expected);
+    }
+
+    #[test]
+    fn bits_count_from_the_high_bit() {
+        let bytes = [0b1000_0001, 0b0100_0000];
+        assert!(is_set(&bytes, 0));
+        assert!(is_set(&bytes, 7));
+        assert!(is_set(&bytes, 9));
+        assert!(!is_set(&bytes, 1));
+        assert!(!is_set(&bytes, 100));
+    }
+
+    #[test]
+    fn candidates_skip_excluded_positions() {
+        let matching = Bitmap::from_sorted(&[0, 1, 2, 9]);
+        // 1 blacklisted, 9 sold out
+        let blacklist = [0b0100_0000];
+        let sold_out = [0, 0b0100_0000];
+        assert_eq!(all(&matching, &[&blacklist[..], &sold_out[..]], false), vec![0, 2]);
+        assert_eq!(all(&matching, &[&blacklist[..], &sold_out[..]], true), vec![2, 0]);
+    }
+
+    // the catalog is ordered most expensive first
+    #[test]
+    fn cheap_candidates_come_from_the_end() {
+        let matching = Bitmap::from_sorted(&[0, 3, 5]);
+        assert_eq!(all(&matching, &[], true), vec![5, 3, 0]);
+        let nothing_excluded: &[u8] = &[];
+        assert_eq!(all(&matching, &[nothing_excluded], false), vec![0, 3, 5]);
+    }
+
+    #[test]
+    fn iterates_from_both_ends() {
+        // an array chunk, a bitset chunk and another array chunk
+        let mut expected = vec![5, 60000];
+        expected.extend((65536..65536 + 7000).filter(|pos| pos % 3 != 0));
+        expected.push(65536 * 2 + 63);
+        expected.push(65536 * 2 + 64);
+        let bitmap = Bitmap::from_sorted(&expected);
+        assert!(!is_array(&bitmap.containers[1]));
+
+        assert_eq!(bitmap.iter().collect::<Vec<u32>>(), expected);
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(bitmap.iter().rev().collect::<Vec<u32>>(), reversed);
+    }
+
+    #[test]
+    fn both_ends_meet_without_repeating() {
+        let expected: Vec<u32> = (0..5000).map(|i| i * 2).chain(vec![70000, 70001]).collect();
+        let bitmap = Bitmap::from_sorted(&expected);
+        let mut iter = bitmap.iter();
+        let mut seen = vec![];
+        loop {
+            match (iter.next(), iter.next_back()) {
+                (None, None) => break,
+                (front, back) => {
+                    seen.extend(front);
+                    seen.extend(back);
+                }
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn cheap_candidates_stop_at_the_first() {
+        let matching = Bitmap::from_sorted(&positions(10000, 1));
+        let mut found = candidates(&matching, &[], true);
+        assert_eq!(found.next(), Some(9999));
+        assert_eq!(found.next(), Some(9998));
+    }
+}
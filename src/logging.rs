@@ -0,0 +1,92 @@
+extern crate log;
+use log::{Log, Level, LevelFilter, Metadata, Record};
+
+extern crate rustc_serialize;
+use rustc_serialize::json::{Json, ToJson};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// every line on stdout is one json object, so the access log and everything else can
+// be collected and searched the same way
+
+const LEVEL_VAR: &'static str = "BAKEOFF_LOG";
+
+struct JsonLogger;
+
+static LOGGER: JsonLogger = JsonLogger;
+
+fn timestamp() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as f64 + (d.subsec_nanos() / 1000000) as f64 / 1000.0)
+        .unwrap_or(0.0)
+}
+
+pub fn level_from_name(name: &str) -> Option<LevelFilter> {
+    match name.trim().to_lowercase().as_str() {
+        "off" => Some(LevelFilter::Off),
+        "error" => Some(LevelFilter::Error),
+        "warn" | "warning" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None
+    }
+}
+
+// writes one line with the time, level and target alongside the given fields
+pub fn emit(level: Level, target: &str, mut fields: BTreeMap<String, Json>) {
+    if level > log::max_level() {
+        return;
+    }
+    fields.insert("ts".to_string(), timestamp().to_json());
+    fields.insert("level".to_string(), level.to_string().to_lowercase().to_json());
+    fields.insert("target".to_string(), target.to_json());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    // nowhere left to report a failed write to
+    let _ = writeln!(out, "{}", Json::Object(fields));
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        let mut fields = BTreeMap::new();
+        fields.insert("message".to_string(), record.args().to_string().to_json());
+        emit(record.level(), record.target(), fields);
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+// info by default, or BAKEOFF_LOG; called first thing so nothing is logged before it
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger already set");
+    let level = env::var(LEVEL_VAR).ok().and_then(|name| level_from_name(&name));
+    log::set_max_level(level.unwrap_or(LevelFilter::Info));
+}
+
+// the config file's level, unless BAKEOFF_LOG already chose one
+pub fn configure(level: Option<String>) {
+    if env::var(LEVEL_VAR).is_ok() {
+        return;
+    }
+    if let Some(name) = level {
+        match level_from_name(&name) {
+            Some(level) => log::set_max_level(level),
+            None => warn!("unknown log level {}, keeping {}", name, log::max_level())
+        }
+    }
+}
+
+pub fn flush() {
+    log::logger().flush();
+}
@@ -1,8 +1,8 @@
 extern crate iron;
 use iron::prelude::*;
 use iron::Protocol;
+use iron::method::Method;
 
-#[macro_use]
 extern crate router;
 
 extern crate hyper;
@@ -17,90 +17,276 @@ use persistent::{Read};
 use std::cmp::Ordering;
 
 use std::collections::HashMap;
-use std::collections::HashSet;
 
-extern crate bit_vec;
-use bit_vec::BitVec;
+use std::env;
+use std::fs::File;
+use std::process;
+use std::time::Duration;
 
 extern crate r2d2;
 extern crate r2d2_redis;
 extern crate redis;
 
-use std::default::Default;
-
 use r2d2_redis::RedisConnectionManager;
 
+use roles::Role;
+
 extern crate url;
 
 extern crate mustache;
 
 extern crate num_cpus;
 
+#[macro_use]
+extern crate log;
+
 mod endpoints;
 mod response;
 mod pies;
 mod cache;
 mod pie_state;
+mod experiments;
+mod index;
+mod search;
+mod config;
+mod taxonomy;
+mod diet;
+mod templates;
+mod forms;
+mod params;
+mod catalog;
+mod formats;
+mod snapshot;
+mod images;
+mod routes;
+mod api;
+mod request_id;
+mod openapi;
+mod accounts;
+mod roles;
+mod audit;
+mod ratelimit;
+mod idempotency;
+mod logging;
+mod access_log;
+mod metrics;
+mod health;
+mod shutdown;
+mod store;
+
+// every route the server answers; names are what routes::Routes builds urls from.
+// degrades marks those that still answer while redis is unavailable
+fn route_table(legacy_get_purchases: bool) -> Vec<routes::Route> {
+    // old clients bought pie with GET, which link prefetchers and crawlers do too, so
+    // that only works while the config asks for it
+    let purchase_legacy = routes::Route::post("purchase_legacy", "/pie/:pie_id/purchases", endpoints::purchase);
+    let purchase_legacy = if legacy_get_purchases { purchase_legacy.also(Method::Get) } else { purchase_legacy };
+
+    vec![
+        routes::Route::get("root", "/", endpoints::hello_world).degrades(),
+        routes::Route::get("hello_world", "/hello_world", endpoints::hello_world).degrades(),
+        routes::Route::get("pies", "/pies", endpoints::pies).degrades(),
+        routes::Route::get("recommend", "/pies/recommend", endpoints::recommend),
+        routes::Route::get("search", "/pies/search", endpoints::search).degrades(),
+        routes::Route::get("pie_legacy", "/pie/:pie_id", endpoints::pie).degrades(),
+        routes::Route::get("pie", "/pies/:pie_id", endpoints::pie).degrades(),
+        routes::Route::get("image", "/images/:pie_id", endpoints::image).degrades(),
+        purchase_legacy,
+        routes::Route::post("purchase", "/pies/:pie_id/purchases", endpoints::purchase),
+        routes::Route::get("experiments", "/admin/experiments", endpoints::experiment_stats).requires(Role::Staff),
+        routes::Route::get("export", "/admin/export", endpoints::export).requires(Role::Admin),
+        routes::Route::post("import", "/admin/import", endpoints::import).requires(Role::Admin),
+        routes::Route::post("restock", "/admin/pies/:pie_id/restock", endpoints::restock).requires(Role::Staff),
+        routes::Route::post("refund", "/admin/pies/:pie_id/refunds", endpoints::refund).requires(Role::Staff),
+        routes::Route::post("reset", "/admin/reset", endpoints::reset).requires(Role::Admin),
+        routes::Route::get("audit", "/admin/audit", endpoints::audit_log).requires(Role::Admin),
+        routes::Route::get("openapi", "/openapi.json", endpoints::openapi).degrades(),
+        routes::Route::get("metrics", "/metrics", endpoints::metrics).degrades(),
+        routes::Route::get("healthz", "/healthz", endpoints::healthz).degrades(),
+        routes::Route::get("readyz", "/readyz", endpoints::readyz).degrades(),
+        routes::Route::get("login_page", "/login", endpoints::login_page).degrades(),
+        routes::Route::post("login", "/login", endpoints::login),
+        routes::Route::post("register", "/register", endpoints::register),
+        routes::Route::post("logout", "/logout", endpoints::logout),
+        routes::Route::get("api_pies", "/api/v1/pies", api::pies).degrades(),
+        routes::Route::get("api_recommend", "/api/v1/pies/recommend", api::recommend),
+        routes::Route::get("api_search", "/api/v1/pies/search", api::search).degrades(),
+        routes::Route::get("api_pie", "/api/v1/pies/:pie_id", api::pie).degrades(),
+        routes::Route::post("api_purchase", "/api/v1/pies/:pie_id/purchases", api::purchase),
+        routes::Route::post("api_register", "/api/v1/users", api::register),
+        routes::Route::post("api_login", "/api/v1/sessions", api::login),
+        routes::Route::delete("api_logout", "/api/v1/sessions", api::logout),
+        routes::Route::get("api_me", "/api/v1/me", api::me).degrades(),
+        // anything else under the api still answers with an envelope
+        routes::Route::any("api_not_found", "/api/v1/*path", api::not_found).degrades()
+    ]
+}
 
 fn main() {
-    let router = router!(
-        get "/" => endpoints::hello_world,
-        get "/hello_world" => endpoints::hello_world,
-        get "/pies" => endpoints::pies,
-        get "/pies/recommend" => endpoints::recommend,
-        get "/pie/:pie_id" => endpoints::pie,
-        get "/pies/:pie_id" => endpoints::pie,
-        any "/pie/:pie_id/purchases" => endpoints::purchase,
-        post "/pies/:pie_id/purchases" => endpoints::purchase
-    );
+    logging::init();
+    let config = config::load();
+    logging::configure(config.log_level.clone());
+    let route_table = route_table(config.legacy_get_purchases.unwrap_or(false));
+    // the document is written by hand per route, so refuse to run with it out of date
+    if let Err(problems) = openapi::check(&route_table) {
+        for problem in problems {
+            error!("openapi: {}", problem);
+        }
+        process::exit(1);
+    }
+
+    let args: Vec<String> = env::args().collect();
+    let check_only = args.iter().any(|arg| arg == "--check-catalog");
+    let strict = args.iter().any(|arg| arg == "--strict");
+
+    let taxonomy = taxonomy::Taxonomy::new(&config.taxonomy.clone().unwrap_or_default());
 
-    let mut chain = Chain::new(router);
-    let pies = parse_pie_json();
+    // flags win over the config file
+    let source = arg_value(&args, "--catalog").unwrap_or(config.catalog());
+    let format = match arg_value(&args, "--catalog-format").or(config.catalog_format.clone()) {
+        Some(name) => formats::Format::from_name(&name).unwrap_or_else(|| {
+            error!("unknown catalog format {}", name);
+            process::exit(1);
+        }),
+        None => formats::Format::detect(&source)
+    };
+    let pies = load_catalog(&source, format, &config.catalog_list_delimiter(), &taxonomy,
+                            strict || config.strict_catalog.unwrap_or(false), check_only);
     let sorted_pies = make_price_ordered(&pies);
-    chain.link_before(Read::<cache::LabelBitVec>::one(make_label_bitvec(&sorted_pies)));
+
+    let store_config = config.redis.clone().unwrap_or_default();
+    let redis = store::Store::new(connect_redis(&store_config), &store_config);
+    update_redis(&pies, &redis);
+    let limiter = ratelimit::Limiter::new(&config.rate_limits.clone().unwrap_or_default(), &redis);
+
+    let experiment = experiments::new(&config.experiment.clone().unwrap_or_default()).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    let drain = shutdown::Drain::new();
+
+    let mut chain = Chain::new(routes::router(&route_table, &limiter));
+    chain.link_before(access_log::AccessLog);
+    chain.link_before(request_id::RequestId);
+    chain.link_before(drain.clone());
+    chain.link_after(request_id::RequestId);
+    chain.link_after(access_log::AccessLog);
+    chain.link_after(drain.clone());
+    chain.link_before(Read::<cache::LabelIndex>::one(make_label_index(&sorted_pies, &taxonomy)));
+    chain.link_before(Read::<cache::DietIndex>::one(diet::DietIndex::new(&sorted_pies, &taxonomy)));
     chain.link_before(Read::<cache::IdIndex>::one(make_id_index(&sorted_pies)));
+    chain.link_before(Read::<cache::SearchIndex>::one(make_search_index(&sorted_pies)));
+    chain.link_before(Read::<cache::Images>::one(images::Images::new(&config.images_dir(), &sorted_pies)));
     chain.link_before(Read::<cache::SortedPies>::one(sorted_pies));
-
-    let redis = connect_redis();
+    chain.link_before(Read::<cache::Experiment>::one(experiment));
+    // outside production, edited templates are picked up without a restart
+    chain.link_before(Read::<cache::Templates>::one(
+        templates::Templates::new(&config.templates_dir(), !cfg!(feature = "prod"))
+    ));
+    chain.link_before(Read::<cache::OpenApi>::one(openapi::document(&route_table).to_string()));
+    chain.link_before(Read::<cache::Routes>::one(routes::Routes::new(&route_table, config.public_url.clone(),
+                                                                         config.trust_forwarded_host.unwrap_or(false))));
+    chain.link_before(Read::<cache::Metrics>::one(metrics::Metrics::new()));
     chain.link_before(Read::<cache::Redis>::one(redis.clone()));
-    update_redis(&pies, &redis);
+    chain.link_before(accounts::Authenticate::new(redis.clone(), make_roles(&config), make_api_keys(&config)));
 
-    if cfg!(feature = "prod") {
-        println!("running in production mode");
-        Iron::new(chain).listen_with("0.0.0.0:31415",
-                                     3000 * ::num_cpus::get(),
-                                     Protocol::Http,
-                                     None).unwrap();
+    let threads = if cfg!(feature = "prod") {
+        info!("running in production mode");
+        3000 * ::num_cpus::get()
     } else {
-        Iron::new(chain).listen_with("0.0.0.0:31415",
-                                     8 * ::num_cpus::get(),
-                                     Protocol::Http,
-                                     None).unwrap();
+        8 * ::num_cpus::get()
+    };
+
+    let signals = shutdown::signals();
+    // kept rather than dropped, since dropping it waits on the server forever
+    let _listening = Iron::new(chain).listen_with("0.0.0.0:31415",
+                                                  threads,
+                                                  Protocol::Http,
+                                                  None).unwrap();
+    info!("listening on 0.0.0.0:31415");
+
+    signals.recv().expect("signal handler went away");
+    let timeout = Duration::from_secs(config.shutdown_timeout.unwrap_or(30));
+    info!("shutting down, waiting up to {}s for {} requests", timeout.as_secs(), drain.in_flight());
+    let drained = drain.drain(timeout);
+    if drained {
+        info!("every request finished, exiting");
+    } else {
+        error!("{} requests still running after {}s, exiting anyway", drain.in_flight(), timeout.as_secs());
     }
+    // redis writes are made as each request goes, so the logs are all that's buffered
+    logging::flush();
+    process::exit(if drained { 0 } else { 1 });
+}
 
+fn arg_value(args: &Vec<String>, flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
 }
 
-fn parse_pie_json() -> Vec<pies::Pie> {
-    let client = Client::new();
-    let mut res = client.get("http://stash.truex.com/tech/bakeoff/pies.json")
-        .send().unwrap();
+fn read_catalog(source: &str) -> String {
+    let mut text = String::new();
 
-    let mut json = String::new();
-    res.read_to_string(&mut json)
-        .expect("failed to read pies.json");
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let client = Client::new();
+        let mut res = client.get(source)
+            .send().unwrap();
+
+        res.read_to_string(&mut text)
+            .expect("failed to read catalog");
+    } else {
+        File::open(source)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .expect("failed to read catalog");
+    }
 
-    println!("{}\n", json);
-    pies::new(json)
+    text
 }
 
-fn connect_redis() -> r2d2::Pool<r2d2_redis::RedisConnectionManager> {
+// --check-catalog validates and exits; otherwise bad pies are dropped, or in
+// strict mode the server refuses to start
+fn load_catalog(source: &str,
+                format: formats::Format,
+                list_delimiter: &str,
+                taxonomy: &taxonomy::Taxonomy,
+                strict: bool,
+                check_only: bool) -> Vec<pies::Pie> {
+    info!("loading {:?} catalog from {}", format, source);
+    let pies = match formats::decode(&read_catalog(source), format, list_delimiter) {
+        Ok(pies) => pies,
+        Err(e) => {
+            error!("catalog could not be decoded: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let report = catalog::validate(&pies, taxonomy);
+    report.log();
+
+    let passes = report.passes(strict);
+    if check_only {
+        process::exit(if passes { 0 } else { 1 });
+    }
+    if !passes && strict {
+        error!("refusing to start with an invalid catalog in strict mode");
+        process::exit(1);
+    }
+
+    catalog::accept(pies, &report)
+}
+
+// a slow or missing redis fails within the timeouts rather than holding requests
+fn connect_redis(store_config: &store::StoreConfig) -> r2d2::Pool<r2d2_redis::RedisConnectionManager> {
+    let builder = r2d2::Config::builder()
+        .connection_timeout(store_config.connection_timeout())
+        .connection_customizer(Box::new(store::Timeouts(store_config.command_timeout())));
     let config = if cfg!(feature = "prod") {
-        println!("running in production mode");
-        r2d2::Config::builder()
-            .pool_size(1000 * ::num_cpus::get() as u32)
-            .build()
+        builder.pool_size(1000 * ::num_cpus::get() as u32).build()
     } else {
-        Default::default()
+        builder.build()
     };
 
     let manager = RedisConnectionManager::new("redis://localhost:6379").unwrap();
@@ -108,9 +294,36 @@ fn connect_redis() -> r2d2::Pool<r2d2_redis::RedisConnectionManager> {
     pool
 }
 
-fn update_redis(pies: &Vec<pies::Pie>, pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>) {
+// admins listed in both places are still admins
+fn make_roles(config: &config::Config) -> HashMap<String, Role> {
+    let mut roles = HashMap::new();
+    for username in config.staff.clone().unwrap_or(vec![]) {
+        roles.insert(username, Role::Staff);
+    }
+    for username in config.admins.clone().unwrap_or(vec![]) {
+        roles.insert(username, Role::Admin);
+    }
+    roles
+}
+
+fn make_api_keys(config: &config::Config) -> HashMap<String, accounts::User> {
+    let mut keys = HashMap::new();
+    for api_key in config.api_keys.clone().unwrap_or(vec![]) {
+        let role = Role::from_name(&api_key.role).unwrap_or_else(|| {
+            error!("api key {} has unknown role {}", api_key.name, api_key.role);
+            process::exit(1);
+        });
+        keys.insert(api_key.key, accounts::User { username: api_key.name, role: role });
+    }
+    keys
+}
+
+fn update_redis(pies: &Vec<pies::Pie>, store: &store::Store) {
     for pie in pies {
-        pie_state::set_remaining(pool, pie)
+        if let Err(e) = pie_state::set_remaining(store, pie) {
+            error!("could not set the remaining slices of pie {}: {}", pie.id, e);
+            process::exit(1);
+        }
     }
 }
 
@@ -122,28 +335,15 @@ fn make_id_index(pies: &Vec<pies::Pie>) -> HashMap<u64, (pies::Pie, usize)> {
     hash
 }
 
-fn make_label_bitvec(pies: &Vec<pies::Pie>) -> HashMap<String, BitVec> {
-    let mut label_set = HashSet::new();
-    let mut hash = HashMap::new();
-
-    for pie in pies {
-        for label in &pie.labels {
-            label_set.insert(label);
-        }
-    }
-
-    for label in label_set {
-        let mut bv = BitVec::from_elem(pies.len(), false);
-        for (i, pie) in pies.iter().enumerate() {
-            if pie.labels.contains(label) {
-                bv.set(i, true);
-            }
-        }
-        hash.insert(label.clone(), bv);
-    }
+// built from the same catalog as the id index, so it must be rebuilt whenever that is
+fn make_search_index(pies: &Vec<pies::Pie>) -> search::SearchIndex {
+    search::SearchIndex::new(pies)
+}
 
-    println!("bitvecs {:?}\n", hash);
-    hash
+fn make_label_index(pies: &Vec<pies::Pie>, taxonomy: &taxonomy::Taxonomy) -> index::LabelIndex {
+    let label_index = index::LabelIndex::new(pies, taxonomy);
+    info!("indexed {} labels over {} pies", label_index.len(), pies.len());
+    label_index
 }
 
 fn make_price_ordered(pies: &Vec<pies::Pie>) -> Vec<pies::Pie> {
@@ -152,6 +352,55 @@ fn make_price_ordered(pies: &Vec<pies::Pie>) -> Vec<pies::Pie> {
         b.price_per_slice.partial_cmp(&a.price_per_slice)
             .unwrap_or(Ordering::Equal)
     );
-    println!("ordered pies {:?}\n", vec);
+    debug!("ordered pies {:?}", vec);
     vec
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use super::route_table;
+    use openapi;
+    use routes;
+
+    // whatever the document says a path and method do must be what the router sends
+    // there, and the other way round
+    fn check_document(table: &Vec<routes::Route>) {
+        assert_eq!(openapi::check(table), Ok(()));
+
+        let document = openapi::document(table);
+        let paths = document.find("paths").and_then(Json::as_object).expect("no paths");
+        let routed = |path: &str, method: &str| table.iter()
+            .filter(|route| !route.path.contains('*') && openapi::openapi_path(route.path) == path)
+            .find(|route| route.methods.iter().any(|m| m.to_string().to_lowercase() == method))
+            .map(|route| route.name);
+
+        for route in table.iter().filter(|route| !route.path.contains('*')) {
+            for method in &route.methods {
+                let method = method.to_string().to_lowercase();
+                let id = paths.get(&openapi::openapi_path(route.path))
+                    .and_then(|operations| operations.find(&method))
+                    .and_then(|operation| operation.find("operationId"))
+                    .and_then(Json::as_string);
+                assert_eq!(id, Some(route.name), "{} {} is not documented", method, route.path);
+            }
+        }
+
+        for (path, operations) in paths {
+            for (method, operation) in operations.as_object().expect("not an object") {
+                let id = operation.find("operationId").and_then(Json::as_string);
+                assert_eq!(routed(path, method), id, "{} {} is documented but not routed", method, path);
+            }
+        }
+    }
+
+    #[test]
+    fn document_matches_the_router() {
+        check_document(&route_table(false));
+    }
+
+    #[test]
+    fn document_matches_the_router_with_legacy_get_purchases() {
+        check_document(&route_table(true));
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use pies;
+use pie_state;
+use store;
+
+// counted in memory per server, and read by prometheus from /metrics in its text format;
+// remaining slices, the redis pool and its breaker are looked up when scraped
+
+// seconds
+const LATENCY_BUCKETS: &'static [f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    // one count per bucket, of observations at most its bound
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    // by route and status; the count doubles as the request counter
+    requests: BTreeMap<(String, u16), Histogram>,
+    purchases: BTreeMap<&'static str, u64>,
+    // by pie id
+    slices_sold: BTreeMap<u64, u64>,
+    recommendation_hits: u64,
+    recommendation_misses: u64
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Default::default()
+    }
+
+    pub fn request(&self, route: &str, status: u16, seconds: f64) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.requests.entry((route.to_string(), status)).or_insert_with(Default::default).observe(seconds);
+    }
+
+    // slices only count toward sales when the outcome is success
+    pub fn purchase(&self, outcome: &'static str, pie_id: u64, slices: u64) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.purchases.entry(outcome).or_insert(0) += 1;
+        if outcome == "success" {
+            *counts.slices_sold.entry(pie_id).or_insert(0) += slices;
+        }
+    }
+
+    pub fn recommendation(&self, hit: bool) {
+        let mut counts = self.counts.lock().unwrap();
+        if hit {
+            counts.recommendation_hits += 1;
+        } else {
+            counts.recommendation_misses += 1;
+        }
+    }
+
+    pub fn render(&self, store: &store::Store, pies: &Vec<pies::Pie>) -> String {
+        let mut out = String::new();
+        {
+            let counts = self.counts.lock().unwrap();
+
+            header(&mut out, "bakeoff_http_requests_total", "counter", "Requests answered, by route and status.");
+            for (&(ref route, status), histogram) in &counts.requests {
+                let _ = writeln!(out, "bakeoff_http_requests_total{{route=\"{}\",status=\"{}\"}} {}", route, status, histogram.count);
+            }
+
+            header(&mut out, "bakeoff_http_request_duration_seconds", "histogram", "Time to answer a request, by route and status.");
+            for (&(ref route, status), histogram) in &counts.requests {
+                let labels = format!("route=\"{}\",status=\"{}\"", route, status);
+                for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                    let _ = writeln!(out, "bakeoff_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+                }
+                let _ = writeln!(out, "bakeoff_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+                let _ = writeln!(out, "bakeoff_http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+                let _ = writeln!(out, "bakeoff_http_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+            }
+
+            header(&mut out, "bakeoff_purchases_total", "counter", "Purchase attempts, by outcome.");
+            for (outcome, count) in &counts.purchases {
+                let _ = writeln!(out, "bakeoff_purchases_total{{outcome=\"{}\"}} {}", outcome, count);
+            }
+
+            header(&mut out, "bakeoff_slices_sold_total", "counter", "Slices sold, by pie.");
+            for (pie_id, slices) in &counts.slices_sold {
+                let _ = writeln!(out, "bakeoff_slices_sold_total{{pie_id=\"{}\"}} {}", pie_id, slices);
+            }
+
+            header(&mut out, "bakeoff_recommendations_total", "counter", "Recommendations asked for, by whether a pie was found.");
+            let _ = writeln!(out, "bakeoff_recommendations_total{{result=\"hit\"}} {}", counts.recommendation_hits);
+            let _ = writeln!(out, "bakeoff_recommendations_total{{result=\"miss\"}} {}", counts.recommendation_misses);
+        }
+
+        header(&mut out, "bakeoff_remaining_slices", "gauge", "Slices left, by pie.");
+        let ids: Vec<&u64> = pies.iter().map(|pie| &pie.id).collect();
+        // left out while redis is unavailable, rather than reported as zero
+        for (pie, remaining) in pies.iter().zip(pie_state::get_all_remaining(store, &ids).unwrap_or(vec![])) {
+            let _ = writeln!(out, "bakeoff_remaining_slices{{pie_id=\"{}\"}} {}", pie.id, remaining);
+        }
+
+        header(&mut out, "bakeoff_redis_breaker_open", "gauge", "1 while redis is being left alone after failing.");
+        let _ = writeln!(out, "bakeoff_redis_breaker_open {}", if store.is_open() { 1 } else { 0 });
+
+        let state = store.state();
+        header(&mut out, "bakeoff_redis_pool_connections", "gauge", "Redis connections open, by whether one is in use.");
+        let _ = writeln!(out, "bakeoff_redis_pool_connections{{state=\"idle\"}} {}", state.idle_connections);
+        let _ = writeln!(out, "bakeoff_redis_pool_connections{{state=\"in_use\"}} {}", state.connections - state.idle_connections);
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
@@ -0,0 +1,745 @@
+extern crate rustc_serialize;
+use rustc_serialize::json::Json;
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+use api;
+use audit;
+use ratelimit;
+use roles::Role;
+use routes;
+
+// an openapi 3 document for every route in main's route table. paths and methods come
+// from the table itself; what each route takes and returns is written down here, by
+// route name, and check() refuses to start the server when the two disagree
+
+const VERSION: &'static str = "1.0.0";
+
+struct Param {
+    name: &'static str,
+    // path, query or header
+    location: &'static str,
+    kind: &'static str,
+    required: bool,
+    description: &'static str
+}
+
+struct Reply {
+    status: u16,
+    description: &'static str,
+    media: &'static str,
+    schema: Option<Json>
+}
+
+struct Operation {
+    summary: &'static str,
+    params: Vec<Param>,
+    // a schema name, sent as json or as a form
+    body: Option<&'static str>,
+    replies: Vec<Reply>
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    let mut map = BTreeMap::new();
+    for (key, value) in fields {
+        map.insert(key.to_string(), value);
+    }
+    Json::Object(map)
+}
+
+fn string(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn kind(name: &str) -> Json {
+    object(vec![("type", string(name))])
+}
+
+fn array(items: Json) -> Json {
+    object(vec![("type", string("array")), ("items", items)])
+}
+
+fn reference(schema: &str) -> Json {
+    object(vec![("$ref", string(&format!("#/components/schemas/{}", schema)))])
+}
+
+fn schema(properties: Vec<(&str, Json)>, required: &[&str]) -> Json {
+    object(vec![
+        ("type", string("object")),
+        ("required", Json::Array(required.iter().map(|name| string(name)).collect())),
+        ("properties", object(properties))
+    ])
+}
+
+// the v1 envelope with data of the given schema
+fn envelope(data: &str) -> Json {
+    object(vec![("allOf", Json::Array(vec![
+        reference("Envelope"),
+        schema(vec![("data", reference(data))], &[])
+    ]))])
+}
+
+fn pie_id() -> Param {
+    Param { name: "pie_id", location: "path", kind: "integer", required: true, description: "the pie's id" }
+}
+
+fn query(name: &'static str, kind: &'static str, required: bool, description: &'static str) -> Param {
+    Param { name: name, location: "query", kind: kind, required: required, description: description }
+}
+
+fn idempotency_key() -> Param {
+    Param {
+        name: "Idempotency-Key", location: "header", kind: "string", required: false,
+        description: "the same value on every retry of an order; retries get the first try's response"
+    }
+}
+
+fn json(status: u16, description: &'static str, schema: Json) -> Reply {
+    Reply { status: status, description: description, media: "application/json", schema: Some(schema) }
+}
+
+fn html(status: u16, description: &'static str) -> Reply {
+    Reply { status: status, description: description, media: "text/html", schema: None }
+}
+
+fn text(status: u16, description: &'static str) -> Reply {
+    Reply { status: status, description: description, media: "text/plain", schema: None }
+}
+
+fn filters() -> Vec<Param> {
+    vec![
+        query("labels", "string", false, "comma separated labels, all of which must match"),
+        query("exclude", "string", false, "comma separated allergens the pie must not contain"),
+        query("certified", "string", false, "comma separated certifications the pie must hold")
+    ]
+}
+
+fn recommend_params() -> Vec<Param> {
+    vec![
+        query("username", "string", true, "who is asking"),
+        query("budget", "string", true, "cheap or premium"),
+        query("labels", "string", true, "comma separated labels, all of which must match"),
+        query("exclude", "string", false, "comma separated allergens the pie must not contain"),
+        query("certified", "string", false, "comma separated certifications the pie must hold")
+    ]
+}
+
+fn search_params() -> Vec<Param> {
+    vec![
+        query("q", "string", true, "words to look for in names and labels"),
+        query("page", "integer", false, "starting from 1"),
+        query("per_page", "integer", false, "10 by default, at most 50")
+    ]
+}
+
+fn legacy_purchase_replies() -> Vec<Reply> {
+    vec![
+        json(201, "bought", reference("LegacyMessage")),
+        json(400, "invalid parameters", reference("Invalid")),
+        json(401, "not signed in", reference("LegacyError")),
+        json(402, "amount is not price_per_slice * slices", reference("LegacyError")),
+        text(404, "no such pie"),
+        json(409, "an earlier try with this Idempotency-Key hasn't answered yet", reference("LegacyError")),
+        json(410, "sold out", reference("LegacyError")),
+        json(422, "this Idempotency-Key was sent before with a different order", reference("LegacyError")),
+        json(429, "the user has reached the limit for this pie", reference("LegacyError")),
+        html(200, "form posts answer with a page, using the same status codes")
+    ]
+}
+
+// every route the document covers, with the methods it's documented for, so a documented
+// route missing from the table is caught too. purchase_legacy only takes get when the
+// config's legacy_get_purchases asks for it
+const DOCUMENTED: &'static [(&'static str, &'static [&'static str])] = &[
+    ("root", &["get"]), ("hello_world", &["get"]), ("pies", &["get"]), ("recommend", &["get"]),
+    ("search", &["get"]), ("pie_legacy", &["get"]), ("pie", &["get"]), ("image", &["get"]),
+    ("purchase_legacy", &["post", "get"]), ("purchase", &["post"]), ("experiments", &["get"]),
+    ("export", &["get"]), ("import", &["post"]), ("openapi", &["get"]), ("metrics", &["get"]),
+    ("healthz", &["get"]), ("readyz", &["get"]), ("login_page", &["get"]), ("login", &["post"]),
+    ("register", &["post"]), ("logout", &["post"]), ("restock", &["post"]), ("refund", &["post"]),
+    ("reset", &["post"]), ("audit", &["get"]),
+    ("api_pies", &["get"]), ("api_recommend", &["get"]), ("api_search", &["get"]), ("api_pie", &["get"]),
+    ("api_purchase", &["post"]), ("api_register", &["post"]), ("api_login", &["post"]),
+    ("api_logout", &["delete"]), ("api_me", &["get"])
+];
+
+fn documented_methods(name: &str) -> Option<&'static [&'static str]> {
+    DOCUMENTED.iter().find(|&&(documented, _)| documented == name).map(|&(_, methods)| methods)
+}
+
+fn operation(route: &str) -> Option<Operation> {
+    let op = match route {
+        "root" | "hello_world" => Operation {
+            summary: "Hello, World!",
+            params: vec![],
+            body: None,
+            replies: vec![text(200, "a greeting")]
+        },
+        "pies" => Operation {
+            summary: "The catalog as a page",
+            params: filters(),
+            body: None,
+            replies: vec![html(200, "the matching pies")]
+        },
+        "recommend" => Operation {
+            summary: "Recommend a pie",
+            params: recommend_params(),
+            body: None,
+            replies: vec![
+                json(200, "a pie to try", reference("LegacyRecommendation")),
+                json(400, "invalid parameters", reference("Invalid")),
+                json(404, "nothing matches", reference("LegacyError"))
+            ]
+        },
+        "search" => Operation {
+            summary: "Search pies by name and label",
+            params: search_params(),
+            body: None,
+            replies: vec![
+                json(200, "one page of results", reference("SearchResults")),
+                json(400, "missing or invalid parameters", reference("Invalid"))
+            ]
+        },
+        "pie_legacy" | "pie" => Operation {
+            summary: "One pie as a page, or as json when the id ends in .json",
+            params: vec![pie_id()],
+            body: None,
+            replies: vec![
+                html(200, "the pie with a purchase form"),
+                json(200, "the pie, for /pies/{pie_id}.json", reference("Pie")),
+                text(404, "no such pie, for .json"),
+                html(404, "no such pie")
+            ]
+        },
+        "image" => Operation {
+            summary: "A pie's image",
+            params: vec![pie_id(), query("size", "string", false, "original, thumb, small or medium")],
+            body: None,
+            replies: vec![
+                Reply { status: 200, description: "the image, or a placeholder svg", media: "image/*", schema: None },
+                Reply { status: 304, description: "unchanged since If-None-Match", media: "image/*", schema: None },
+                text(404, "no such pie or size")
+            ]
+        },
+        "purchase_legacy" => Operation {
+            summary: "Buy slices of a pie (kept for old clients; GET only with legacy_get_purchases)",
+            params: vec![pie_id(), idempotency_key()],
+            body: Some("PurchaseRequest"),
+            replies: legacy_purchase_replies()
+        },
+        "purchase" => Operation {
+            summary: "Buy slices of a pie",
+            params: vec![pie_id(), idempotency_key()],
+            body: Some("PurchaseRequest"),
+            replies: legacy_purchase_replies()
+        },
+        "experiments" => Operation {
+            summary: "Recommendation experiment results",
+            params: vec![],
+            body: None,
+            replies: vec![json(200, "per arm counts", reference("ExperimentStats"))]
+        },
+        "export" => Operation {
+            summary: "The catalog with live inventory",
+            params: vec![query("format", "string", false, "csv for a spreadsheet, json otherwise")],
+            body: None,
+            replies: vec![
+                json(200, "a snapshot", reference("Snapshot")),
+                Reply { status: 200, description: "a snapshot, for ?format=csv", media: "text/csv", schema: None }
+            ]
+        },
+        "import" => Operation {
+            summary: "Replace live inventory from a snapshot, as json or text/csv",
+            params: vec![],
+            body: Some("Restore"),
+            replies: vec![
+                json(200, "what was restored", reference("RestoreSummary")),
+                json(400, "the snapshot could not be read", reference("Invalid"))
+            ]
+        },
+        "openapi" => Operation {
+            summary: "This document",
+            params: vec![],
+            body: None,
+            replies: vec![json(200, "an openapi 3 document", kind("object"))]
+        },
+        "metrics" => Operation {
+            summary: "Counters and gauges for prometheus",
+            params: vec![],
+            body: None,
+            replies: vec![text(200, "prometheus text format 0.0.4")]
+        },
+        "healthz" => Operation {
+            summary: "Whether the process is up",
+            params: vec![],
+            body: None,
+            replies: vec![json(200, "always, while the process answers", reference("Liveness"))]
+        },
+        "readyz" => Operation {
+            summary: "Whether the server can take traffic",
+            params: vec![],
+            body: None,
+            replies: vec![
+                json(200, "the catalog is loaded, redis answers and the pool has a connection free", reference("Readiness")),
+                json(503, "a check failed", reference("Readiness"))
+            ]
+        },
+        "api_pies" => Operation {
+            summary: "The catalog",
+            params: filters(),
+            body: None,
+            replies: vec![json(200, "the matching pies", envelope("PieList"))]
+        },
+        "api_pie" => Operation {
+            summary: "One pie",
+            params: vec![pie_id()],
+            body: None,
+            replies: vec![
+                json(200, "the pie", envelope("Pie")),
+                json(404, "not_found", reference("Envelope"))
+            ]
+        },
+        "api_purchase" => Operation {
+            summary: "Buy slices of a pie",
+            params: vec![pie_id(), idempotency_key()],
+            body: Some("PurchaseRequest"),
+            replies: vec![
+                json(201, "bought", envelope("Receipt")),
+                json(400, "invalid_request", reference("Envelope")),
+                json(401, "unauthorized", reference("Envelope")),
+                json(402, "wrong_amount", reference("Envelope")),
+                json(403, "forbidden, a form post without its csrf token", reference("Envelope")),
+                json(404, "not_found", reference("Envelope")),
+                json(409, "order_in_progress", reference("Envelope")),
+                json(410, "sold_out", reference("Envelope")),
+                json(422, "idempotency_key_reused", reference("Envelope")),
+                json(429, "purchase_limit", reference("Envelope"))
+            ]
+        },
+        "api_recommend" => Operation {
+            summary: "Recommend a pie",
+            params: recommend_params(),
+            body: None,
+            replies: vec![
+                json(200, "a pie to try", envelope("Recommendation")),
+                json(400, "invalid_request", reference("Envelope")),
+                json(404, "no_recommendation", reference("Envelope"))
+            ]
+        },
+        "api_search" => Operation {
+            summary: "Search pies by name and label",
+            params: search_params(),
+            body: None,
+            replies: vec![
+                json(200, "one page of results", envelope("SearchResults")),
+                json(400, "invalid_request", reference("Envelope"))
+            ]
+        },
+        "login_page" => Operation {
+            summary: "Sign in and register forms",
+            params: vec![],
+            body: None,
+            replies: vec![html(200, "the forms, or a sign out button when signed in")]
+        },
+        "login" | "register" => Operation {
+            summary: "Sign in, or create an account and sign in, from the form",
+            params: vec![],
+            body: Some("Credentials"),
+            replies: vec![
+                Reply { status: 303, description: "signed in, on to the pies with the session cookie set", media: "text/html", schema: None },
+                html(400, "missing or unacceptable username or password"),
+                html(401, "wrong username or password"),
+                html(403, "the csrf token didn't match"),
+                html(409, "the username is taken")
+            ]
+        },
+        "logout" => Operation {
+            summary: "Sign out from the form",
+            params: vec![],
+            body: None,
+            replies: vec![
+                Reply { status: 303, description: "signed out, on to the sign in page", media: "text/html", schema: None },
+                html(403, "the csrf token didn't match")
+            ]
+        },
+        "restock" => Operation {
+            summary: "Put slices back on sale",
+            params: vec![pie_id()],
+            body: Some("Restock"),
+            replies: vec![
+                json(200, "the pie's new count", reference("Inventory")),
+                json(400, "invalid parameters, or more slices on sale than the pie has", reference("Invalid")),
+                text(404, "no such pie")
+            ]
+        },
+        "refund" => Operation {
+            summary: "Take back slices a user bought",
+            params: vec![pie_id()],
+            body: Some("Refund"),
+            replies: vec![
+                json(200, "the pie's new count", reference("Inventory")),
+                json(400, "invalid parameters, or more slices than the user bought", reference("Invalid")),
+                text(404, "no such pie")
+            ]
+        },
+        "reset" => Operation {
+            summary: "Every pie back to full, with no purchases",
+            params: vec![],
+            body: None,
+            replies: vec![
+                json(200, "what was reset", reference("RestoreSummary")),
+                json(400, "the body couldn't be read", reference("Invalid"))
+            ]
+        },
+        "audit" => Operation {
+            summary: "Who restocked, refunded, reset and imported, newest first",
+            params: vec![query("limit", "integer", false, "100 by default, at least 1 and at most 1000")],
+            body: None,
+            replies: vec![
+                json(200, "the entries", array(reference("AuditEntry"))),
+                json(400, "limit isn't a whole number of at least 1", reference("Invalid"))
+            ]
+        },
+        "api_register" => Operation {
+            summary: "Create an account and sign in",
+            params: vec![],
+            body: Some("Credentials"),
+            replies: vec![
+                json(201, "the new session", envelope("Session")),
+                json(400, "invalid_request", reference("Envelope")),
+                json(409, "username_taken", reference("Envelope"))
+            ]
+        },
+        "api_login" => Operation {
+            summary: "Sign in",
+            params: vec![],
+            body: Some("Credentials"),
+            replies: vec![
+                json(201, "the new session", envelope("Session")),
+                json(400, "invalid_request", reference("Envelope")),
+                json(401, "bad_credentials", reference("Envelope"))
+            ]
+        },
+        "api_logout" => Operation {
+            summary: "End the bearer token's session",
+            params: vec![],
+            body: None,
+            replies: vec![
+                json(200, "who was signed out", envelope("Account")),
+                json(401, "unauthorized", reference("Envelope"))
+            ]
+        },
+        "api_me" => Operation {
+            summary: "Who the bearer token belongs to",
+            params: vec![],
+            body: None,
+            replies: vec![
+                json(200, "the signed in user", envelope("Account")),
+                json(401, "unauthorized", reference("Envelope"))
+            ]
+        },
+        _ => return None
+    };
+    Some(op)
+}
+
+fn schemas() -> Json {
+    let strings = || array(kind("string"));
+    let error_codes = Json::Array(api::ERROR_CODES.iter().map(|code| string(code.name())).collect());
+
+    object(vec![
+        ("FieldError", schema(vec![("field", kind("string")), ("message", kind("string"))], &["field", "message"])),
+        ("Purchase", schema(vec![("username", kind("string")), ("slices", kind("integer"))], &["username", "slices"])),
+        ("Pie", schema(vec![
+            ("id", kind("integer")),
+            ("name", kind("string")),
+            ("image_url", kind("string")),
+            ("price_per_slice", kind("number")),
+            // null, with degraded set, while redis is unavailable
+            ("remaining_slices", object(vec![("type", string("integer")), ("nullable", Json::Boolean(true))])),
+            ("degraded", kind("boolean")),
+            ("purchases", array(reference("Purchase"))),
+            ("contains", strings()),
+            ("may_contain", strings()),
+            ("certified", strings()),
+            ("url", kind("string")),
+            ("image_src", kind("string")),
+            ("purchase_url", kind("string"))
+        ], &["id", "name", "price_per_slice", "remaining_slices", "degraded"])),
+        ("PieList", schema(vec![("pies", array(reference("Pie")))], &["pies"])),
+        ("Liveness", schema(vec![("status", kind("string"))], &["status"])),
+        ("Check", schema(vec![
+            ("name", kind("string")),
+            ("ok", kind("boolean")),
+            ("detail", kind("string"))
+        ], &["name", "ok", "detail"])),
+        ("Readiness", schema(vec![("ready", kind("boolean")), ("checks", array(reference("Check")))], &["ready", "checks"])),
+        ("Credentials", schema(vec![
+            ("username", kind("string")),
+            ("password", kind("string")),
+            ("csrf_token", kind("string"))
+        ], &["username", "password"])),
+        ("Session", schema(vec![
+            ("username", kind("string")),
+            ("token", kind("string")),
+            ("expires_in", kind("integer"))
+        ], &["username", "token", "expires_in"])),
+        ("Account", schema(vec![
+            ("username", kind("string")),
+            ("role", object(vec![("type", string("string")), ("enum", Json::Array(vec![
+                string(Role::Customer.name()), string(Role::Staff.name()), string(Role::Admin.name())
+            ]))]))
+        ], &["username", "role"])),
+        ("Restock", schema(vec![("slices", kind("integer")), ("csrf_token", kind("string"))], &["slices"])),
+        ("Refund", schema(vec![
+            ("username", kind("string")),
+            ("slices", kind("integer")),
+            ("csrf_token", kind("string"))
+        ], &["username", "slices"])),
+        ("Inventory", schema(vec![("pie_id", kind("integer")), ("remaining_slices", kind("integer"))], &["pie_id", "remaining_slices"])),
+        ("AuditEntry", schema(vec![
+            ("at", kind("integer")),
+            ("actor", kind("string")),
+            ("role", kind("string")),
+            ("action", object(vec![("type", string("string")), ("enum", Json::Array(vec![
+                string(audit::Action::Restock.name()), string(audit::Action::Refund.name()),
+                string(audit::Action::Reset.name()), string(audit::Action::Import.name())
+            ]))])),
+            ("pie_id", kind("integer")),
+            ("details", kind("string"))
+        ], &["at", "actor", "role", "action", "details"])),
+        ("PurchaseRequest", schema(vec![
+            ("username", kind("string")),
+            ("amount", kind("number")),
+            ("slices", kind("integer")),
+            ("csrf_token", kind("string"))
+        ], &["amount"])),
+        ("Receipt", schema(vec![
+            ("pie_id", kind("integer")),
+            ("username", kind("string")),
+            ("slices", kind("integer")),
+            ("amount", kind("number"))
+        ], &["pie_id", "username", "slices", "amount"])),
+        ("Recommendation", schema(vec![("pie_id", kind("integer")), ("pie_url", kind("string"))], &["pie_id", "pie_url"])),
+        ("LegacyRecommendation", schema(vec![("pie_url", kind("string"))], &["pie_url"])),
+        ("SearchResult", schema(vec![
+            ("id", kind("integer")),
+            ("name", kind("string")),
+            ("image_url", kind("string")),
+            ("price_per_slice", kind("number")),
+            ("labels", strings()),
+            ("score", kind("number"))
+        ], &["id", "name", "score"])),
+        ("SearchResults", schema(vec![
+            ("query", kind("string")),
+            ("page", kind("integer")),
+            ("per_page", kind("integer")),
+            ("total", kind("integer")),
+            ("results", array(reference("SearchResult")))
+        ], &["query", "page", "per_page", "total", "results"])),
+        ("ApiError", schema(vec![
+            ("code", object(vec![("type", string("string")), ("enum", error_codes)])),
+            ("message", kind("string")),
+            ("fields", array(reference("FieldError")))
+        ], &["code", "message", "fields"])),
+        ("Envelope", schema(vec![
+            ("data", object(vec![("nullable", Json::Boolean(true))])),
+            ("error", object(vec![("nullable", Json::Boolean(true)), ("allOf", Json::Array(vec![reference("ApiError")]))])),
+            ("request_id", kind("string"))
+        ], &["data", "error", "request_id"])),
+        ("LegacyMessage", schema(vec![("text", kind("string"))], &["text"])),
+        ("LegacyError", schema(vec![("error", kind("string"))], &["error"])),
+        ("Invalid", schema(vec![("error", kind("string")), ("fields", array(reference("FieldError")))], &["error", "fields"])),
+        ("ArmStats", schema(vec![
+            ("arm", kind("string")),
+            ("strategy", kind("string")),
+            ("recommendations", kind("integer")),
+            ("conversions", kind("integer")),
+            ("slices", kind("integer")),
+            ("conversion_rate", kind("number"))
+        ], &["arm", "strategy"])),
+        ("ExperimentStats", schema(vec![("experiment", kind("string")), ("arms", array(reference("ArmStats")))], &["experiment", "arms"])),
+        ("SnapshotPie", schema(vec![
+            ("id", kind("integer")),
+            ("name", kind("string")),
+            ("image_url", kind("string")),
+            ("price_per_slice", kind("number")),
+            ("slices", kind("integer")),
+            ("labels", strings()),
+            ("remaining_slices", kind("integer")),
+            ("sold_out", kind("boolean")),
+            ("purchases", array(reference("Purchase")))
+        ], &["id", "remaining_slices", "purchases"])),
+        ("Snapshot", schema(vec![("pies", array(reference("SnapshotPie")))], &["pies"])),
+        ("RestorePie", schema(vec![
+            ("id", kind("integer")),
+            ("remaining_slices", kind("integer")),
+            ("purchases", array(reference("Purchase")))
+        ], &["id", "remaining_slices", "purchases"])),
+        ("Restore", schema(vec![("pies", array(reference("RestorePie")))], &["pies"])),
+        ("RestoreSummary", schema(vec![
+            ("restored", kind("integer")),
+            ("reset", kind("integer")),
+            ("skipped", array(kind("integer")))
+        ], &["restored", "reset", "skipped"]))
+    ])
+}
+
+// wildcard routes are fallbacks, not part of the api
+fn is_documentable(route: &routes::Route) -> bool {
+    !route.path.contains('*')
+}
+
+// /pies/:pie_id becomes /pies/{pie_id}
+pub fn openapi_path(path: &str) -> String {
+    let segments: Vec<String> = path.split('/').map(|segment| {
+        if segment.starts_with(':') {
+            format!("{{{}}}", &segment[1..])
+        } else {
+            segment.to_string()
+        }
+    }).collect();
+    segments.join("/")
+}
+
+fn path_params(path: &str) -> HashSet<&str> {
+    path.split('/').filter(|segment| segment.starts_with(':')).map(|segment| &segment[1..]).collect()
+}
+
+// a route answering every method is documented as get and post, the two it is used with
+fn methods(route: &routes::Route) -> Vec<String> {
+    if route.methods.is_empty() {
+        vec!["get".to_string(), "post".to_string()]
+    } else {
+        route.methods.iter().map(|method| method.to_string().to_lowercase()).collect()
+    }
+}
+
+// every documented route is in the table and every route in the table is documented,
+// with the same path parameters on both sides and every method it's routed for
+pub fn check(table: &Vec<routes::Route>) -> Result<(), Vec<String>> {
+    let mut problems = vec![];
+    let names: HashSet<&str> = table.iter().map(|route| route.name).collect();
+
+    for &(name, _) in DOCUMENTED {
+        if !names.contains(name) {
+            problems.push(format!("{} is documented but not routed", name));
+        }
+    }
+
+    for route in table.iter().filter(|route| is_documentable(route)) {
+        let (op, documented) = match (operation(route.name), documented_methods(route.name)) {
+            (Some(ref op), Some(documented)) => (op.params.iter()
+                .filter(|param| param.location == "path")
+                .map(|param| param.name)
+                .collect::<HashSet<&str>>(), documented),
+            _ => {
+                problems.push(format!("{} {} is routed but not documented", route.name, route.path));
+                continue;
+            }
+        };
+        if op != path_params(route.path) {
+            problems.push(format!("{} {} documents path parameters {:?}", route.name, route.path, op));
+        }
+        for method in methods(route) {
+            if !documented.contains(&method.as_str()) {
+                problems.push(format!("{} {} is routed for {} but documents {:?}", route.name, route.path, method, documented));
+            }
+        }
+    }
+
+    if problems.is_empty() { Ok(()) } else { Err(problems) }
+}
+
+pub fn document(table: &Vec<routes::Route>) -> Json {
+    let mut paths: BTreeMap<String, Json> = BTreeMap::new();
+
+    for route in table.iter().filter(|route| is_documentable(route)) {
+        let mut op = match operation(route.name) {
+            Some(op) => op,
+            None => continue
+        };
+        // the role check answers before the handler does
+        if route.role.is_some() {
+            op.replies.push(json(401, "not signed in", reference("LegacyError")));
+            op.replies.push(json(403, "the user's role doesn't allow this", reference("LegacyError")));
+        }
+        // so does the rate limit, on any route it's configured for
+        let error = if route.path.starts_with("/api/") { reference("Envelope") } else { reference("LegacyError") };
+        if !ratelimit::exempt(route) {
+            op.replies.push(json(429, "too many requests, retry after the Retry-After header's seconds", error.clone()));
+        }
+        // and the redis breaker, on any route that can't do without redis
+        if !route.degrades {
+            op.replies.push(json(503, "redis is unavailable, retry after the Retry-After header's seconds", error));
+        }
+
+        let params: Vec<Json> = op.params.iter().map(|param| object(vec![
+            ("name", string(param.name)),
+            ("in", string(param.location)),
+            ("required", Json::Boolean(param.required)),
+            ("description", string(param.description)),
+            ("schema", kind(param.kind))
+        ])).collect();
+
+        // several replies may share a status, differing by media type
+        let mut responses: BTreeMap<String, Json> = BTreeMap::new();
+        for reply in &op.replies {
+            let entry = responses.entry(reply.status.to_string())
+                .or_insert_with(|| object(vec![("description", string(reply.description)), ("content", object(vec![]))]));
+            if let Json::Object(ref mut response) = *entry {
+                let media = match reply.schema {
+                    Some(ref schema) => object(vec![("schema", schema.clone())]),
+                    None => object(vec![])
+                };
+                if let Some(&mut Json::Object(ref mut content)) = response.get_mut("content") {
+                    content.insert(reply.media.to_string(), media);
+                }
+            }
+        }
+
+        let summary = match route.role {
+            Some(role) => format!("{} ({} only)", op.summary, role.name()),
+            None => op.summary.to_string()
+        };
+        let mut fields = vec![
+            ("operationId", string(route.name)),
+            ("summary", Json::String(summary)),
+            ("parameters", Json::Array(params)),
+            ("responses", Json::Object(responses))
+        ];
+        if let Some(body) = op.body {
+            fields.push(("requestBody", object(vec![("content", object(vec![
+                ("application/json", object(vec![("schema", reference(body))])),
+                ("application/x-www-form-urlencoded", object(vec![("schema", reference(body))]))
+            ]))])));
+        }
+        let operation = object(fields);
+
+        let entry = paths.entry(openapi_path(route.path)).or_insert_with(|| object(vec![]));
+        if let Json::Object(ref mut methods_map) = *entry {
+            for method in methods(route) {
+                methods_map.insert(method, operation.clone());
+            }
+        }
+    }
+
+    object(vec![
+        ("openapi", string("3.0.3")),
+        ("info", object(vec![("title", string("bakeoff")), ("version", string(VERSION))])),
+        ("paths", Json::Object(paths)),
+        ("components", object(vec![
+            ("schemas", schemas()),
+            ("securitySchemes", object(vec![
+                ("bearer", object(vec![("type", string("http")), ("scheme", string("bearer"))])),
+                ("session", object(vec![("type", string("apiKey")), ("in", string("cookie")), ("name", string("bakeoff_session"))]))
+            ]))
+        ])),
+        ("security", Json::Array(vec![
+            object(vec![]),
+            object(vec![("bearer", Json::Array(vec![]))]),
+            object(vec![("session", Json::Array(vec![]))])
+        ]))
+    ])
+}
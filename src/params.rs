@@ -0,0 +1,523 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::headers::ContentType;
+
+extern crate hyper;
+use hyper::mime::{Mime, TopLevel, SubLevel};
+
+extern crate url;
+use url::form_urlencoded;
+
+extern crate rustc_serialize;
+use rustc_serialize::json::Json;
+
+use std::io::Read;
+use std::str::FromStr;
+
+// request parameters from the query string plus a json or form encoded body,
+// checked field by field so a client is told everything it got wrong at once
+
+// no order, refund or restock comes near this, and it keeps slice counts well
+// inside what redis and isize can hold
+pub const MAX_SLICES: u64 = 1000000;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Text(String),
+    Number(f64),
+    List(Vec<String>)
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String
+}
+
+fn field_error(field: &str, message: &str) -> FieldError {
+    FieldError {
+        field: field.to_string(),
+        message: message.to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Query,
+    Json,
+    Form
+}
+
+#[derive(Clone, Debug)]
+pub struct Params {
+    pub source: Source,
+    fields: Vec<(String, Value)>
+}
+
+fn read_body(req: &mut Request) -> Result<String, FieldError> {
+    let mut body = String::new();
+    match req.body.read_to_string(&mut body) {
+        Ok(_) => Ok(body),
+        Err(_) => Err(field_error("body", "could not be read as utf-8"))
+    }
+}
+
+fn json_fields(body: &str) -> Result<Vec<(String, Value)>, Vec<FieldError>> {
+    let object = match Json::from_str(body) {
+        Ok(Json::Object(object)) => object,
+        Ok(_) => return Err(vec![field_error("body", "must be a json object")]),
+        Err(_) => return Err(vec![field_error("body", "is not valid json")])
+    };
+
+    let mut fields = vec![];
+    let mut errors = vec![];
+    for (key, value) in object {
+        let value = match value {
+            Json::String(s) => Value::Text(s),
+            Json::I64(n) => Value::Number(n as f64),
+            Json::U64(n) => Value::Number(n as f64),
+            Json::F64(n) => Value::Number(n),
+            Json::Array(items) => {
+                let strings: Vec<String> = items.iter()
+                    .filter_map(|item| item.as_string().map(|s| s.to_string()))
+                    .collect();
+                if strings.len() != items.len() {
+                    errors.push(field_error(&key, "must be a list of strings"));
+                    continue;
+                }
+                Value::List(strings)
+            }
+            _ => {
+                errors.push(field_error(&key, "must be a string, number or list of strings"));
+                continue;
+            }
+        };
+        fields.push((key, value));
+    }
+
+    if errors.is_empty() { Ok(fields) } else { Err(errors) }
+}
+
+pub fn read(req: &mut Request) -> Result<Params, Vec<FieldError>> {
+    let url = req.url.clone().into_generic_url();
+    let mut fields = vec![];
+    for (key, value) in url.query_pairs() {
+        fields.push((key.into_owned(), Value::Text(value.into_owned())));
+    }
+
+    let source = match req.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => Source::Json,
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded, _))) => Source::Form,
+        _ => Source::Query
+    };
+
+    match source {
+        Source::Json => {
+            let body = try!(read_body(req).map_err(|e| vec![e]));
+            fields.extend(try!(json_fields(&body)));
+        }
+        Source::Form => {
+            let body = try!(read_body(req).map_err(|e| vec![e]));
+            for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+                fields.push((key.into_owned(), Value::Text(value.into_owned())));
+            }
+        }
+        Source::Query => {}
+    }
+
+    Ok(Params { source: source, fields: fields })
+}
+
+impl Params {
+    fn unknown(&self, known: &[&str]) -> Vec<FieldError> {
+        let mut errors = vec![];
+        for &(ref key, _) in &self.fields {
+            if !known.contains(&key.as_str()) && !errors.iter().any(|e: &FieldError| &e.field == key) {
+                errors.push(field_error(key, "is not a recognised field"));
+            }
+        }
+        errors
+    }
+
+    fn single(&self, name: &str) -> Result<Option<&Value>, FieldError> {
+        let mut values = self.fields.iter().filter(|&&(ref key, _)| key == name);
+        let first = values.next().map(|&(_, ref value)| value);
+        if values.next().is_some() {
+            return Err(field_error(name, "was given more than once"));
+        }
+        Ok(first)
+    }
+
+    pub fn text(&self, name: &str) -> Result<Option<String>, FieldError> {
+        match try!(self.single(name)) {
+            Some(&Value::Text(ref s)) => Ok(Some(s.clone())),
+            Some(_) => Err(field_error(name, "must be a string")),
+            None => Ok(None)
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Result<Option<f64>, FieldError> {
+        let n = match try!(self.single(name)) {
+            Some(&Value::Number(n)) => n,
+            Some(&Value::Text(ref s)) => match f64::from_str(s.trim()) {
+                Ok(n) => n,
+                Err(_) => return Err(field_error(name, "must be a number"))
+            },
+            Some(&Value::List(_)) => return Err(field_error(name, "must be a number")),
+            None => return Ok(None)
+        };
+        if !n.is_finite() {
+            return Err(field_error(name, "must be a finite number"));
+        }
+        Ok(Some(n))
+    }
+
+    pub fn positive_integer(&self, name: &str) -> Result<Option<u64>, FieldError> {
+        match try!(self.number(name)) {
+            Some(n) if n.fract() != 0.0 => Err(field_error(name, "must be a whole number")),
+            Some(n) if n < 1.0 => Err(field_error(name, "must be at least 1")),
+            Some(n) if n > MAX_SLICES as f64 => Err(field_error(name, &format!("must be at most {}", MAX_SLICES))),
+            Some(n) => Ok(Some(n as u64)),
+            None => Ok(None)
+        }
+    }
+
+    // repeated fields, comma separated text and json lists all add up
+    pub fn list(&self, name: &str) -> Vec<String> {
+        let mut list = vec![];
+        for &(ref key, ref value) in &self.fields {
+            if key != name {
+                continue;
+            }
+            match *value {
+                Value::Text(ref s) => list.extend(s.split(",").map(|item| item.to_string())),
+                Value::List(ref items) => list.extend(items.iter().cloned()),
+                Value::Number(n) => list.push(n.to_string())
+            }
+        }
+        list.into_iter().filter(|item| !item.trim().is_empty()).collect()
+    }
+}
+
+fn collect<T>(result: Result<T, FieldError>, errors: &mut Vec<FieldError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            errors.push(e);
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PurchaseRequest {
+    // purchases belong to the signed in user; a username sent anyway must be theirs
+    pub username: Option<String>,
+    // missing is not a validation error, it is bad math
+    pub amount: Option<f64>,
+    pub slices: u64,
+    pub csrf_token: Option<String>
+}
+
+pub fn purchase(params: &Params) -> Result<PurchaseRequest, Vec<FieldError>> {
+    let mut errors = params.unknown(&["username", "amount", "slices", "csrf_token"]);
+
+    let username = collect(params.text("username"), &mut errors).and_then(|u| u);
+    let amount = collect(params.number("amount"), &mut errors);
+    let slices = collect(params.positive_integer("slices"), &mut errors);
+    let csrf_token = collect(params.text("csrf_token"), &mut errors);
+
+    if let Some(a) = amount.and_then(|a| a) {
+        if a < 0.0 {
+            errors.push(field_error("amount", "must not be negative"));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(PurchaseRequest {
+        username: username.and_then(|u| if u.trim().is_empty() { None } else { Some(u) }),
+        amount: amount.and_then(|a| a),
+        slices: slices.and_then(|s| s).unwrap_or(1),
+        csrf_token: csrf_token.and_then(|t| t)
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    pub csrf_token: Option<String>
+}
+
+pub fn credentials(params: &Params) -> Result<Credentials, Vec<FieldError>> {
+    let mut errors = params.unknown(&["username", "password", "csrf_token"]);
+
+    let username = collect(params.text("username"), &mut errors).and_then(|u| u);
+    let password = collect(params.text("password"), &mut errors).and_then(|p| p);
+    let csrf_token = collect(params.text("csrf_token"), &mut errors);
+
+    for &(name, ref value) in [("username", &username), ("password", &password)].iter() {
+        match **value {
+            Some(ref v) if !v.is_empty() => {},
+            _ => if !errors.iter().any(|e| e.field == name) {
+                errors.push(field_error(name, "is required"));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(Credentials {
+        username: username.unwrap_or_default().trim().to_string(),
+        password: password.unwrap_or_default(),
+        csrf_token: csrf_token.and_then(|t| t)
+    })
+}
+
+// slices to put back on sale
+pub fn restock(params: &Params) -> Result<u64, Vec<FieldError>> {
+    let mut errors = params.unknown(&["slices", "csrf_token"]);
+
+    let slices = collect(params.positive_integer("slices"), &mut errors).and_then(|s| s);
+    if slices.is_none() && !errors.iter().any(|e| e.field == "slices") {
+        errors.push(field_error("slices", "is required"));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(slices.unwrap_or_default())
+}
+
+#[derive(Clone, Debug)]
+pub struct RefundRequest {
+    pub username: String,
+    pub slices: u64
+}
+
+pub fn refund(params: &Params) -> Result<RefundRequest, Vec<FieldError>> {
+    let mut errors = params.unknown(&["username", "slices", "csrf_token"]);
+
+    let username = collect(params.text("username"), &mut errors).and_then(|u| u);
+    let slices = collect(params.positive_integer("slices"), &mut errors).and_then(|s| s);
+
+    match username {
+        Some(ref u) if !u.trim().is_empty() => {},
+        _ => if !errors.iter().any(|e| e.field == "username") {
+            errors.push(field_error("username", "is required"));
+        }
+    }
+    if slices.is_none() && !errors.iter().any(|e| e.field == "slices") {
+        errors.push(field_error("slices", "is required"));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(RefundRequest {
+        username: username.unwrap_or_default(),
+        slices: slices.unwrap_or_default()
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct RecommendRequest {
+    pub username: String,
+    pub budget: String,
+    pub labels: Vec<String>,
+    pub exclude: Vec<String>,
+    pub certified: Vec<String>
+}
+
+pub fn recommend(params: &Params) -> Result<RecommendRequest, Vec<FieldError>> {
+    let mut errors = params.unknown(&["username", "budget", "labels", "exclude", "certified"]);
+
+    let username = collect(params.text("username"), &mut errors).and_then(|u| u);
+    let budget = collect(params.text("budget"), &mut errors).and_then(|b| b);
+    let labels = params.list("labels");
+
+    match username {
+        Some(ref u) if !u.trim().is_empty() => {},
+        _ => if !errors.iter().any(|e| e.field == "username") {
+            errors.push(field_error("username", "is required"));
+        }
+    }
+
+    match budget {
+        Some(ref b) if b == "cheap" || b == "premium" => {},
+        Some(_) => errors.push(field_error("budget", "must be cheap or premium")),
+        None => if !errors.iter().any(|e| e.field == "budget") {
+            errors.push(field_error("budget", "is required"));
+        }
+    }
+
+    if labels.is_empty() {
+        errors.push(field_error("labels", "must name at least one label"));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(RecommendRequest {
+        username: username.unwrap_or_default(),
+        budget: budget.unwrap_or_default(),
+        labels: labels,
+        exclude: params.list("exclude"),
+        certified: params.list("certified")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{credentials, json_fields, purchase, recommend, refund, restock, Params, Source, Value, MAX_SLICES};
+
+    fn query(fields: &[(&str, &str)]) -> Params {
+        Params {
+            source: Source::Query,
+            fields: fields.iter().map(|&(key, value)| (key.to_string(), Value::Text(value.to_string()))).collect()
+        }
+    }
+
+    fn json(body: &str) -> Params {
+        Params { source: Source::Json, fields: json_fields(body).unwrap() }
+    }
+
+    fn errors<T>(result: Result<T, Vec<super::FieldError>>) -> Vec<(String, String)> {
+        match result {
+            Ok(_) => panic!("expected field errors"),
+            Err(errors) => errors.into_iter().map(|e| (e.field, e.message)).collect()
+        }
+    }
+
+    fn error(field: &str, message: &str) -> (String, String) {
+        (field.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn json_bodies_must_be_objects_of_strings_numbers_and_lists() {
+        assert_eq!(errors(json_fields("[1]")), vec![error("body", "must be a json object")]);
+        assert_eq!(errors(json_fields("{")), vec![error("body", "is not valid json")]);
+        assert_eq!(errors(json_fields(r#"{"labels": ["a", 1], "slices": null}"#)).len(), 2);
+    }
+
+    #[test]
+    fn purchase_defaults_to_one_slice() {
+        let request = purchase(&query(&[("amount", "3.5"), ("username", " ")])).unwrap();
+        assert_eq!(request.slices, 1);
+        assert_eq!(request.amount, Some(3.5));
+        assert_eq!(request.username, None);
+        assert_eq!(purchase(&json(r#"{"slices": 3, "csrf_token": "t"}"#)).unwrap().slices, 3);
+    }
+
+    #[test]
+    fn purchase_rejects_unknown_and_repeated_fields() {
+        assert_eq!(errors(purchase(&query(&[("pie", "1"), ("pie", "2")]))), vec![error("pie", "is not a recognised field")]);
+        assert_eq!(errors(purchase(&query(&[("slices", "1"), ("slices", "2")]))), vec![error("slices", "was given more than once")]);
+    }
+
+    #[test]
+    fn purchase_rejects_bad_numbers() {
+        assert_eq!(errors(purchase(&query(&[("amount", "NaN")]))), vec![error("amount", "must be a finite number")]);
+        assert_eq!(errors(purchase(&query(&[("amount", "inf")]))), vec![error("amount", "must be a finite number")]);
+        assert_eq!(errors(purchase(&query(&[("amount", "lots")]))), vec![error("amount", "must be a number")]);
+        assert_eq!(errors(purchase(&query(&[("amount", "-1")]))), vec![error("amount", "must not be negative")]);
+        assert_eq!(errors(purchase(&json(r#"{"amount": ["1"]}"#))), vec![error("amount", "must be a number")]);
+        assert_eq!(errors(purchase(&query(&[("slices", "1.5")]))), vec![error("slices", "must be a whole number")]);
+        assert_eq!(errors(purchase(&query(&[("slices", "0")]))), vec![error("slices", "must be at least 1")]);
+        assert_eq!(errors(purchase(&json(r#"{"username": 7}"#))), vec![error("username", "must be a string")]);
+    }
+
+    #[test]
+    fn purchase_caps_slices() {
+        assert_eq!(purchase(&query(&[("slices", &MAX_SLICES.to_string())])).unwrap().slices, MAX_SLICES);
+        assert_eq!(errors(purchase(&query(&[("slices", &(MAX_SLICES + 1).to_string())]))),
+                   vec![error("slices", &format!("must be at most {}", MAX_SLICES))]);
+    }
+
+    #[test]
+    fn purchase_reports_every_error_at_once() {
+        let found = errors(purchase(&query(&[("amount", "x"), ("slices", "0"), ("colour", "red")])));
+        assert_eq!(found, vec![error("colour", "is not a recognised field"), error("amount", "must be a number"), error("slices", "must be at least 1")]);
+    }
+
+    #[test]
+    fn credentials_need_a_username_and_password() {
+        let given = credentials(&query(&[("username", " alice "), ("password", " secret ")])).unwrap();
+        assert_eq!(given.username, "alice");
+        assert_eq!(given.password, " secret ");
+        assert_eq!(errors(credentials(&query(&[("password", "")]))),
+                   vec![error("username", "is required"), error("password", "is required")]);
+    }
+
+    #[test]
+    fn credentials_reject_unknown_repeated_and_mistyped_fields() {
+        assert_eq!(errors(credentials(&query(&[("username", "a"), ("password", "b"), ("admin", "1")]))),
+                   vec![error("admin", "is not a recognised field")]);
+        assert_eq!(errors(credentials(&query(&[("username", "a"), ("username", "b"), ("password", "c")]))),
+                   vec![error("username", "was given more than once")]);
+        assert_eq!(errors(credentials(&json(r#"{"username": "a", "password": 1}"#))),
+                   vec![error("password", "must be a string")]);
+    }
+
+    #[test]
+    fn restock_needs_a_slice_count_in_range() {
+        assert_eq!(restock(&query(&[("slices", "4"), ("csrf_token", "t")])).unwrap(), 4);
+        assert_eq!(errors(restock(&query(&[]))), vec![error("slices", "is required")]);
+        assert_eq!(errors(restock(&query(&[("slices", "4"), ("slices", "4")]))), vec![error("slices", "was given more than once")]);
+        assert_eq!(errors(restock(&query(&[("slices", "4"), ("pie", "1")]))), vec![error("pie", "is not a recognised field")]);
+        assert_eq!(errors(restock(&query(&[("slices", "-inf")]))), vec![error("slices", "must be a finite number")]);
+        assert_eq!(errors(restock(&query(&[("slices", "1e7")]))), vec![error("slices", &format!("must be at most {}", MAX_SLICES))]);
+    }
+
+    #[test]
+    fn refund_needs_a_username_and_slices() {
+        let request = refund(&json(r#"{"username": "bob", "slices": 2}"#)).unwrap();
+        assert_eq!((request.username.as_str(), request.slices), ("bob", 2));
+        assert_eq!(errors(refund(&query(&[("username", " ")]))),
+                   vec![error("username", "is required"), error("slices", "is required")]);
+    }
+
+    #[test]
+    fn refund_rejects_unknown_repeated_and_bad_fields() {
+        assert_eq!(errors(refund(&query(&[("username", "bob"), ("slices", "1"), ("amount", "1")]))),
+                   vec![error("amount", "is not a recognised field")]);
+        assert_eq!(errors(refund(&query(&[("username", "a"), ("username", "b"), ("slices", "1")]))),
+                   vec![error("username", "was given more than once")]);
+        assert_eq!(errors(refund(&query(&[("username", "bob"), ("slices", "NaN")]))),
+                   vec![error("slices", "must be a finite number")]);
+        assert_eq!(errors(refund(&query(&[("username", "bob"), ("slices", "1000001")]))),
+                   vec![error("slices", &format!("must be at most {}", MAX_SLICES))]);
+    }
+
+    #[test]
+    fn recommend_gathers_lists_from_every_spelling() {
+        let request = recommend(&query(&[("username", "bob"), ("budget", "cheap"), ("labels", "fruit,,nuts"), ("labels", "red"), ("exclude", "nuts")])).unwrap();
+        assert_eq!(request.labels, vec!["fruit", "nuts", "red"]);
+        assert_eq!(request.exclude, vec!["nuts"]);
+        assert!(request.certified.is_empty());
+        let request = recommend(&json(r#"{"username": "bob", "budget": "premium", "labels": ["fruit"], "certified": ["vegan"]}"#)).unwrap();
+        assert_eq!(request.labels, vec!["fruit"]);
+        assert_eq!(request.certified, vec!["vegan"]);
+    }
+
+    #[test]
+    fn recommend_rejects_missing_bad_unknown_and_repeated_fields() {
+        assert_eq!(errors(recommend(&query(&[]))),
+                   vec![error("username", "is required"), error("budget", "is required"), error("labels", "must name at least one label")]);
+        assert_eq!(errors(recommend(&query(&[("username", "bob"), ("budget", "free"), ("labels", "fruit")]))),
+                   vec![error("budget", "must be cheap or premium")]);
+        assert_eq!(errors(recommend(&query(&[("username", "bob"), ("budget", "cheap"), ("labels", "fruit"), ("slices", "1")]))),
+                   vec![error("slices", "is not a recognised field")]);
+        assert_eq!(errors(recommend(&query(&[("username", "bob"), ("budget", "cheap"), ("budget", "cheap"), ("labels", "fruit")]))),
+                   vec![error("budget", "was given more than once")]);
+        assert_eq!(errors(recommend(&json(r#"{"username": "bob", "budget": 1, "labels": []}"#))),
+                   vec![error("budget", "must be a string"), error("labels", "must name at least one label")]);
+    }
+}
@@ -1,44 +1,45 @@
 
-extern crate r2d2;
-extern crate r2d2_redis;
 extern crate redis;
 
 use redis::Commands;
 
 use std::collections::HashMap;
-use std::ops::Deref;
-
-extern crate bit_vec;
-use bit_vec::BitVec;
 
 use pies;
+use index;
+use store;
+use diet;
+use experiments::Strategy;
 
 macro_rules! remaining_key { ($x:expr) => (format!("pie-{}-remaining", $x)) }
 macro_rules! purchases_key { ($x:expr) => (format!("pie-{}-purchases", $x)) }
 macro_rules! user_blacklist_key { ($x:expr) => (format!("user-{}-blacklist", $x)) }
 macro_rules! sold_out_key { () => ("pies-sold-out") }
 
-pub fn set_remaining(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>, pie: &pies::Pie) {
-    let conn = pool.get().expect("redis connection failed");
-    let _ : () = conn.set(remaining_key!(pie.id), pie.slices).unwrap();
-
-//    let n : u64 = conn.get(remaining_key!(pie.id)).unwrap();
-//    println!("setting remaining for pie {} to {}", pie.name, n);
+// writes go through the store once, and fail with Unavailable rather than panicking
+pub fn set_remaining(store: &store::Store, pie: &pies::Pie) -> Result<(), store::Unavailable> {
+    let _ : () = try!(store.write(|conn| conn.set(remaining_key!(pie.id), pie.slices)));
+    debug!("set remaining for pie {} to {}", pie.name, pie.slices);
+    Ok(())
 }
 
-pub fn get_remaining(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>, pie: &pies::Pie) -> u64 {
-    let conn = pool.get().expect("redis connection failed");
-    let n : u64 = conn.get(remaining_key!(pie.id)).unwrap();
-    n
+// reads are retried through the store
+pub fn get_remaining(store: &store::Store, pie: &pies::Pie) -> Result<u64, store::Unavailable> {
+    store.read(|conn| conn.get(remaining_key!(pie.id)))
 }
 
-pub fn get_all_remaining(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>, ids: &Vec<&u64>) -> Vec<u64> {
-    let conn = pool.get().expect("redis connection failed");
+pub fn get_all_remaining(store: &store::Store, ids: &Vec<&u64>) -> Result<Vec<u64>, store::Unavailable> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+
     let keys : Vec<String> = ids.iter().map( |&id|
         remaining_key!(id)
     ).collect();
-    let n : Vec<u64> = conn.get(keys).unwrap();
-    n
+    // MGET rather than conn.get, which sends a plain GET for a single key
+    store.read(|conn| redis::cmd("MGET")
+        .arg(keys.clone())
+        .query(conn))
 }
 
 pub enum PurchaseStatus {
@@ -47,205 +48,265 @@ pub enum PurchaseStatus {
     Success
 }
 
-const ALLOWED_PIES: isize = 3;
-
-fn get_user_blacklist(conn: &r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>, user: &String) -> BitVec {
-    let bits : Vec<u8> = conn.get(user_blacklist_key!(user)).unwrap();
-    let bitvec = BitVec::from_bytes(&bits);
-//    println!("{}: {:?}", user, bitvec);
-    bitvec
-}
-
-fn get_pie_soldout(conn: &r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>) -> BitVec {
-    let bits : Vec<u8> = conn.get(sold_out_key!()).unwrap();
-    let bitvec = BitVec::from_bytes(&bits);
-    bitvec
-}
-
-fn set_user_blacklist(conn: &r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>, user: &String, bitvec_pos: usize) {
-//    println!("{:?} {:?}", user, bitvec_pos);
-
-    // this doesn't work for some reason, so using the raw command version
-    // let bitset : bool = conn.setbit(, bitvec_pos, true).unwrap();
-    let _ : () = redis::cmd("SETBIT")
-        .arg(user_blacklist_key!(user))
-        .arg(bitvec_pos)
-        .arg(1)
-        .query(conn.deref())
-        .unwrap();
-
-}
-
-fn set_pie_soldout(conn: &r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>, bitvec_pos: usize) {
-    // this doesn't work for some reason, using the raw command version
-    // let _ : () = conn.setbit(sold_out_key!(), bitvec_pos, true).unwrap();
-
-    let _ : () = redis::cmd("SETBIT")
-        .arg(sold_out_key!())
-        .arg(bitvec_pos)
-        .arg(1)
-        .query(conn.deref())
-        .unwrap();
-}
-
-fn check_user_blacklist(conn: &r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>, user: &String, bitvec_pos: usize) -> bool {
-    let bitset : bool = conn.getbit(user_blacklist_key!(user), bitvec_pos).unwrap();
-    bitset
-}
-
-fn flatten_bv(labels: &Vec<String>, label_bitvecs: &HashMap<String, BitVec>) -> BitVec {
-    let mut bitvecvec = vec![];
-
-    for label in labels {
-        match label_bitvecs.get(label) {
-            Some(bv) => {
-                bitvecvec.push(bv);
-            }
-            None => {
-                return BitVec::from_elem(1, false);
-            }
-        }
-    }
-
-    let mut scratch_bv = match bitvecvec.first() {
-        Some(bv) => bv.clone().to_owned(),
-        None => {
-            return BitVec::from_elem(1, false)
-        }
-    };
+// most slices one user may buy of one pie
+pub const ALLOWED_PIES: isize = 3;
 
-    for bitvec in &bitvecvec {
-        scratch_bv.intersect(bitvec);
-    }
-
-    scratch_bv
-}
-
-fn pad_shorter_bv(bv1: &mut BitVec, bv2: &mut BitVec) -> () {
-    let (longer, shorter) = if bv1.len() > bv2.len() {
-        (bv1, bv2)
-    } else {
-        (bv2, bv1)
-    };
+// how many pies from the budget end the scarcity strategy chooses between
+const SCARCITY_WINDOW: usize = 3;
 
-    let diff = longer.len() - shorter.len();
-    shorter.grow(diff, false);
-}
+// candidates checked bit by bit before falling back to fetching both exclusion bitmaps
+const PROBE: usize = 8;
 
-pub fn recommend<'pie>(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>,
+pub fn recommend<'pie>(store: &store::Store,
                  labels: &Vec<String>,
                  pies: &'pie Vec<pies::Pie>,
-                 label_bitvecs: &HashMap<String, BitVec>,
+                 label_index: &index::LabelIndex,
+                 diet_index: &diet::DietIndex,
+                 exclusions: &diet::Exclusions,
                  user: &String,
-                 budget: &String) -> Option<&'pie pies::Pie> {
+                 budget: &String,
+                 strategy: &Strategy) -> Result<Option<&'pie pies::Pie>, store::Unavailable> {
 
-    let mut possible_pies = flatten_bv(&labels, &label_bitvecs);
-//    println!("possible pies {:?}", possible_pies);
+    let cheap = if budget == "cheap" {
+        true
+    } else if budget == "premium" {
+        false
+    } else {
+        return Ok(None);
+    };
 
-    if possible_pies.none() {
-        return None;
+    let possible_pies = match label_index.matching(labels) {
+        Some(bitmap) => bitmap,
+        None => return Ok(None)
+    };
+    let possible_pies = diet_index.filter(&possible_pies, exclusions);
+    if possible_pies.is_empty() {
+        return Ok(None);
     }
+    trace!("{} possible pies", possible_pies.len());
 
-    let conn = pool.get().expect("redis connection failed");
-    let mut user_blacklist = get_user_blacklist(&conn, user);
-    let mut sold_out_pies = get_pie_soldout(&conn);
-
-    pad_shorter_bv(&mut possible_pies, &mut user_blacklist);
-    pad_shorter_bv(&mut possible_pies, &mut sold_out_pies);
-    pad_shorter_bv(&mut user_blacklist, &mut sold_out_pies);
-
-    // todo: deconfusion comment
-    user_blacklist.negate();
-    sold_out_pies.negate();
-    possible_pies.intersect(&user_blacklist);
-    possible_pies.intersect(&sold_out_pies);
-
-//    println!("matching: {:?} ", possible_pies);
+    let wanted = match *strategy {
+        Strategy::Price => 1,
+        Strategy::Scarcity => SCARCITY_WINDOW
+    };
+    let probing = PROBE.max(wanted);
+
+    // the first few candidates' bits in one round trip, which is all most requests need
+    let probe : Vec<(usize, &'pie pies::Pie)> = index::candidates(&possible_pies, &[], cheap)
+        .filter_map(|i| pies.get(i).map(|pie| (i, pie)))
+        .filter(|&(_, pie)| diet_index.allows(pie, exclusions))
+        .take(probing)
+        .collect();
+    if probe.is_empty() {
+        return Ok(None);
+    }
+    let mut check = redis::pipe();
+    for &(i, _) in &probe {
+        check.cmd("GETBIT").arg(user_blacklist_key!(user)).arg(i)
+            .cmd("GETBIT").arg(sold_out_key!()).arg(i);
+    }
+    let bits : Vec<bool> = try!(store.read(|conn| check.query(conn)));
+    let mut window : Vec<&'pie pies::Pie> = probe.iter().zip(bits.chunks(2))
+        .filter(|&(_, bits)| !bits.iter().any(|&bit| bit))
+        .map(|(&(_, pie), _)| pie)
+        .take(wanted)
+        .collect();
+    trace!("{} of {} probed candidates allowed", window.len(), probe.len());
+
+    // too many of them excluded: both bitmaps, checked bit by bit without decoding
+    if window.len() < wanted && probe.len() == probing {
+        let (user_blacklist, sold_out_pies) : (Vec<u8>, Vec<u8>) = try!(store.read(|conn| redis::pipe()
+            .cmd("GET").arg(user_blacklist_key!(user))
+            .cmd("GET").arg(sold_out_key!())
+            .query(conn)));
+        window = index::candidates(&possible_pies, &[&user_blacklist, &sold_out_pies], cheap)
+            .filter_map(|i| pies.get(i))
+            .filter(|pie| diet_index.allows(pie, exclusions))
+            .take(wanted)
+            .collect();
+    }
 
-    if budget == "cheap" {
-        for (i, pie_match) in possible_pies.iter().enumerate().rev() {
-//            println!("cheap checking {} -> {}", i, pie_match);
-            if pie_match {
-                return pies.get(i);
-            }
+    match *strategy {
+        Strategy::Price => {
+            Ok(window.first().cloned())
         }
-    } else if budget == "premium" {
-        for (i, pie_match) in possible_pies.iter().enumerate() {
-//            println!("premium checking {} -> {}", i, pie_match);
-            if pie_match {
-                return pies.get(i);
+        Strategy::Scarcity => {
+            if window.is_empty() {
+                return Ok(None);
             }
+
+            // MGET rather than conn.get, which sends a plain GET for a single key
+            let keys : Vec<String> = window.iter().map( |pie|
+                remaining_key!(pie.id)
+            ).collect();
+            let remaining : Vec<u64> = try!(store.read(|conn| redis::cmd("MGET")
+                .arg(keys.clone())
+                .query(conn)));
+
+            // min_by_key keeps the first minimum, so ties still favour the budget end
+            Ok(window.into_iter().zip(remaining.into_iter())
+                .filter(|&(_, n)| n > 0)
+                .min_by_key(|&(_, n)| n)
+                .map(|(pie, _)| pie))
         }
-    } else {
-        return None;
     }
-    None
 }
 
-pub fn purchase_pie(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>,
+// the checks and every write of a purchase in one script, so a purchase is taken whole
+// or not at all, and two racing each other can't both take the last slices. returns
+// 0 for success, 1 over the limit and 2 gone, as PurchaseStatus
+const PURCHASE_SCRIPT: &'static str = r#"
+local pos = tonumber(ARGV[2])
+local amount = tonumber(ARGV[3])
+local limit = tonumber(ARGV[4])
+if redis.call('GETBIT', KEYS[1], pos) == 1 then
+  return 1
+end
+local left = tonumber(redis.call('GET', KEYS[3]) or '0')
+if left <= 0 or amount > left then
+  return 2
+end
+local previous = tonumber(redis.call('HGET', KEYS[2], ARGV[1]) or '0')
+if previous + amount > limit then
+  return 1
+end
+if previous + amount == limit then
+  redis.call('SETBIT', KEYS[1], pos, 1)
+end
+redis.call('HINCRBY', KEYS[2], ARGV[1], amount)
+redis.call('DECRBY', KEYS[3], amount)
+if left - amount <= 0 then
+  redis.call('SETBIT', KEYS[4], pos, 1)
+end
+return 0
+"#;
+
+pub fn purchase_pie(store: &store::Store,
                     pie: &pies::Pie,
                     bitvec_pos: usize,
                     user: &String,
-                    amount: isize) -> PurchaseStatus {
-    if amount > ALLOWED_PIES {
-        return PurchaseStatus::Fatty;
-    }
-
-//    println!("bitvec pos for purchase {}", bitvec_pos);
-
-    let conn = pool.get().expect("redis connection failed");
-    if check_user_blacklist(&conn, user, bitvec_pos) {
-//        println!("blocked purchase via blacklist");
-        return PurchaseStatus::Fatty;
-    }
-
-    let prev_purchase : bool = conn.hexists(purchases_key!(pie.id), user).unwrap();
-    let num_left : isize = conn.get(remaining_key!(pie.id)).unwrap();
-
-    if num_left <= 0 {
-        return PurchaseStatus::Gone;
-    }
-
-    if amount > num_left {
-        return PurchaseStatus::Gone;
+                    slices: u64) -> Result<PurchaseStatus, store::Unavailable> {
+    // compared before the cast, so no count can wrap into a negative amount
+    if slices > ALLOWED_PIES as u64 {
+        return Ok(PurchaseStatus::Fatty);
     }
 
-    if prev_purchase {
-        let previous_amount : isize = conn.hget(purchases_key!(pie.id), user).unwrap();
-//        println!("previous amount {:?}", previous_amount);
-        if previous_amount + amount > ALLOWED_PIES {
-            return PurchaseStatus::Fatty;
-        } else {
-            if previous_amount + amount == ALLOWED_PIES {
-//                println!("reached max amount");
-                set_user_blacklist(&conn, user, bitvec_pos)
-            }
+    trace!("bitvec pos for purchase {}", bitvec_pos);
 
-            let _ : isize = conn.hincr(purchases_key!(pie.id), user, amount).unwrap();
-            let _ : () = conn.incr(remaining_key!(pie.id), -1 * amount).unwrap();
-//            println!("bought {} pies total!", n)
-        }
-    } else {
-//        println!("buying pie!");
-        if amount == ALLOWED_PIES {
-            set_user_blacklist(&conn, user, bitvec_pos)
+    let script = redis::Script::new(PURCHASE_SCRIPT);
+    let status : i64 = try!(store.write(|conn| script
+        .key(user_blacklist_key!(user))
+        .key(purchases_key!(pie.id))
+        .key(remaining_key!(pie.id))
+        .key(sold_out_key!())
+        .arg(user.as_str())
+        .arg(bitvec_pos)
+        .arg(slices)
+        .arg(ALLOWED_PIES as i64)
+        .invoke(conn)));
+
+    Ok(match status {
+        0 => PurchaseStatus::Success,
+        1 => {
+            debug!("{} can't have {} more of pie {}", user, slices, pie.id);
+            PurchaseStatus::Fatty
         }
+        _ => PurchaseStatus::Gone
+    })
+}
 
-        let _ : isize = conn.hincr(purchases_key!(pie.id), user, amount).unwrap();
-        let _ : () = conn.incr(remaining_key!(pie.id), -1 * amount).unwrap();
-    }
+pub enum RestockStatus {
+    // with the slices now remaining
+    Restocked(u64),
+    // more would be on sale than the pie has slices; with the slices remaining
+    TooMany(u64)
+}
 
-    if num_left - amount <= 0 {
-        set_pie_soldout(&conn, bitvec_pos);
-    }
+// in one script so a purchase can't land between the check and the increment
+const RESTOCK_SCRIPT: &'static str = r#"
+local remaining = tonumber(redis.call('GET', KEYS[1]) or '0')
+local slices = tonumber(ARGV[1])
+if remaining + slices > tonumber(ARGV[2]) then
+  return {0, remaining}
+end
+remaining = redis.call('INCRBY', KEYS[1], slices)
+if remaining > 0 then
+  redis.call('SETBIT', KEYS[2], ARGV[3], 0)
+end
+return {1, remaining}
+"#;
+
+// puts slices back on sale, and the pie back in recommendations. never more than the
+// catalog's slices, so an export of a restocked pie can always be imported again
+pub fn restock(store: &store::Store,
+               pie: &pies::Pie,
+               bitvec_pos: usize,
+               slices: u64) -> Result<RestockStatus, store::Unavailable> {
+    let script = redis::Script::new(RESTOCK_SCRIPT);
+    let (restocked, remaining) : (i64, u64) = try!(store.write(|conn| script
+        .key(remaining_key!(pie.id))
+        .key(sold_out_key!())
+        .arg(slices)
+        .arg(pie.slices)
+        .arg(bitvec_pos)
+        .invoke(conn)));
+    Ok(if restocked == 1 {
+        RestockStatus::Restocked(remaining)
+    } else {
+        RestockStatus::TooMany(remaining)
+    })
+}
 
-    PurchaseStatus::Success
+pub enum RefundStatus {
+    // with the slices now remaining
+    Refunded(u64),
+    // the user never bought that many
+    NotPurchased
 }
 
-pub fn pie_purchases(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>, pie: &pies::Pie) -> Vec<pies::Purchase> {
-    let conn = pool.get().expect("redis connection failed");
+// like a purchase, taken back whole or not at all; -1 when the user bought fewer
+const REFUND_SCRIPT: &'static str = r#"
+local slices = tonumber(ARGV[2])
+local bought = tonumber(redis.call('HGET', KEYS[1], ARGV[1]) or '0')
+if bought < slices then
+  return -1
+end
+if bought == slices then
+  redis.call('HDEL', KEYS[1], ARGV[1])
+else
+  redis.call('HSET', KEYS[1], ARGV[1], bought - slices)
+end
+local remaining = redis.call('INCRBY', KEYS[2], slices)
+redis.call('SETBIT', KEYS[3], ARGV[3], 0)
+redis.call('SETBIT', KEYS[4], ARGV[3], 0)
+return remaining
+"#;
+
+// takes back slices a user bought, so they're on sale again and the user is under the limit
+pub fn refund(store: &store::Store,
+              pie: &pies::Pie,
+              bitvec_pos: usize,
+              user: &String,
+              slices: u64) -> Result<RefundStatus, store::Unavailable> {
+    let script = redis::Script::new(REFUND_SCRIPT);
+    let remaining : i64 = try!(store.write(|conn| script
+        .key(purchases_key!(pie.id))
+        .key(remaining_key!(pie.id))
+        .key(user_blacklist_key!(user))
+        .key(sold_out_key!())
+        .arg(user.as_str())
+        .arg(slices)
+        .arg(bitvec_pos)
+        .invoke(conn)));
+    Ok(if remaining < 0 {
+        RefundStatus::NotPurchased
+    } else {
+        RefundStatus::Refunded(remaining as u64)
+    })
+}
 
-    let purchases : HashMap<String, u64> = conn.hgetall(purchases_key!(pie.id)).unwrap();
+pub fn pie_purchases(store: &store::Store, pie: &pies::Pie) -> Result<Vec<pies::Purchase>, store::Unavailable> {
+    let purchases : HashMap<String, u64> = try!(store.read(|conn| conn.hgetall(purchases_key!(pie.id))));
 
     let mut vec = Vec::new();
     for (user, amount) in &purchases {
@@ -256,6 +317,56 @@ pub fn pie_purchases(pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>, pie:
         vec.push(purchase);
     }
 
-//    println!("{:?}", vec);
-    vec
+    trace!("purchases of pie {}: {:?}", pie.id, vec);
+    Ok(vec)
+}
+
+pub fn get_sold_out(store: &store::Store) -> Result<Vec<u8>, store::Unavailable> {
+    store.read(|conn| conn.get(sold_out_key!()))
+}
+
+// found with SCAN, which unlike KEYS doesn't hold up every other client while it walks
+// the keyspace
+fn blacklist_keys(store: &store::Store) -> Result<Vec<String>, store::Unavailable> {
+    store.read(|conn| {
+        let keys : redis::Iter<String> = try!(conn.scan_match(user_blacklist_key!("*")));
+        Ok(keys.collect())
+    })
+}
+
+// what one pie's live state becomes
+pub struct Restored<'a> {
+    pub pie: &'a pies::Pie,
+    pub bitvec_pos: usize,
+    pub remaining: u64,
+    pub purchases: &'a [pies::Purchase]
+}
+
+// replaces every blacklist, the sold out bitmap and each pie's remaining count and
+// purchases in one MULTI/EXEC, so no purchase runs against limits half rebuilt
+pub fn restore_all(store: &store::Store, restored: &[Restored]) -> Result<(), store::Unavailable> {
+    let blacklists = try!(blacklist_keys(store));
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for key in blacklists {
+        pipe.cmd("DEL").arg(key).ignore();
+    }
+    pipe.cmd("DEL").arg(sold_out_key!()).ignore();
+
+    for pie in restored {
+        pipe.cmd("SET").arg(remaining_key!(pie.pie.id)).arg(pie.remaining).ignore();
+        pipe.cmd("DEL").arg(purchases_key!(pie.pie.id)).ignore();
+        for purchase in pie.purchases {
+            pipe.cmd("HSET").arg(purchases_key!(pie.pie.id)).arg(&purchase.username).arg(purchase.slices).ignore();
+            if purchase.slices >= ALLOWED_PIES as u64 {
+                pipe.cmd("SETBIT").arg(user_blacklist_key!(purchase.username)).arg(pie.bitvec_pos).arg(1).ignore();
+            }
+        }
+        if pie.remaining == 0 {
+            pipe.cmd("SETBIT").arg(sold_out_key!()).arg(pie.bitvec_pos).arg(1).ignore();
+        }
+    }
+
+    store.write(|conn| pipe.query(conn))
 }
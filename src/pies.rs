@@ -2,6 +2,8 @@
 extern crate rustc_serialize;
 use rustc_serialize::json;
 
+use params;
+
 #[derive(RustcDecodable, RustcEncodable, Debug)]
 pub struct Pies {
     pub pies: Vec<Pie>
@@ -19,7 +21,10 @@ pub struct Pie {
     pub image_url: String,
     pub price_per_slice: f64,
     pub slices: u64,
-    pub labels: Vec<String>
+    pub labels: Vec<String>,
+    pub contains: Option<Vec<String>>,
+    pub may_contain: Option<Vec<String>>,
+    pub certified: Option<Vec<String>>
 }
 
 #[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
@@ -28,8 +33,38 @@ pub struct ShowPie {
     pub name: String,
     pub image_url: String,
     pub price_per_slice: f64,
-    pub remaining_slices: u64,
-    pub purchases: Vec<Purchase>
+    // None while redis is unavailable, and degraded says so
+    pub remaining_slices: Option<u64>,
+    pub degraded: bool,
+    pub purchases: Vec<Purchase>,
+    pub contains: Vec<String>,
+    pub may_contain: Vec<String>,
+    pub certified: Vec<String>,
+    // paths built from the route table, for links in pages and clients alike
+    pub url: String,
+    pub image_src: String,
+    pub purchase_url: String
+}
+
+// the detail page, carrying the token its purchase form must send back
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct PiePage {
+    pub pie: ShowPie,
+    pub csrf_token: String,
+    // who the form will buy as; without one the page asks them to sign in
+    pub username: Option<String>,
+    pub login_url: String
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct PurchaseResult {
+    pub status: u16,
+    pub message: String,
+    pub success: bool,
+    pub errors: Vec<params::FieldError>,
+    pub pie_id: u64,
+    pub pie_name: String,
+    pub pie_url: String
 }
 
 #[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
@@ -38,8 +73,14 @@ pub struct Purchase {
     pub slices: u64
 }
 
-pub fn new(json: String) -> Vec<Pie> {
-    let decoded: Pies = json::decode(&json).unwrap();
-    println!("{:?}", decoded.pies);
-    decoded.pies
-}
\ No newline at end of file
+pub fn new(json: String) -> Result<Vec<Pie>, json::DecoderError> {
+    let decoded: Pies = try!(json::decode(&json));
+    Ok(decoded.pies)
+}
+
+// after a restock or refund
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct Inventory {
+    pub pie_id: u64,
+    pub remaining_slices: u64
+}
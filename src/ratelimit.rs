@@ -0,0 +1,357 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::status;
+use iron::BeforeMiddleware;
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+
+extern crate r2d2;
+extern crate r2d2_redis;
+extern crate redis;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::process;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use accounts;
+use api;
+use routes;
+
+// a token bucket per route and client: each request takes a token, tokens come back
+// at per_second up to burst, and an empty bucket answers 429 with Retry-After
+macro_rules! bucket_key { ($route:expr, $who:expr) => (format!("ratelimit-{}-{}", $route, $who)) }
+
+// the in-process map never holds more than this many buckets; once full it evicts
+// down to nine tenths of that, so the work is spread over many new clients
+const MAX_BUCKETS: usize = 100000;
+
+#[derive(RustcDecodable, Copy, Clone, Debug)]
+pub struct Rule {
+    pub burst: f64,
+    pub per_second: f64
+}
+
+// loose enough for browsing, and buying pie is slower than that anyway
+const DEFAULT_RULE: Rule = Rule { burst: 60.0, per_second: 10.0 };
+const PURCHASE_RULE: Rule = Rule { burst: 5.0, per_second: 0.5 };
+
+#[derive(RustcDecodable, Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    // on unless turned off
+    pub enabled: Option<bool>,
+    // memory, per server, or redis, shared by every server
+    pub backend: Option<String>,
+    // for routes not listed below; purchases get a stricter one
+    pub default: Option<Rule>,
+    // route name to rule, e.g. "purchase" or "api_pies"
+    pub routes: Option<HashMap<String, Rule>>,
+    // take the client's address from X-Forwarded-For, when behind a proxy we run
+    pub trust_forwarded_for: Option<bool>
+}
+
+// each bucket keeps its route's rule, since one map holds every route's buckets
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    rule: Rule
+}
+
+// lua, so that reading and writing the bucket is one step for every server
+const TAKE_SCRIPT: &'static str = r#"
+local burst = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(state[1]) or burst
+local at = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - at) * rate / 1000)
+local allowed = 0
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+else
+    wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'at', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+return {allowed, wait}
+"#;
+
+enum Backend {
+    // with the most buckets it holds
+    InProcess(Mutex<HashMap<String, Bucket>>, usize),
+    Redis(r2d2::Pool<r2d2_redis::RedisConnectionManager>, redis::Script)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000)
+        .unwrap_or(0)
+}
+
+impl Backend {
+    // Ok, or Err with how many milliseconds until a token is back
+    fn take(&self, key: String, rule: &Rule) -> Result<(), u64> {
+        match *self {
+            Backend::InProcess(ref buckets, cap) => {
+                let mut buckets = buckets.lock().unwrap();
+                let now = Instant::now();
+                if buckets.len() >= cap && !buckets.contains_key(&key) {
+                    evict(&mut buckets, now, cap / 10 * 9);
+                }
+
+                let bucket = buckets.entry(key).or_insert(Bucket { tokens: rule.burst, updated: now, rule: *rule });
+                bucket.tokens = refill(bucket, now);
+                bucket.updated = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    Ok(())
+                } else {
+                    Err(((1.0 - bucket.tokens) * 1000.0 / rule.per_second).ceil() as u64)
+                }
+            },
+            Backend::Redis(ref pool, ref script) => {
+                let taken = pool.get().map_err(|e| e.to_string()).and_then(|conn| {
+                    script.key(key).arg(rule.burst).arg(rule.per_second).arg(now_ms())
+                        .invoke::<(i64, i64)>(&*conn)
+                        .map_err(|e| e.to_string())
+                });
+                match taken {
+                    Ok((1, _)) => Ok(()),
+                    Ok((_, wait)) => Err(wait as u64),
+                    // better to let people buy pie than to turn everyone away
+                    Err(e) => {
+                        warn!("rate limit check failed, letting the request through: {}", e);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+}
+
+// what the bucket holds now, without taking anything
+fn refill(bucket: &Bucket, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated);
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    (bucket.tokens + seconds * bucket.rule.per_second).min(bucket.rule.burst)
+}
+
+// full buckets go first, since forgetting one changes nothing, then the least recently
+// used until there are at most keep left
+fn evict(buckets: &mut HashMap<String, Bucket>, now: Instant, keep: usize) {
+    buckets.retain(|_, bucket| refill(bucket, now) < bucket.rule.burst);
+    if buckets.len() > keep {
+        let mut used: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+        used.sort();
+        let cutoff = used[used.len() - keep - 1];
+        buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+}
+
+// load balancers and scrapers poll these from a handful of addresses, and a 429 there
+// would take a healthy server out of rotation
+const EXEMPT: &'static [&'static str] = &["healthz", "readyz", "metrics"];
+
+pub fn exempt(route: &routes::Route) -> bool {
+    EXEMPT.contains(&route.name)
+}
+
+pub struct Limiter {
+    config: RateLimitConfig,
+    backend: Arc<Backend>
+}
+
+impl Limiter {
+    pub fn new(config: &RateLimitConfig, pool: &r2d2::Pool<r2d2_redis::RedisConnectionManager>) -> Limiter {
+        let backend = match config.backend.clone().unwrap_or("memory".to_string()).as_str() {
+            "memory" => Backend::InProcess(Mutex::new(HashMap::new()), MAX_BUCKETS),
+            "redis" => Backend::Redis(pool.clone(), redis::Script::new(TAKE_SCRIPT)),
+            other => {
+                error!("unknown rate limit backend {}", other);
+                process::exit(1);
+            }
+        };
+        Limiter {
+            config: config.clone(),
+            backend: Arc::new(backend)
+        }
+    }
+
+    fn rule(&self, route: &routes::Route) -> Option<Rule> {
+        if !self.config.enabled.unwrap_or(true) || exempt(route) {
+            return None;
+        }
+        let configured = self.config.routes.as_ref().and_then(|routes| routes.get(route.name).cloned());
+        let rule = configured.unwrap_or_else(|| {
+            if route.path.ends_with("/purchases") {
+                PURCHASE_RULE
+            } else {
+                self.config.default.unwrap_or(DEFAULT_RULE)
+            }
+        });
+        // a rule that never refills would lock people out for good
+        if rule.burst < 1.0 || rule.per_second <= 0.0 {
+            error!("rate limit for {} needs burst >= 1 and per_second > 0", route.name);
+            process::exit(1);
+        }
+        Some(rule)
+    }
+
+    // the middleware for one route, or None when it isn't limited
+    pub fn limit(&self, route: &routes::Route) -> Option<Limit> {
+        self.rule(route).map(|rule| Limit {
+            route: route.name,
+            rule: rule,
+            envelope: route.path.starts_with("/api/"),
+            trust_forwarded_for: self.config.trust_forwarded_for.unwrap_or(false),
+            backend: self.backend.clone()
+        })
+    }
+}
+
+pub struct Limit {
+    route: &'static str,
+    rule: Rule,
+    // api routes answer with the api's envelope, the rest with the legacy error shape
+    envelope: bool,
+    trust_forwarded_for: bool,
+    backend: Arc<Backend>
+}
+
+fn forwarded_for(req: &Request) -> Option<String> {
+    req.headers.get_raw("X-Forwarded-For")
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .and_then(|value| if value.is_empty() { None } else { Some(value) })
+}
+
+impl Limit {
+    // service accounts and signed in users get their own bucket wherever they connect
+    // from; everyone else shares one per address
+    fn client(&self, req: &Request) -> String {
+        if let Some(user) = accounts::current_user(req) {
+            let kind = if accounts::api_key(req).is_some() { "key" } else { "user" };
+            return format!("{}:{}", kind, user.username);
+        }
+        let address = if self.trust_forwarded_for { forwarded_for(req) } else { None };
+        format!("ip:{}", address.unwrap_or(req.remote_addr.ip().to_string()))
+    }
+}
+
+#[derive(Debug)]
+struct TooManyRequests;
+
+impl fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for TooManyRequests {
+    fn description(&self) -> &str {
+        "Too many requests, please slow down."
+    }
+}
+
+impl BeforeMiddleware for Limit {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let key = bucket_key!(self.route, self.client(req));
+        let wait_ms = match self.backend.take(key, &self.rule) {
+            Ok(()) => return Ok(()),
+            Err(wait_ms) => wait_ms
+        };
+
+        let body = if self.envelope {
+            api::error_body(req, api::ErrorCode::RateLimited)
+        } else {
+            format!("{{\"error\": \"{}\"}}", TooManyRequests.description())
+        };
+        let mut err = IronError::new(TooManyRequests, (
+            status::TooManyRequests,
+            body,
+            Header(ContentType::json())
+        ));
+        // whole seconds, rounded up so a client waiting that long always gets through
+        let retry_after = ((wait_ms + 999) / 1000).max(1);
+        err.response.headers.set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::{evict, Backend, Bucket, Rule};
+
+    const SLOW: Rule = Rule { burst: 2.0, per_second: 0.001 };
+    const FAST: Rule = Rule { burst: 5.0, per_second: 0.001 };
+
+    fn names(buckets: &HashMap<String, Bucket>) -> Vec<String> {
+        let mut names: Vec<String> = buckets.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn evicts_full_buckets_first() {
+        let start = Instant::now();
+        let now = start + Duration::from_millis(100);
+        let mut buckets = HashMap::new();
+        buckets.insert("old".to_string(), Bucket { tokens: 0.0, updated: start, rule: SLOW });
+        buckets.insert("full".to_string(), Bucket { tokens: 2.0, updated: now, rule: SLOW });
+        buckets.insert("refilled".to_string(), Bucket { tokens: 1.0, updated: start, rule: Rule { burst: 2.0, per_second: 100.0 } });
+        evict(&mut buckets, now, 5);
+        assert_eq!(names(&buckets), vec!["old"]);
+    }
+
+    #[test]
+    fn then_the_least_recently_used() {
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        for i in 0..10 {
+            buckets.insert(format!("client-{}", i), Bucket { tokens: 0.0, updated: start + Duration::from_millis(i), rule: SLOW });
+        }
+        evict(&mut buckets, start + Duration::from_millis(100), 3);
+        assert_eq!(names(&buckets), vec!["client-7", "client-8", "client-9"]);
+    }
+
+    #[test]
+    fn routes_sharing_the_map_keep_their_own_rules() {
+        let backend = Backend::InProcess(Mutex::new(HashMap::new()), 100);
+        assert!(backend.take("purchase-ip:1".to_string(), &SLOW).is_ok());
+        assert!(backend.take("pies-ip:1".to_string(), &FAST).is_ok());
+        assert!(backend.take("purchase-ip:1".to_string(), &SLOW).is_ok());
+        assert!(backend.take("purchase-ip:1".to_string(), &SLOW).is_err());
+        for _ in 0..4 {
+            assert!(backend.take("pies-ip:1".to_string(), &FAST).is_ok());
+        }
+        assert!(backend.take("pies-ip:1".to_string(), &FAST).is_err());
+    }
+
+    #[test]
+    fn a_full_map_makes_room_for_new_clients() {
+        let backend = Backend::InProcess(Mutex::new(HashMap::new()), 10);
+        for i in 0..10 {
+            assert!(backend.take(format!("purchase-ip:{}", i), &SLOW).is_ok());
+        }
+        // the first client is the least recently used, so it's forgotten and starts full
+        assert!(backend.take("pies-ip:new".to_string(), &FAST).is_ok());
+        if let Backend::InProcess(ref buckets, _) = backend {
+            let buckets = buckets.lock().unwrap();
+            assert!(buckets.len() <= 10);
+            assert!(!buckets.contains_key("purchase-ip:0"));
+            assert!(buckets.contains_key("pies-ip:new"));
+        }
+    }
+}
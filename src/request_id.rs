@@ -0,0 +1,60 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::{typemap, BeforeMiddleware, AfterMiddleware};
+
+extern crate rand;
+use rand::Rng;
+
+use std::str;
+
+// every request gets an id, echoed in X-Request-Id so a client's report can be
+// matched to our side; a sensible id sent by the client (or a proxy) is kept
+
+const HEADER: &'static str = "X-Request-Id";
+const MAX_LEN: usize = 64;
+
+pub struct RequestId;
+impl typemap::Key for RequestId { type Value = String; }
+
+fn is_sensible(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN &&
+        id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn incoming(req: &Request) -> Option<String> {
+    req.headers.get_raw(HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.trim().to_string())
+        .and_then(|id| if is_sensible(&id) { Some(id) } else { None })
+}
+
+pub fn new_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+// the id middleware assigned, or a fresh one if it never ran
+pub fn get(req: &Request) -> String {
+    req.extensions.get::<RequestId>().cloned().unwrap_or_else(new_id)
+}
+
+impl BeforeMiddleware for RequestId {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let id = incoming(req).unwrap_or_else(new_id);
+        req.extensions.insert::<RequestId>(id);
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestId {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        res.headers.set_raw(HEADER, vec![get(req).into_bytes()]);
+        Ok(res)
+    }
+
+    // middleware that refuses a request answers through here, and still gets the id
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        err.response.headers.set_raw(HEADER, vec![get(req).into_bytes()]);
+        Err(err)
+    }
+}
@@ -3,10 +3,25 @@ use iron::prelude::*;
 use iron::status;
 use iron::headers::ContentType;
 use iron::modifiers::Header;
+use iron::mime::{Mime, TopLevel, SubLevel, Attr, Value};
+use iron::headers::{ETag, EntityTag, CacheControl, CacheDirective, Location};
+
+use std::path::PathBuf;
 
 extern crate router;
 extern crate core;
 
+extern crate rustc_serialize;
+use rustc_serialize::json;
+
+use params;
+
+#[derive(RustcEncodable)]
+struct Invalid<'a> {
+    error: &'static str,
+    fields: &'a Vec<params::FieldError>
+}
+
 pub fn not_found() -> IronResult<Response> {
     Ok(Response::with((
                           status::NotFound,
@@ -47,6 +62,48 @@ pub fn glutton() -> IronResult<Response> {
                       )))
 }
 
+pub fn in_progress() -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Conflict,
+                          "{\"error\": \"That order is still being placed.\"}",
+                          Header(ContentType::json())
+                      )))
+}
+
+pub fn key_reused() -> IronResult<Response> {
+    Ok(Response::with((
+                          status::UnprocessableEntity,
+                          "{\"error\": \"That Idempotency-Key was used for a different order.\"}",
+                          Header(ContentType::json())
+                      )))
+}
+
+pub fn unavailable(retry_after: u64) -> IronResult<Response> {
+    let mut res = Response::with((
+                          status::ServiceUnavailable,
+                          "{\"error\": \"Pie is unavailable right now, please try again shortly.\"}",
+                          Header(ContentType::json())
+                      ));
+    res.headers.set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+    Ok(res)
+}
+
+pub fn unauthorized() -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Unauthorized,
+                          "{\"error\": \"Please sign in.\"}",
+                          Header(ContentType::json())
+                      )))
+}
+
+pub fn forbidden() -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Forbidden,
+                          "{\"error\": \"Your session expired, please try again.\"}",
+                          Header(ContentType::json())
+                      )))
+}
+
 pub fn no_recommends() -> IronResult<Response> {
     Ok(Response::with((
                           status::NotFound,
@@ -55,10 +112,27 @@ pub fn no_recommends() -> IronResult<Response> {
                       )))
 }
 
-pub fn recommend(i: usize) -> IronResult<Response> {
+#[derive(RustcEncodable)]
+struct Recommendation {
+    pie_url: String
+}
+
+pub fn recommend(pie_url: String) -> IronResult<Response> {
     Ok(Response::with((
                           status::Ok,
-                          format!("{{ \"pie_url\": \"http://rust.fanboy.app/pies/{}\" }}", i),
+                          json::encode(&Recommendation { pie_url: pie_url }).unwrap(),
+                          Header(ContentType::json())
+                      )))
+}
+
+pub fn invalid(errors: &Vec<params::FieldError>) -> IronResult<Response> {
+    let body = Invalid {
+        error: "Invalid request.",
+        fields: errors
+    };
+    Ok(Response::with((
+                          status::BadRequest,
+                          json::encode(&body).unwrap(),
                           Header(ContentType::json())
                       )))
 }
@@ -88,6 +162,16 @@ pub fn text(string: String) -> IronResult<Response> {
                       )))
 }
 
+// prometheus's text exposition format
+pub fn prometheus(text: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Ok,
+                          text,
+                          Header(ContentType(Mime(TopLevel::Text, SubLevel::Plain,
+                                                  vec![(Attr::Ext("version".to_string()), Value::Ext("0.0.4".to_string()))])))
+                      )))
+}
+
 pub fn json(json: String) -> IronResult<Response> {
     Ok(Response::with((
                           status::Ok,
@@ -96,10 +180,71 @@ pub fn json(json: String) -> IronResult<Response> {
                       )))
 }
 
+pub fn json_status(status: status::Status, json: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status,
+                          json,
+                          Header(ContentType::json())
+                      )))
+}
+
+pub fn csv(csv: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Ok,
+                          csv,
+                          Header(ContentType(Mime(TopLevel::Text, SubLevel::Ext("csv".to_string()), vec![])))
+                      )))
+}
+
 pub fn html(html: String) -> IronResult<Response> {
     Ok(Response::with((
                           status::Ok,
                           html,
                           Header(ContentType::html())
                       )))
-}
\ No newline at end of file
+}
+
+pub fn html_status(status: status::Status, html: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status,
+                          html,
+                          Header(ContentType::html())
+                      )))
+}
+
+// a day is plenty, the etag catches anything that changes sooner
+const IMAGE_MAX_AGE: u32 = 86400;
+
+pub fn image(path: PathBuf, etag: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Ok,
+                          path,
+                          Header(ETag(EntityTag::new(false, etag))),
+                          Header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(IMAGE_MAX_AGE)]))
+                      )))
+}
+
+pub fn svg(svg: String, etag: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status::Ok,
+                          svg,
+                          Header(ContentType(Mime(TopLevel::Image, SubLevel::Ext("svg+xml".to_string()), vec![]))),
+                          Header(ETag(EntityTag::new(false, etag))),
+                          Header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(IMAGE_MAX_AGE)]))
+                      )))
+}
+
+pub fn not_modified(etag: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status::NotModified,
+                          Header(ETag(EntityTag::new(false, etag)))
+                      )))
+}
+
+// after a form post, so reloading the next page doesn't post again
+pub fn see_other(location: String) -> IronResult<Response> {
+    Ok(Response::with((
+                          status::SeeOther,
+                          Header(Location(location))
+                      )))
+}
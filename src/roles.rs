@@ -0,0 +1,83 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::status;
+use iron::BeforeMiddleware;
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+
+extern crate rustc_serialize;
+use rustc_serialize::{Encodable, Encoder};
+
+use std::error::Error;
+use std::fmt;
+
+use accounts;
+
+// each role can do everything the ones before it can
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Customer,
+    Staff,
+    Admin
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name.to_lowercase().as_str() {
+            "customer" => Some(Role::Customer),
+            "staff" => Some(Role::Staff),
+            "admin" => Some(Role::Admin),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Role::Customer => "customer",
+            Role::Staff => "staff",
+            Role::Admin => "admin"
+        }
+    }
+}
+
+impl Encodable for Role {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+#[derive(Debug)]
+struct Denied(&'static str);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Error for Denied {
+    fn description(&self) -> &str {
+        self.0
+    }
+}
+
+fn deny(status: status::Status, message: &'static str) -> IronError {
+    IronError::new(Denied(message), (
+        status,
+        format!("{{\"error\": \"{}\"}}", message),
+        Header(ContentType::json())
+    ))
+}
+
+// linked in front of a single route's handler, so the route table says who may use it
+pub struct Require(pub Role);
+
+impl BeforeMiddleware for Require {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match accounts::current_user(req) {
+            Some(ref user) if user.role >= self.0 => Ok(()),
+            Some(_) => Err(deny(status::Forbidden, "You are not allowed to do that.")),
+            None => Err(deny(status::Unauthorized, "Please sign in."))
+        }
+    }
+}
@@ -0,0 +1,247 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::{status, typemap, Handler, BeforeMiddleware};
+use iron::method::Method;
+use iron::headers::{Host, Allow, ContentType};
+use iron::modifiers::Header;
+
+extern crate router;
+use router::Router;
+
+use api;
+use roles;
+use ratelimit;
+use store;
+
+use std::collections::HashMap;
+use std::str;
+use std::sync::Arc;
+
+// every route is declared once, in main, and both the router and url building come
+// from that list, so a link can't point at a path the server doesn't answer
+
+pub type Endpoint = fn(&mut Request) -> IronResult<Response>;
+
+pub struct Route {
+    pub name: &'static str,
+    // empty answers every method
+    pub methods: Vec<Method>,
+    pub path: &'static str,
+    pub handler: Endpoint,
+    // who may use it; None is anyone, signed in or not
+    pub role: Option<roles::Role>,
+    // still answers while redis is unavailable, without live state; see store::Guarded
+    pub degrades: bool
+}
+
+impl Route {
+    fn new(name: &'static str, methods: Vec<Method>, path: &'static str, handler: Endpoint) -> Route {
+        Route { name: name, methods: methods, path: path, handler: handler, role: None, degrades: false }
+    }
+
+    pub fn get(name: &'static str, path: &'static str, handler: Endpoint) -> Route {
+        Route::new(name, vec![Method::Get], path, handler)
+    }
+
+    pub fn post(name: &'static str, path: &'static str, handler: Endpoint) -> Route {
+        Route::new(name, vec![Method::Post], path, handler)
+    }
+
+    pub fn delete(name: &'static str, path: &'static str, handler: Endpoint) -> Route {
+        Route::new(name, vec![Method::Delete], path, handler)
+    }
+
+    pub fn any(name: &'static str, path: &'static str, handler: Endpoint) -> Route {
+        Route::new(name, vec![], path, handler)
+    }
+
+    // answers another method with the same handler
+    pub fn also(mut self, method: Method) -> Route {
+        self.methods.push(method);
+        self
+    }
+
+    pub fn requires(mut self, role: roles::Role) -> Route {
+        self.role = Some(role);
+        self
+    }
+
+    pub fn degrades(mut self) -> Route {
+        self.degrades = true;
+        self
+    }
+}
+
+// records which route answered, for the access log
+struct Named(&'static str);
+impl typemap::Key for Named { type Value = &'static str; }
+
+impl BeforeMiddleware for Named {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Named>(self.0);
+        Ok(())
+    }
+}
+
+// the name of the route answering the request, once the router has found it
+pub fn name(req: &Request) -> Option<&'static str> {
+    req.extensions.get::<Named>().cloned()
+}
+
+// a path routed for some methods answers the rest with 405 and the ones it does take
+struct MethodNotAllowed {
+    allow: Vec<Method>,
+    envelope: bool
+}
+
+impl Handler for MethodNotAllowed {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let body = if self.envelope {
+            api::error_body(req, api::ErrorCode::MethodNotAllowed)
+        } else {
+            "{\"error\": \"That method isn't allowed here.\"}".to_string()
+        };
+        Ok(Response::with((
+                              status::MethodNotAllowed,
+                              body,
+                              Header(ContentType::json()),
+                              Header(Allow(self.allow.clone()))
+                          )))
+    }
+}
+
+pub fn router(routes: &Vec<Route>, limiter: &ratelimit::Limiter) -> Router {
+    let mut router = Router::new();
+    // path to the methods it's routed for, in table order
+    let mut allowed: Vec<(&'static str, Vec<Method>)> = vec![];
+
+    for route in routes {
+        // every route gets its own chain, so the rate limit and role are checked
+        // before the handler runs, all behind the redis breaker
+        let mut handler = Chain::new(route.handler);
+        handler.link_before(Named(route.name));
+        if let Some(limit) = limiter.limit(route) {
+            handler.link_before(limit);
+        }
+        if let Some(role) = route.role {
+            handler.link_before(roles::Require(role));
+        }
+        let handler = store::Guarded {
+            handler: handler,
+            degrades: route.degrades,
+            envelope: route.path.starts_with("/api/")
+        };
+
+        if route.methods.is_empty() {
+            router.any(route.path, handler);
+            continue;
+        }
+        // the router takes one handler per method, so they share the chain
+        let handler = Arc::new(handler);
+        for method in &route.methods {
+            router.route(method.clone(), route.path, Shared(handler.clone()));
+        }
+        match allowed.iter().position(|&(path, _)| path == route.path) {
+            Some(i) => allowed[i].1.extend(route.methods.iter().cloned()),
+            None => allowed.push((route.path, route.methods.clone()))
+        }
+    }
+
+    for (path, methods) in allowed {
+        // a path that takes any method never needs the 405
+        if routes.iter().any(|route| route.path == path && route.methods.is_empty()) {
+            continue;
+        }
+        router.any(path, MethodNotAllowed { allow: methods, envelope: path.starts_with("/api/") });
+    }
+    router
+}
+
+struct Shared(Arc<store::Guarded>);
+
+impl Handler for Shared {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.0.handle(req)
+    }
+}
+
+pub struct Routes {
+    paths: HashMap<&'static str, &'static str>,
+    // overrides whatever the request says about where it was sent
+    public_url: Option<String>,
+    // any client can send X-Forwarded-*, so they only count behind a proxy that sets them
+    trust_forwarded: bool
+}
+
+// the first value of a proxy header, which may list every hop
+fn forwarded(req: &Request, name: &str) -> Option<String> {
+    req.headers.get_raw(name)
+        .and_then(|values| values.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .and_then(|value| if value.is_empty() { None } else { Some(value) })
+}
+
+impl Routes {
+    pub fn new(routes: &Vec<Route>, public_url: Option<String>, trust_forwarded: bool) -> Routes {
+        let mut paths = HashMap::new();
+        for route in routes {
+            if paths.insert(route.name, route.path).is_some() {
+                panic!("route {} is declared twice", route.name);
+            }
+        }
+
+        Routes {
+            paths: paths,
+            public_url: public_url.map(|url| url.trim_right_matches('/').to_string()),
+            trust_forwarded: trust_forwarded
+        }
+    }
+
+    // fills in each :param; panics on an unknown route or a missing param, both programming errors
+    pub fn path(&self, name: &str, params: &[(&str, &str)]) -> String {
+        let pattern = self.paths.get(name).expect(&format!("no route named {}", name));
+
+        let segments: Vec<String> = pattern.split('/').map(|segment| {
+            if segment.starts_with(':') {
+                let param = &segment[1..];
+                params.iter()
+                    .find(|&&(key, _)| key == param)
+                    .map(|&(_, value)| value.to_string())
+                    .expect(&format!("route {} needs {}", name, param))
+            } else {
+                segment.to_string()
+            }
+        }).collect();
+
+        segments.join("/")
+    }
+
+    // public_url when configured, otherwise where the client (or a trusted proxy) says it
+    // sent the request
+    pub fn base_url(&self, req: &Request) -> String {
+        if let Some(ref url) = self.public_url {
+            return url.clone();
+        }
+
+        let proxied = |name: &str| if self.trust_forwarded { forwarded(req, name) } else { None };
+        let url = req.url.clone().into_generic_url();
+        let scheme = proxied("X-Forwarded-Proto").unwrap_or(url.scheme().to_string());
+        let host = proxied("X-Forwarded-Host")
+            .or_else(|| req.headers.get::<Host>().map(|host| match host.port {
+                Some(port) => format!("{}:{}", host.hostname, port),
+                None => host.hostname.clone()
+            }))
+            .unwrap_or_else(|| match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or("localhost"), port),
+                None => url.host_str().unwrap_or("localhost").to_string()
+            });
+
+        format!("{}://{}", scheme, host)
+    }
+
+    pub fn url(&self, req: &Request, name: &str, params: &[(&str, &str)]) -> String {
+        format!("{}{}", self.base_url(req), self.path(name, params))
+    }
+}
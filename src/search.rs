@@ -0,0 +1,319 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use pies;
+
+// a label hit counts for more than a name hit
+const NAME_WEIGHT: f64 = 1.0;
+const LABEL_WEIGHT: f64 = 2.0;
+
+const EXACT_SCORE: f64 = 1.0;
+const PREFIX_SCORE: f64 = 0.7;
+const TYPO_SCORE: f64 = 0.5;
+const TWO_TYPO_SCORE: f64 = 0.3;
+
+// short words get no prefix or typo matching, otherwise "a" matches everything
+const MIN_PREFIX_LEN: usize = 2;
+const MIN_TYPO_LEN: usize = 4;
+const MIN_TWO_TYPO_LEN: usize = 7;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Field {
+    Name,
+    Label
+}
+
+#[derive(Clone, Debug)]
+struct Posting {
+    doc: usize,
+    field: Field
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct SearchResult {
+    pub id: u64,
+    pub name: String,
+    pub image_url: String,
+    pub price_per_slice: f64,
+    pub labels: Vec<String>,
+    pub score: f64
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct SearchResults {
+    pub query: String,
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+    pub results: Vec<SearchResult>
+}
+
+#[derive(Clone, Debug)]
+pub struct SearchIndex {
+    pies: Vec<pies::Pie>,
+    postings: HashMap<String, Vec<Posting>>,
+    // every indexed term in order, for prefix lookups
+    terms: Vec<String>
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+// levenshtein distance, giving up as soon as it must exceed max
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let diff = if a.len() > b.len() { a.len() - b.len() } else { b.len() - a.len() };
+    if diff > max {
+        return None;
+    }
+
+    let mut prev: Vec<usize> = (0..b.len() + 1).collect();
+    let mut cur = vec![0; b.len() + 1];
+    for i in 1..a.len() + 1 {
+        cur[0] = i;
+        let mut row_min = cur[0];
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            row_min = row_min.min(cur[j]);
+        }
+        if row_min > max {
+            return None;
+        }
+        ::std::mem::swap(&mut prev, &mut cur);
+    }
+
+    if prev[b.len()] <= max { Some(prev[b.len()]) } else { None }
+}
+
+impl SearchIndex {
+    pub fn new(pies: &Vec<pies::Pie>) -> SearchIndex {
+        let mut postings: HashMap<String, Vec<Posting>> = HashMap::new();
+
+        for (doc, pie) in pies.iter().enumerate() {
+            for token in tokenize(&pie.name) {
+                postings.entry(token).or_insert(vec![]).push(Posting { doc: doc, field: Field::Name });
+            }
+            for label in &pie.labels {
+                for token in tokenize(label) {
+                    postings.entry(token).or_insert(vec![]).push(Posting { doc: doc, field: Field::Label });
+                }
+            }
+        }
+
+        let mut terms: Vec<String> = postings.keys().cloned().collect();
+        terms.sort();
+
+        SearchIndex {
+            pies: pies.clone(),
+            postings: postings,
+            terms: terms
+        }
+    }
+
+    // every indexed term the query token could stand for, with how good a match it is
+    fn expand(&self, token: &str) -> Vec<(&String, f64)> {
+        let mut matches = vec![];
+        let char_len = token.chars().count();
+
+        let start = match self.terms.binary_search_by(|term| term.as_str().cmp(token)) {
+            Ok(i) | Err(i) => i
+        };
+        if char_len >= MIN_PREFIX_LEN {
+            for term in self.terms[start..].iter().take_while(|term| term.starts_with(token)) {
+                let score = if term == token { EXACT_SCORE } else { PREFIX_SCORE };
+                matches.push((term, score));
+            }
+        } else if self.terms.get(start).map_or(false, |term| term == token) {
+            matches.push((&self.terms[start], EXACT_SCORE));
+        }
+
+        let max_typos = if char_len >= MIN_TWO_TYPO_LEN {
+            2
+        } else if char_len >= MIN_TYPO_LEN {
+            1
+        } else {
+            0
+        };
+        if max_typos > 0 {
+            for term in &self.terms {
+                if term.starts_with(token) {
+                    continue;
+                }
+                match edit_distance(token, term, max_typos) {
+                    Some(1) => matches.push((term, TYPO_SCORE)),
+                    Some(2) => matches.push((term, TWO_TYPO_SCORE)),
+                    _ => {}
+                }
+            }
+        }
+
+        matches
+    }
+
+    // every query word has to match something; a pie's score is the sum of its best hit per word
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        let tokens = tokenize(query);
+        if tokens.is_empty() {
+            return vec![];
+        }
+
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        for (n, token) in tokens.iter().enumerate() {
+            let mut best: HashMap<usize, f64> = HashMap::new();
+            for (term, score) in self.expand(token) {
+                for posting in &self.postings[term] {
+                    let weight = match posting.field {
+                        Field::Name => NAME_WEIGHT,
+                        Field::Label => LABEL_WEIGHT
+                    };
+                    let hit = best.entry(posting.doc).or_insert(0.0);
+                    if score * weight > *hit {
+                        *hit = score * weight;
+                    }
+                }
+            }
+
+            if n == 0 {
+                scores = best;
+            } else {
+                scores = scores.into_iter()
+                    .filter_map(|(doc, score)| best.get(&doc).map(|hit| (doc, score + hit)))
+                    .collect();
+            }
+            if scores.is_empty() {
+                return vec![];
+            }
+        }
+
+        let mut ranked: Vec<(usize, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|&(a_doc, a_score), &(b_doc, b_score)| {
+            b_score.partial_cmp(&a_score)
+                .unwrap_or(Ordering::Equal)
+                .then(self.pies[a_doc].id.cmp(&self.pies[b_doc].id))
+        });
+
+        ranked.into_iter().map(|(doc, score)| {
+            let pie = &self.pies[doc];
+            SearchResult {
+                id: pie.id,
+                name: pie.name.clone(),
+                image_url: pie.image_url.clone(),
+                price_per_slice: pie.price_per_slice,
+                labels: pie.labels.clone(),
+                score: score
+            }
+        }).collect()
+    }
+}
+
+pub fn paginate(query: &str, results: Vec<SearchResult>, page: usize, per_page: usize) -> SearchResults {
+    let total = results.len();
+    // page comes from the client, so a huge one must not overflow
+    let results = results.into_iter()
+        .skip(page.saturating_sub(1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+
+    SearchResults {
+        query: query.to_string(),
+        page: page,
+        per_page: per_page,
+        total: total,
+        results: results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{paginate, SearchIndex, SearchResult};
+    use pies;
+
+    fn index() -> SearchIndex {
+        let pies: Vec<pies::Pie> = vec![
+            (1, "Apple Pie", vec!["fruit"]),
+            (2, "Cherry Crumble", vec!["fruit", "apple"]),
+            (3, "Chocolate Cream", vec!["chocolate"]),
+            (4, "Pumpkin Pie", vec!["seasonal"]),
+            (5, "Raspberry Tart", vec!["fruit"])
+        ].into_iter().map(|(id, name, labels): (u64, &str, Vec<&str>)| pies::Pie {
+            id: id,
+            name: name.to_string(),
+            image_url: String::new(),
+            price_per_slice: 1.0,
+            slices: 8,
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            contains: None,
+            may_contain: None,
+            certified: None
+        }).collect();
+        SearchIndex::new(&pies)
+    }
+
+    fn ids(results: &Vec<SearchResult>) -> Vec<u64> {
+        results.iter().map(|result| result.id).collect()
+    }
+
+    #[test]
+    fn a_label_hit_outranks_a_name_hit() {
+        assert_eq!(ids(&index().search("apple")), vec![2, 1]);
+    }
+
+    #[test]
+    fn equal_scores_are_ordered_by_id() {
+        assert_eq!(ids(&index().search("fruit")), vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn matches_prefixes_below_exact_words() {
+        let index = index();
+        let exact = index.search("chocolate");
+        let prefix = index.search("choc");
+        assert_eq!(ids(&prefix), vec![3]);
+        assert!((exact[0].score - 2.0).abs() < 1e-9);
+        assert!((prefix[0].score - 1.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn one_letter_only_matches_whole_words() {
+        assert_eq!(ids(&index().search("p")), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn matches_typos_by_word_length() {
+        let index = index();
+        assert_eq!(ids(&index.search("chery")), vec![2]);
+        assert_eq!(ids(&index.search("Pumpkn")), vec![4]);
+        // two typos once a word is long enough
+        let two = index.search("rasberri");
+        assert_eq!(ids(&two), vec![5]);
+        assert!((two[0].score - 0.3).abs() < 1e-9);
+        // none for short words
+        assert_eq!(ids(&index.search("apl")), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn every_word_must_match() {
+        let index = index();
+        assert_eq!(ids(&index.search("fruit tart")), vec![5]);
+        assert_eq!(ids(&index.search("apple tart")), Vec::<u64>::new());
+        assert_eq!(ids(&index.search(" ,.")), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn pages_through_results() {
+        let results = index().search("fruit");
+        let page = paginate("fruit", results.clone(), 2, 2);
+        assert_eq!(page.total, 3);
+        assert_eq!(ids(&page.results), vec![5]);
+        // page 0 is read as the first
+        assert_eq!(ids(&paginate("fruit", results.clone(), 0, 2).results), vec![1, 2]);
+        assert_eq!(ids(&paginate("fruit", results.clone(), 3, 2).results), Vec::<u64>::new());
+        assert_eq!(ids(&paginate("fruit", results, usize::max_value(), 2).results), Vec::<u64>::new());
+    }
+}
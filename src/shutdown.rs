@@ -0,0 +1,124 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::status;
+use iron::{typemap, BeforeMiddleware, AfterMiddleware};
+use iron::headers::{Connection, ContentType};
+use iron::modifiers::Header;
+
+extern crate ctrlc;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// on SIGTERM or SIGINT the server stops taking requests, answering 503 so a load balancer
+// moves on, and waits for the ones already running, purchases among them, to finish
+
+struct State {
+    draining: AtomicBool,
+    in_flight: AtomicUsize
+}
+
+#[derive(Clone)]
+pub struct Drain(Arc<State>);
+
+// marks a request counted in in_flight, so only those are counted back out
+struct Counted;
+impl typemap::Key for Counted { type Value = (); }
+
+#[derive(Debug)]
+struct ShuttingDown;
+
+impl fmt::Display for ShuttingDown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for ShuttingDown {
+    fn description(&self) -> &str {
+        "The server is shutting down, please try again."
+    }
+}
+
+impl Drain {
+    pub fn new() -> Drain {
+        Drain(Arc::new(State { draining: AtomicBool::new(false), in_flight: AtomicUsize::new(0) }))
+    }
+
+    fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight.load(Ordering::SeqCst)
+    }
+
+    // refuses new requests, then waits for the rest; true when none were left running
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.0.draining.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        while self.in_flight() > 0 {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+
+    fn finish(&self, req: &mut Request) {
+        if req.extensions.remove::<Counted>().is_some() {
+            self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl BeforeMiddleware for Drain {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        // counted before checking, so drain() can't see none running while one starts
+        self.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        if self.is_draining() {
+            self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let mut err = IronError::new(ShuttingDown, (
+                status::ServiceUnavailable,
+                format!("{{\"error\": \"{}\"}}", ShuttingDown.description()),
+                Header(ContentType::json()),
+                Header(Connection::close())
+            ));
+            err.response.headers.set_raw("Retry-After", vec![b"1".to_vec()]);
+            return Err(err);
+        }
+        req.extensions.insert::<Counted>(());
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Drain {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        self.finish(req);
+        // a kept alive connection would keep coming back here
+        if self.is_draining() {
+            res.headers.set(Connection::close());
+        }
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        self.finish(req);
+        Err(err)
+    }
+}
+
+// receives once for the first SIGTERM or SIGINT
+pub fn signals() -> Receiver<()> {
+    let (sender, receiver) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = sender.send(());
+    }).expect("failed to set the signal handler");
+    receiver
+}
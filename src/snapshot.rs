@@ -0,0 +1,341 @@
+extern crate csv;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use pies;
+use pie_state;
+use index;
+use store;
+
+// the catalog plus live inventory, for backups and moving state between environments
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct SnapshotPie {
+    pub id: u64,
+    pub name: String,
+    pub image_url: String,
+    pub price_per_slice: f64,
+    pub slices: u64,
+    pub labels: Vec<String>,
+    pub remaining_slices: u64,
+    pub sold_out: bool,
+    pub purchases: Vec<pies::Purchase>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct Snapshot {
+    pub pies: Vec<SnapshotPie>
+}
+
+// only the live fields matter on import; the catalog itself is never replaced
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct RestorePie {
+    pub id: u64,
+    pub remaining_slices: u64,
+    pub purchases: Vec<pies::Purchase>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct Restore {
+    pub pies: Vec<RestorePie>
+}
+
+pub enum RestoreError {
+    // a row that doesn't fit the catalog; nothing was written
+    Invalid(String),
+    Unavailable(store::Unavailable)
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    pub reset: usize,
+    // in the snapshot but not in the running catalog
+    pub skipped: Vec<u64>
+}
+
+const LIST_DELIMITER: &'static str = ";";
+const CSV_HEADERS: &'static [&'static str] = &["id", "name", "image_url", "price_per_slice", "slices",
+                                              "labels", "remaining_slices", "sold_out", "purchases"];
+
+// sorted_pies must be the price ordered catalog, whose positions the sold out bitmap uses
+pub fn take(store: &store::Store, sorted_pies: &Vec<pies::Pie>) -> Result<Snapshot, store::Unavailable> {
+    let ids: Vec<&u64> = sorted_pies.iter().map(|pie| &pie.id).collect();
+    let remaining = try!(pie_state::get_all_remaining(store, &ids));
+    let sold_out = try!(pie_state::get_sold_out(store));
+
+    let mut pies = Vec::new();
+    for (i, (pie, remaining)) in sorted_pies.iter().zip(remaining.into_iter()).enumerate() {
+        pies.push(SnapshotPie {
+            id: pie.id,
+            name: pie.name.clone(),
+            image_url: pie.image_url.clone(),
+            price_per_slice: pie.price_per_slice,
+            slices: pie.slices,
+            labels: pie.labels.clone(),
+            remaining_slices: remaining,
+            sold_out: index::is_set(&sold_out, i),
+            purchases: try!(pie_state::pie_purchases(store, pie))
+        });
+    }
+
+    Ok(Snapshot { pies: pies })
+}
+
+// what each pie's live state becomes, checked row by row against the catalog
+fn plan<'a>(sorted_pies: &'a Vec<pies::Pie>,
+            restore: &'a Restore) -> Result<(Vec<pie_state::Restored<'a>>, RestoreSummary), String> {
+    let mut by_id: HashMap<u64, &RestorePie> = HashMap::new();
+    for saved in &restore.pies {
+        if by_id.insert(saved.id, saved).is_some() {
+            return Err(format!("pie {} is in the snapshot more than once", saved.id));
+        }
+        let mut buyers = HashSet::new();
+        for purchase in &saved.purchases {
+            if purchase.username.trim().is_empty() {
+                return Err(format!("pie {} has a purchase without a username", saved.id));
+            }
+            if !buyers.insert(&purchase.username) {
+                return Err(format!("pie {} lists {} more than once", saved.id, purchase.username));
+            }
+            if purchase.slices == 0 || purchase.slices > pie_state::ALLOWED_PIES as u64 {
+                return Err(format!("pie {} has {} buying {} slices, but a purchase is 1 to {}",
+                                   saved.id, purchase.username, purchase.slices, pie_state::ALLOWED_PIES));
+            }
+        }
+    }
+
+    let mut restored = vec![];
+    let mut summary = RestoreSummary { restored: 0, reset: 0, skipped: vec![] };
+    for (i, pie) in sorted_pies.iter().enumerate() {
+        match by_id.remove(&pie.id) {
+            Some(saved) => {
+                if saved.remaining_slices > pie.slices {
+                    return Err(format!("pie {} has {} remaining_slices but only {} slices",
+                                       pie.id, saved.remaining_slices, pie.slices));
+                }
+                restored.push(pie_state::Restored {
+                    pie: pie,
+                    bitvec_pos: i,
+                    remaining: saved.remaining_slices,
+                    purchases: &saved.purchases
+                });
+                summary.restored += 1;
+            }
+            None => {
+                restored.push(pie_state::Restored {
+                    pie: pie,
+                    bitvec_pos: i,
+                    remaining: pie.slices,
+                    purchases: &[]
+                });
+                summary.reset += 1;
+            }
+        }
+    }
+
+    summary.skipped = by_id.keys().cloned().collect();
+    summary.skipped.sort();
+    Ok((restored, summary))
+}
+
+// replaces all live state: pies missing from the snapshot go back to full, and
+// blacklists and sold out bits are rebuilt from the restored purchases. nothing is
+// written unless every row fits its pie
+pub fn restore(store: &store::Store,
+               sorted_pies: &Vec<pies::Pie>,
+               restore: &Restore) -> Result<RestoreSummary, RestoreError> {
+    let (restored, summary) = try!(plan(sorted_pies, restore).map_err(RestoreError::Invalid));
+    try!(pie_state::restore_all(store, &restored).map_err(RestoreError::Unavailable));
+    Ok(summary)
+}
+
+pub fn to_csv(snapshot: &Snapshot) -> String {
+    let mut writer = csv::Writer::from_memory();
+    writer.write(CSV_HEADERS.iter().cloned()).unwrap();
+
+    for pie in &snapshot.pies {
+        let purchases: Vec<String> = pie.purchases.iter()
+            .map(|purchase| format!("{}:{}", purchase.username, purchase.slices))
+            .collect();
+        let row = vec![
+            pie.id.to_string(),
+            pie.name.clone(),
+            pie.image_url.clone(),
+            pie.price_per_slice.to_string(),
+            pie.slices.to_string(),
+            pie.labels.join(LIST_DELIMITER),
+            pie.remaining_slices.to_string(),
+            pie.sold_out.to_string(),
+            purchases.join(LIST_DELIMITER)
+        ];
+        writer.write(row.into_iter()).unwrap();
+    }
+
+    writer.as_string().to_string()
+}
+
+// reads back what to_csv writes, or any csv with id, remaining_slices and purchases columns
+pub fn from_csv(text: &str) -> Result<Restore, String> {
+    let mut reader = csv::Reader::from_string(text).has_headers(true);
+    let headers = try!(reader.headers().map_err(|e| e.to_string()));
+    let column = |name: &str| headers.iter().position(|header| header.trim() == name);
+
+    let (id_col, remaining_col) = match (column("id"), column("remaining_slices")) {
+        (Some(id), Some(remaining)) => (id, remaining),
+        _ => return Err("csv needs id and remaining_slices columns".to_string())
+    };
+    let purchases_col = column("purchases");
+
+    let mut pies = vec![];
+    for (n, record) in reader.records().enumerate() {
+        let line = n + 2;
+        let row = try!(record.map_err(|e| format!("line {}: {}", line, e)));
+        let cell = |i: usize| row.get(i).map(|value| value.trim()).unwrap_or("");
+
+        let mut purchases = vec![];
+        for entry in purchases_col.map(|i| cell(i)).unwrap_or("").split(LIST_DELIMITER) {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            // usernames may contain colons, the count never does
+            let mut parts = entry.rsplitn(2, ':');
+            let slices = parts.next().and_then(|n| u64::from_str(n.trim()).ok());
+            match (parts.next(), slices) {
+                (Some(username), Some(slices)) => purchases.push(pies::Purchase {
+                    username: username.to_string(),
+                    slices: slices
+                }),
+                _ => return Err(format!("line {}: purchase {} is not username:slices", line, entry))
+            }
+        }
+
+        pies.push(RestorePie {
+            id: try!(u64::from_str(cell(id_col)).map_err(|e| format!("line {}: id: {}", line, e))),
+            remaining_slices: try!(u64::from_str(cell(remaining_col))
+                                   .map_err(|e| format!("line {}: remaining_slices: {}", line, e))),
+            purchases: purchases
+        });
+    }
+
+    Ok(Restore { pies: pies })
+}
+
+#[cfg(test)]
+mod tests {
+    use pies::{Pie, Purchase};
+
+    use super::{from_csv, plan, to_csv, Restore, RestorePie, Snapshot, SnapshotPie};
+
+    fn catalog() -> Vec<Pie> {
+        [(1, "Apple", 8), (2, "Pecan", 6)].iter().map(|&(id, name, slices)| Pie {
+            id: id,
+            name: name.to_string(),
+            image_url: format!("http://example.com/{}.jpg", id),
+            price_per_slice: 3.5,
+            slices: slices,
+            labels: vec!["fruit".to_string()],
+            contains: None,
+            may_contain: None,
+            certified: None
+        }).collect()
+    }
+
+    fn purchase(username: &str, slices: u64) -> Purchase {
+        Purchase { username: username.to_string(), slices: slices }
+    }
+
+    fn row(id: u64, remaining_slices: u64, purchases: Vec<Purchase>) -> RestorePie {
+        RestorePie { id: id, remaining_slices: remaining_slices, purchases: purchases }
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let snapshot = Snapshot { pies: vec![
+            SnapshotPie {
+                id: 1,
+                name: "Apple, baked".to_string(),
+                image_url: "http://example.com/1.jpg".to_string(),
+                price_per_slice: 3.5,
+                slices: 8,
+                labels: vec!["fruit".to_string(), "sweet".to_string()],
+                remaining_slices: 3,
+                sold_out: false,
+                purchases: vec![purchase("alice", 3), purchase("bob:smith", 2)]
+            },
+            SnapshotPie {
+                id: 2,
+                name: "Pecan".to_string(),
+                image_url: "http://example.com/2.jpg".to_string(),
+                price_per_slice: 4.0,
+                slices: 6,
+                labels: vec![],
+                remaining_slices: 0,
+                sold_out: true,
+                purchases: vec![]
+            }
+        ] };
+
+        let restore = from_csv(&to_csv(&snapshot)).unwrap();
+        assert_eq!(restore.pies.len(), 2);
+        assert_eq!((restore.pies[0].id, restore.pies[0].remaining_slices), (1, 3));
+        assert_eq!(restore.pies[0].purchases.iter().map(|p| (p.username.as_str(), p.slices)).collect::<Vec<_>>(),
+                   vec![("alice", 3), ("bob:smith", 2)]);
+        assert_eq!((restore.pies[1].id, restore.pies[1].remaining_slices), (2, 0));
+        assert!(restore.pies[1].purchases.is_empty());
+    }
+
+    #[test]
+    fn csv_needs_ids_and_counts() {
+        assert_eq!(from_csv("id,purchases\n1,\n").unwrap_err(), "csv needs id and remaining_slices columns");
+        assert!(from_csv("id,remaining_slices\n1,many\n").unwrap_err().starts_with("line 2: remaining_slices:"));
+        assert_eq!(from_csv("id,remaining_slices,purchases\n1,2,alice\n").unwrap_err(),
+                   "line 2: purchase alice is not username:slices");
+        assert_eq!(from_csv("id,remaining_slices\n1,2\n").unwrap().pies[0].remaining_slices, 2);
+    }
+
+    #[test]
+    fn restores_saved_pies_and_resets_the_rest() {
+        let pies = catalog();
+        let restore = Restore { pies: vec![row(2, 1, vec![purchase("alice", 3)]), row(9, 0, vec![])] };
+        let (restored, summary) = plan(&pies, &restore).unwrap();
+        assert_eq!((summary.restored, summary.reset, summary.skipped), (1, 1, vec![9]));
+        assert_eq!(restored.iter().map(|pie| (pie.pie.id, pie.bitvec_pos, pie.remaining, pie.purchases.len())).collect::<Vec<_>>(),
+                   vec![(1, 0, 8, 0), (2, 1, 1, 1)]);
+    }
+
+    #[test]
+    fn an_empty_restore_resets_everything() {
+        let pies = catalog();
+        let (_, summary) = plan(&pies, &Restore { pies: vec![] }).unwrap();
+        assert_eq!((summary.restored, summary.reset), (0, 2));
+    }
+
+    #[test]
+    fn refuses_more_remaining_than_slices() {
+        let pies = catalog();
+        assert_eq!(plan(&pies, &Restore { pies: vec![row(2, 7, vec![])] }).err(),
+                   Some("pie 2 has 7 remaining_slices but only 6 slices".to_string()));
+        assert!(plan(&pies, &Restore { pies: vec![row(2, 6, vec![])] }).is_ok());
+    }
+
+    #[test]
+    fn refuses_duplicate_pies() {
+        let pies = catalog();
+        assert_eq!(plan(&pies, &Restore { pies: vec![row(1, 2, vec![]), row(1, 8, vec![])] }).err(),
+                   Some("pie 1 is in the snapshot more than once".to_string()));
+    }
+
+    #[test]
+    fn refuses_bad_purchases() {
+        let pies = catalog();
+        let refused = |purchases: Vec<Purchase>| plan(&pies, &Restore { pies: vec![row(1, 2, purchases)] }).is_err();
+        assert!(refused(vec![purchase(" ", 1)]));
+        assert!(refused(vec![purchase("alice", 0)]));
+        assert!(refused(vec![purchase("alice", 4)]));
+        assert!(refused(vec![purchase("alice", 1), purchase("alice", 1)]));
+        assert!(!refused(vec![purchase("alice", 3), purchase("bob", 1)]));
+    }
+}
@@ -0,0 +1,286 @@
+extern crate iron;
+use iron::prelude::*;
+use iron::status;
+use iron::Handler;
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+
+extern crate persistent;
+use persistent::Read;
+
+extern crate r2d2;
+extern crate r2d2_redis;
+extern crate redis;
+
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use api;
+use cache;
+use response;
+
+// redis behind timeouts, retries for reads and a circuit breaker: after enough failures
+// in a row the store is left alone for a while, and requests that need it answer 503
+// at once instead of each waiting out a timeout
+
+#[derive(RustcDecodable, Clone, Debug, Default)]
+pub struct StoreConfig {
+    // waiting for a pooled connection, including making a new one; 2000 by default
+    pub connection_timeout_ms: Option<u64>,
+    // for each command's reply; 1000 by default
+    pub command_timeout_ms: Option<u64>,
+    // extra tries for reads that are safe to repeat; 2 by default
+    pub read_retries: Option<u32>,
+    // before the first retry, doubling each time; 50 by default
+    pub retry_backoff_ms: Option<u64>,
+    // failures in a row that open the breaker; 5 by default
+    pub failure_threshold: Option<u32>,
+    // how long it stays open before trying redis again; 10 by default
+    pub open_seconds: Option<u64>
+}
+
+impl StoreConfig {
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_millis(self.connection_timeout_ms.unwrap_or(2000))
+    }
+
+    pub fn command_timeout(&self) -> Duration {
+        Duration::from_millis(self.command_timeout_ms.unwrap_or(1000))
+    }
+}
+
+// sets the command timeout on every connection the pool makes
+#[derive(Debug)]
+pub struct Timeouts(pub Duration);
+
+impl r2d2::CustomizeConnection<redis::Connection, redis::RedisError> for Timeouts {
+    fn on_acquire(&self, conn: &mut redis::Connection) -> Result<(), redis::RedisError> {
+        try!(conn.set_read_timeout(Some(self.0)));
+        conn.set_write_timeout(Some(self.0))
+    }
+}
+
+// the store couldn't be reached; try again in retry_after seconds
+#[derive(Debug, Copy, Clone)]
+pub struct Unavailable {
+    pub retry_after: u64
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for Unavailable {
+    fn description(&self) -> &str {
+        "Pie is unavailable right now, please try again shortly."
+    }
+}
+
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>
+}
+
+struct Breaker {
+    state: Mutex<BreakerState>,
+    threshold: u32,
+    open_for: Duration
+}
+
+fn ceil_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+impl Breaker {
+    // Err with the seconds until it lets requests through again
+    fn allow(&self) -> Result<(), u64> {
+        let state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if until > Instant::now() => Err(ceil_seconds(until - Instant::now()).max(1)),
+            // closed, or open long enough that the next request may try
+            _ => Ok(())
+        }
+    }
+
+    fn success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            info!("redis is answering again, closing the breaker");
+        }
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    // once open, a failed try after the wait opens it again straight away
+    fn failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            if state.open_until.map_or(true, |until| until <= Instant::now()) {
+                warn!("redis failed {} times in a row, opening the breaker for {}s",
+                      state.failures, self.open_for.as_secs());
+            }
+            state.open_until = Some(Instant::now() + self.open_for);
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.allow().is_err()
+    }
+}
+
+#[derive(Clone)]
+pub struct Store {
+    pool: r2d2::Pool<r2d2_redis::RedisConnectionManager>,
+    breaker: Arc<Breaker>,
+    read_retries: u32,
+    retry_backoff: Duration
+}
+
+// everything that writes still takes the pool as before
+impl Deref for Store {
+    type Target = r2d2::Pool<r2d2_redis::RedisConnectionManager>;
+
+    fn deref(&self) -> &r2d2::Pool<r2d2_redis::RedisConnectionManager> {
+        &self.pool
+    }
+}
+
+impl Store {
+    pub fn new(pool: r2d2::Pool<r2d2_redis::RedisConnectionManager>, config: &StoreConfig) -> Store {
+        Store {
+            pool: pool,
+            breaker: Arc::new(Breaker {
+                state: Mutex::new(BreakerState { failures: 0, open_until: None }),
+                threshold: config.failure_threshold.unwrap_or(5).max(1),
+                open_for: Duration::from_secs(config.open_seconds.unwrap_or(10))
+            }),
+            read_retries: config.read_retries.unwrap_or(2),
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms.unwrap_or(50))
+        }
+    }
+
+    fn unavailable(&self) -> Unavailable {
+        Unavailable { retry_after: self.breaker.allow().err().unwrap_or(1) }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    // fails fast while the breaker is open
+    pub fn available(&self) -> Result<(), Unavailable> {
+        self.breaker.allow().map_err(|seconds| Unavailable { retry_after: seconds })
+    }
+
+    // for reads that are safe to repeat: tried again with backoff, and counted
+    // toward the breaker
+    pub fn read<T, F>(&self, query: F) -> Result<T, Unavailable>
+        where F: Fn(&redis::Connection) -> redis::RedisResult<T> {
+        let mut backoff = self.retry_backoff;
+        for attempt in 0..(self.read_retries + 1) {
+            if attempt > 0 {
+                thread::sleep(backoff);
+                backoff = backoff * 2;
+            }
+            try!(self.available());
+
+            match self.attempt(&query) {
+                Ok(value) => return Ok(value),
+                Err(e) => warn!("redis read failed, try {} of {}: {}", attempt + 1, self.read_retries + 1, e)
+            }
+        }
+        Err(self.unavailable())
+    }
+
+    // for writes, which may not be safe to repeat: tried once, and counted toward the breaker
+    pub fn write<T, F>(&self, command: F) -> Result<T, Unavailable>
+        where F: Fn(&redis::Connection) -> redis::RedisResult<T> {
+        try!(self.available());
+        self.attempt(&command).map_err(|e| {
+            warn!("redis write failed: {}", e);
+            self.unavailable()
+        })
+    }
+
+    fn attempt<T, F>(&self, command: &F) -> Result<T, String>
+        where F: Fn(&redis::Connection) -> redis::RedisResult<T> {
+        let result = self.pool.get().map_err(|e| e.to_string())
+            .and_then(|conn| command(conn.deref()).map_err(|e| e.to_string()));
+        match result {
+            Ok(_) => self.breaker.success(),
+            Err(_) => self.breaker.failure()
+        }
+        result
+    }
+
+    // one round trip, counted toward the breaker
+    pub fn ping(&self) -> bool {
+        let pong = self.pool.get().map_err(|e| e.to_string())
+            .and_then(|conn| redis::cmd("PING").query::<String>(conn.deref()).map_err(|e| e.to_string()));
+        match pong {
+            Ok(_) => {
+                self.breaker.success();
+                true
+            },
+            Err(e) => {
+                warn!("redis ping failed: {}", e);
+                self.breaker.failure();
+                false
+            }
+        }
+    }
+}
+
+fn unavailable_error(req: &Request, unavailable: Unavailable, envelope: bool) -> IronError {
+    let body = if envelope {
+        api::error_body(req, api::ErrorCode::Unavailable)
+    } else {
+        format!("{{\"error\": \"{}\"}}", unavailable.description())
+    };
+    let mut err = IronError::new(unavailable, (
+        status::ServiceUnavailable,
+        body,
+        Header(ContentType::json())
+    ));
+    err.response.headers.set_raw("Retry-After", vec![unavailable.retry_after.to_string().into_bytes()]);
+    err
+}
+
+// wraps each route: unless it can serve a degraded view, it answers 503 while the
+// breaker is open. handlers answer 503 themselves when a read or write through the
+// store fails; catching a panic is only the last resort for anything that slipped past
+pub struct Guarded {
+    pub handler: Chain,
+    pub degrades: bool,
+    // api routes answer with the api's envelope
+    pub envelope: bool
+}
+
+impl Handler for Guarded {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let store = req.get::<Read<cache::Redis>>().unwrap();
+        if !self.degrades {
+            if let Err(unavailable) = store.available() {
+                return Err(unavailable_error(req, unavailable, self.envelope));
+            }
+        }
+
+        match panic::catch_unwind(AssertUnwindSafe(|| self.handler.handle(req))) {
+            Ok(result) => result,
+            // a panic with redis still answering is some other bug, and stays a 500
+            Err(_) => if store.ping() {
+                response::error()
+            } else {
+                Err(unavailable_error(req, store.unavailable(), self.envelope))
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, Default)]
+pub struct TaxonomyConfig {
+    // parent label -> child labels, e.g. "fruit": ["apple", "cherry"]
+    pub children: Option<HashMap<String, Vec<String>>>,
+    // alias -> canonical label, e.g. "gf": "gluten-free"
+    pub synonyms: Option<HashMap<String, String>>
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Taxonomy {
+    synonyms: HashMap<String, String>,
+    parents: HashMap<String, Vec<String>>,
+    children: HashMap<String, Vec<String>>,
+    // every canonical label the config mentions
+    known: HashSet<String>
+}
+
+// "  Gluten Free " and "gluten_free" both become "gluten-free"
+pub fn normalize(label: &str) -> String {
+    label.split(|c: char| c.is_whitespace() || c == '_' || c == '-')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+// an alias chain resolves to its end, and a cycle to its first label in order, so
+// every spelling in it ends up at the same label
+fn resolve(alias: &str, synonyms: &HashMap<String, String>) -> String {
+    let mut seen = vec![alias.to_string()];
+    while let Some(next) = synonyms.get(&seen[seen.len() - 1]) {
+        if let Some(i) = seen.iter().position(|label| label == next) {
+            return seen[i..].iter().min().unwrap().clone();
+        }
+        seen.push(next.clone());
+    }
+    seen.pop().unwrap()
+}
+
+// every label reachable from start through edges, start included
+fn walk(start: String, edges: &HashMap<String, Vec<String>>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut reached = vec![];
+    let mut pending = vec![start];
+
+    while let Some(label) = pending.pop() {
+        if !seen.insert(label.clone()) {
+            continue;
+        }
+        if let Some(next) = edges.get(&label) {
+            pending.extend(next.iter().cloned());
+        }
+        reached.push(label);
+    }
+
+    reached
+}
+
+impl Taxonomy {
+    pub fn new(config: &TaxonomyConfig) -> Taxonomy {
+        let mut direct = HashMap::new();
+        if let Some(ref aliases) = config.synonyms {
+            for (alias, label) in aliases {
+                direct.insert(normalize(alias), normalize(label));
+            }
+        }
+        let synonyms = direct.keys()
+            .map(|alias| (alias.clone(), resolve(alias, &direct)))
+            .filter(|&(ref alias, ref label)| alias != label)
+            .collect();
+
+        let mut taxonomy = Taxonomy { synonyms: synonyms, parents: HashMap::new(), children: HashMap::new(), known: HashSet::new() };
+        let canonical: Vec<String> = taxonomy.synonyms.values().cloned().collect();
+        taxonomy.known.extend(canonical);
+
+        if let Some(ref children) = config.children {
+            for (parent, kids) in children {
+                let parent = taxonomy.canonical(parent);
+                taxonomy.known.insert(parent.clone());
+                for kid in kids {
+                    let kid = taxonomy.canonical(kid);
+                    taxonomy.known.insert(kid.clone());
+                    taxonomy.parents.entry(kid.clone()).or_insert(vec![]).push(parent.clone());
+                    taxonomy.children.entry(parent.clone()).or_insert(vec![]).push(kid);
+                }
+            }
+        }
+
+        taxonomy
+    }
+
+    pub fn canonical(&self, label: &str) -> String {
+        let label = normalize(label);
+        match self.synonyms.get(&label) {
+            Some(canonical) => canonical.clone(),
+            None => label
+        }
+    }
+
+    // the label itself plus every ancestor, so an apple pie is also a fruit pie
+    pub fn expand(&self, label: &str) -> Vec<String> {
+        walk(self.canonical(label), &self.parents)
+    }
+
+    // the label itself plus every descendant, so a pie that contains nuts
+    // contains almonds as far as anyone excluding almonds is concerned
+    pub fn descendants(&self, label: &str) -> Vec<String> {
+        walk(self.canonical(label), &self.children)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    pub fn is_known(&self, label: &str) -> bool {
+        self.known.contains(&self.canonical(label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{normalize, Taxonomy, TaxonomyConfig};
+
+    fn taxonomy(children: &[(&str, &[&str])], synonyms: &[(&str, &str)]) -> Taxonomy {
+        Taxonomy::new(&TaxonomyConfig {
+            children: Some(children.iter()
+                .map(|&(parent, kids)| (parent.to_string(), kids.iter().map(|kid| kid.to_string()).collect()))
+                .collect()),
+            synonyms: Some(synonyms.iter()
+                .map(|&(alias, label)| (alias.to_string(), label.to_string()))
+                .collect())
+        })
+    }
+
+    fn sorted(mut labels: Vec<String>) -> Vec<String> {
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn normalizes_case_spacing_and_separators() {
+        assert_eq!(normalize("  Gluten Free "), "gluten-free");
+        assert_eq!(normalize("gluten_free"), "gluten-free");
+        assert_eq!(normalize("GLUTEN--free"), "gluten-free");
+        assert_eq!(normalize(" - "), "");
+    }
+
+    #[test]
+    fn synonyms_resolve_to_their_label() {
+        let taxonomy = taxonomy(&[], &[("GF", "Gluten Free")]);
+        assert_eq!(taxonomy.canonical("gf"), "gluten-free");
+        assert_eq!(taxonomy.canonical("gluten_free"), "gluten-free");
+        assert_eq!(taxonomy.canonical("vegan"), "vegan");
+    }
+
+    #[test]
+    fn synonym_chains_resolve_to_the_end() {
+        let taxonomy = taxonomy(&[], &[("gf", "no-gluten"), ("no-gluten", "gluten-free")]);
+        assert_eq!(taxonomy.canonical("gf"), "gluten-free");
+        assert_eq!(taxonomy.canonical("no gluten"), "gluten-free");
+        assert!(taxonomy.is_known("gf"));
+    }
+
+    #[test]
+    fn synonym_cycles_agree_on_one_label() {
+        let taxonomy = taxonomy(&[], &[("gf", "gluten-free"), ("gluten-free", "gf"), ("coeliac", "gf")]);
+        assert_eq!(taxonomy.canonical("gf"), "gf");
+        assert_eq!(taxonomy.canonical("gluten-free"), "gf");
+        assert_eq!(taxonomy.canonical("coeliac"), "gf");
+    }
+
+    #[test]
+    fn expands_to_every_ancestor() {
+        let taxonomy = taxonomy(&[("food", &["fruit"]), ("fruit", &["apple", "cherry"]), ("red", &["cherry"])], &[]);
+        assert_eq!(sorted(taxonomy.expand("Cherry")), vec!["cherry", "food", "fruit", "red"]);
+        assert_eq!(sorted(taxonomy.expand("apple")), vec!["apple", "food", "fruit"]);
+        assert_eq!(taxonomy.expand("food"), vec!["food"]);
+        assert_eq!(taxonomy.expand("unknown"), vec!["unknown"]);
+    }
+
+    #[test]
+    fn descends_to_every_child() {
+        let taxonomy = taxonomy(&[("food", &["fruit"]), ("fruit", &["apple", "cherry"]), ("red", &["cherry"])], &[]);
+        assert_eq!(sorted(taxonomy.descendants("food")), vec!["apple", "cherry", "food", "fruit"]);
+        assert_eq!(sorted(taxonomy.descendants("red")), vec!["cherry", "red"]);
+        assert_eq!(taxonomy.descendants("apple"), vec!["apple"]);
+    }
+
+    #[test]
+    fn synonyms_apply_inside_the_hierarchy() {
+        let taxonomy = taxonomy(&[("Tree Nuts", &["almonds"])], &[("nuts", "tree nuts"), ("almonds", "almond")]);
+        assert_eq!(sorted(taxonomy.expand("almond")), vec!["almond", "tree-nuts"]);
+        assert_eq!(sorted(taxonomy.descendants("nuts")), vec!["almond", "tree-nuts"]);
+    }
+
+    #[test]
+    fn parent_cycles_end() {
+        let taxonomy = taxonomy(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &["d"])], &[]);
+        assert_eq!(sorted(taxonomy.expand("a")), vec!["a", "b", "c"]);
+        assert_eq!(sorted(taxonomy.descendants("b")), vec!["a", "b", "c"]);
+        assert_eq!(taxonomy.expand("d"), vec!["d"]);
+    }
+
+    #[test]
+    fn knows_only_configured_labels() {
+        assert!(Taxonomy::new(&TaxonomyConfig { children: None, synonyms: Some(HashMap::new()) }).is_empty());
+        let taxonomy = taxonomy(&[("fruit", &["apple"])], &[("gf", "gluten-free")]);
+        assert!(!taxonomy.is_empty());
+        assert!(taxonomy.is_known("Apple"));
+        assert!(taxonomy.is_known("gluten free"));
+        assert!(!taxonomy.is_known("cherry"));
+    }
+}
@@ -0,0 +1,129 @@
+extern crate mustache;
+
+extern crate rustc_serialize;
+use rustc_serialize::Encodable;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+// pages rendered inside layout.mustache; partials are pulled in by the pages themselves
+const PAGES: &'static [&'static str] = &["pies", "pie", "purchase", "error", "login"];
+const LAYOUT: &'static str = "layout";
+
+#[derive(RustcEncodable)]
+struct Layout {
+    title: String,
+    body: String
+}
+
+#[derive(RustcEncodable)]
+pub struct ErrorPage {
+    pub status: u16,
+    pub message: String
+}
+
+#[derive(RustcEncodable)]
+pub struct LoginPage {
+    pub csrf_token: String,
+    pub message: Option<String>,
+    // signed in already, so the page offers to sign out instead
+    pub username: Option<String>,
+    pub login_url: String,
+    pub register_url: String,
+    pub logout_url: String
+}
+
+struct Compiled {
+    at: SystemTime,
+    templates: HashMap<String, mustache::Template>
+}
+
+pub struct Templates {
+    dir: PathBuf,
+    reload: bool,
+    compiled: RwLock<Compiled>
+}
+
+// all or nothing, so a broken template never replaces a working one
+fn compile_all(dir: &PathBuf) -> Result<HashMap<String, mustache::Template>, String> {
+    let context = mustache::Context::new(dir.clone());
+    let mut templates = HashMap::new();
+
+    for name in PAGES.iter().chain([LAYOUT].iter()) {
+        let path = dir.join(format!("{}.mustache", name));
+        let template = try!(context.compile_path(&path)
+            .map_err(|e| format!("failed to compile template {:?}: {:?}", path, e)));
+        templates.insert(name.to_string(), template);
+    }
+
+    Ok(templates)
+}
+
+// the newest modification time of anything in the directory, partials included
+fn last_change(dir: &PathBuf) -> Option<SystemTime> {
+    fs::read_dir(dir).ok().and_then(|entries| {
+        entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.metadata().ok())
+            .filter_map(|metadata| metadata.modified().ok())
+            .max()
+    })
+}
+
+impl Templates {
+    // reload recompiles everything whenever a file in the directory changes, for development
+    pub fn new(dir: &str, reload: bool) -> Templates {
+        let dir = PathBuf::from(dir);
+        let templates = compile_all(&dir).unwrap_or_else(|e| panic!("{}", e));
+        info!("compiled {} templates from {:?}", templates.len(), dir);
+
+        Templates {
+            dir: dir,
+            reload: reload,
+            compiled: RwLock::new(Compiled { at: SystemTime::now(), templates: templates })
+        }
+    }
+
+    fn refresh(&self) {
+        let changed = match last_change(&self.dir) {
+            Some(modified) => modified > self.compiled.read().unwrap().at,
+            None => false
+        };
+        if !changed {
+            return;
+        }
+
+        // the time moves on either way, so a broken template is reported once per edit
+        // rather than on every request
+        let mut compiled = self.compiled.write().unwrap();
+        compiled.at = SystemTime::now();
+        match compile_all(&self.dir) {
+            Ok(templates) => {
+                compiled.templates = templates;
+                info!("recompiled templates from {:?}", self.dir);
+            }
+            Err(e) => error!("{}, keeping the previous templates", e)
+        }
+    }
+
+    fn render_one<T: Encodable>(&self, name: &str, data: &T) -> String {
+        let compiled = self.compiled.read().unwrap();
+        let mut bytes = vec![];
+        compiled.templates[name].render(&mut bytes, data).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    pub fn render<T: Encodable>(&self, page: &str, title: &str, data: &T) -> String {
+        if self.reload {
+            self.refresh();
+        }
+
+        let layout = Layout {
+            title: title.to_string(),
+            body: self.render_one(page, data)
+        };
+        self.render_one(LAYOUT, &layout)
+    }
+}